@@ -1,5 +1,6 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{Decimal, StdError, Uint128};
 use cw0::PaymentError;
+use neutron_sdk::NeutronError;
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -10,6 +11,9 @@ pub enum ContractError {
     #[error("{0}")]
     PaymentError(#[from] PaymentError),
 
+    #[error("{0}")]
+    Neutron(#[from] NeutronError),
+
     #[error("unauthorized")]
     Unauthorized {},
 
@@ -32,6 +36,105 @@ pub enum ContractError {
         remote_address: String,
     },
 
+    #[error("remote address {remote_address} on chain {chain_id} is already registered by {owner}")]
+    RemoteAddressAlreadyRegistered {
+        chain_id: String,
+        remote_address: String,
+        owner: String,
+    },
+
     #[error("No rewards to autocompound")]
     NoRewardsToAutocompound {},
+
+    #[error("ICQ data is too old to autocompound: {registrations}")]
+    StaleIcqData { registrations: String },
+
+    #[error("ICQ results do not hold every key the rewards need yet: {registrations}")]
+    IncompleteIcqData { registrations: String },
+
+    #[error("Fee balance too low to autocompound: {registrations}")]
+    InsufficientFeeBalance { registrations: String },
+
+    #[error("Contract reserve too low to pay the relayers of: {registrations}")]
+    InsufficientRelayerReserve { registrations: String },
+
+    #[error("the contract reserve of {available}untrn can't pay the relayer fees of {required}untrn")]
+    InsufficientReserve { required: Uint128, available: Uint128 },
+
+    #[error("invalid remote address {address}, expected a bech32 address with prefix {expected_prefix}")]
+    InvalidRemoteAddress {
+        address: String,
+        expected_prefix: String,
+    },
+
+    #[error("invalid validator address {validator}, expected a bech32 address with prefix {expected_prefix}")]
+    InvalidValidatorAddress {
+        validator: String,
+        expected_prefix: String,
+    },
+
+    #[error("validator {validator} is listed more than once")]
+    DuplicateValidator { validator: String },
+
+    #[error("at least one validator is required")]
+    NoValidators {},
+
+    #[error("too many validators, max is {max}, got {actual}")]
+    TooManyValidators { max: u64, actual: u64 },
+
+    #[error("{denom} is not accepted for fees")]
+    UnsupportedFeeDenom { denom: String },
+
+    #[error("invalid rate {untrn_rate} for fee denom {denom}")]
+    InvalidFeeDenomRate { denom: String, untrn_rate: Decimal },
+
+    #[error("max commission {max_commission} is above 1")]
+    InvalidMaxCommission { max_commission: Decimal },
+
+    #[error("treasury fee {treasury_fee} is above 1")]
+    InvalidTreasuryFee { treasury_fee: Decimal },
+
+    #[error("treasury holds {available}{denom}, can't withdraw {requested}{denom}")]
+    InsufficientTreasury {
+        denom: String,
+        available: Uint128,
+        requested: Uint128,
+    },
+
+    #[error("host fee percentage {percentage} is above 1")]
+    InvalidHostFee { percentage: Decimal },
+
+    #[error("chain {chain_id} has no host fee, registrations can't be billed on it")]
+    HostFeeNotSupported { chain_id: String },
+
+    #[error("host fees of chain {chain_id} can't be swept, they need a sweep channel and denom, and the ICA as collector")]
+    HostFeeSweepNotConfigured { chain_id: String },
+
+    #[error("no host fees to sweep on chain {chain_id}")]
+    NoHostFeesToSweep { chain_id: String },
+
+    #[error("chain {chain_id} holds {available} of host fees, can't sweep {requested}")]
+    InsufficientHostFees {
+        chain_id: String,
+        available: Uint128,
+        requested: Uint128,
+    },
+
+    #[error("no registration of {remote_address} on chain {chain_id}")]
+    RegistrationNotFound {
+        chain_id: String,
+        remote_address: String,
+    },
+
+    #[error("the interchain queries of the registration are not registered yet")]
+    RegistrationPending {},
+
+    #[error("interchain query deposits in {denom} are not supported, only untrn")]
+    UnsupportedQueryDeposit { denom: String },
+
+    #[error("{field} can't be set and cleared at once")]
+    ConflictingUpdate { field: String },
+
+    #[error("max_kv_query_keys {max_kv_query_keys} can't fit the keys of a single validator")]
+    KvQueryKeyLimitTooLow { max_kv_query_keys: u64 },
 }
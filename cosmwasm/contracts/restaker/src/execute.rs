@@ -1,21 +1,28 @@
 use cosmwasm_std::{
-    coin, coins, entry_point, BankMsg, DepsMut, Env, MessageInfo, Response, StdError, SubMsg, Uint128,
+    coin, coins, entry_point, BankMsg, Coin, Decimal, DepsMut, Env, Event, MessageInfo, Response, StdError,
+    SubMsg, Uint128,
 };
-use cw0::must_pay;
+use cw0::{may_pay, PaymentError};
 use neutron_sdk::bindings::msg::NeutronMsg;
 use neutron_sdk::bindings::query::NeutronQuery;
 use neutron_sdk::interchain_queries::types::QueryPayload;
 use neutron_sdk::interchain_txs::helpers::get_port_id;
+use neutron_sdk::query::min_ibc_fee::query_min_ibc_fee;
 
 use crate::error::ContractError;
 use crate::icq::keys::create_all_icq_keys_for_user;
-use crate::helpers::{get_delegate_submsg, get_due_user_chain_registrations};
+use crate::helpers::{
+    acquire_validator_query, charge_fee, charge_query_deposits, compound_skip_reason, consume_grant_allowance, get_delegate_submsg, get_due_user_chain_registrations,
+    credit_user_balance, get_host_fee_send_msg, get_withdraw_reward_msg, get_sweep_submsg, host_fee_amount, ica_tx_relayer_fee, query_icq_deposit, query_reserve, remove_registration_queries, shard_validators,
+    required_msg_type_urls, track_ica_tx, update_ledger, update_registration_queries, validate_host_fee, validate_registration_input,
+};
 use crate::msg::{ExecuteMsg, UserChainRegistrationInput};
+use crate::icq::reconstruct::query_covered_user_query_data;
 use crate::query::query_calculate_reward;
 use crate::state::{
-    user_chain_registrations, Chain, Config, UserChainRegistration, CONFIG,
+    user_chain_registrations, BillingMode, Chain, ChainProfile, Config, DEFAULT_ICQ_UPDATE_PERIOD, DEFAULT_MAX_ICQ_STALENESS, EscrowedFee, HostFeeConfig, InFlight, IcaTxConfig, IcaTxKind, IcqShard, PauseReason, RegistrationStatus, UserChainRegistration, ValidatorCommission, CONFIG,
     ICA_PORT_ID_TO_CHAIN_ID, NEXT_REPLY_ID, REPLY_ID_TO_USER_CHAIN_REGISTRATION, SUPPORTED_CHAINS,
-    USER_BALANCES,
+    FEE_DENOMS, HOST_FEES, TREASURY, USER_FEE_BALANCES,
 };
 
 //const STAKING_STORE_KEY: &str = "staking";
@@ -35,6 +42,11 @@ pub fn execute(
             connection_id,
             denom,
             autocompound_cost,
+            profile,
+            max_icq_staleness,
+            icq_update_period,
+            ica_tx,
+            host_fee,
         } => add_supported_chain(
             deps,
             env,
@@ -43,12 +55,23 @@ pub fn execute(
             connection_id,
             denom,
             autocompound_cost,
+            profile,
+            max_icq_staleness,
+            icq_update_period,
+            ica_tx,
+            host_fee,
         ),
         ExecuteMsg::UpdateSupportedChain {
             chain_id,
             connection_id,
             denom,
             autocompound_cost,
+            profile,
+            max_icq_staleness,
+            icq_update_period,
+            ica_tx,
+            host_fee,
+            clear_host_fee,
         } => update_supported_chain(
             deps,
             env,
@@ -57,12 +80,52 @@ pub fn execute(
             connection_id,
             denom,
             autocompound_cost,
+            profile,
+            max_icq_staleness,
+            icq_update_period,
+            ica_tx,
+            host_fee,
+            clear_host_fee.unwrap_or(false),
         ),
         ExecuteMsg::RegisterUser { registrations } => register_user(env, deps, info, registrations),
+        ExecuteMsg::UpdateRegistration {
+            chain_id,
+            remote_address,
+            validators,
+            fallback_validator,
+            max_commission,
+            clear_fallback_validator,
+            clear_max_commission,
+            billing,
+        } => update_registration(
+            deps,
+            env,
+            info,
+            chain_id,
+            remote_address,
+            validators,
+            fallback_validator,
+            clear_fallback_validator.unwrap_or(false),
+            max_commission,
+            clear_max_commission.unwrap_or(false),
+            billing,
+        ),
+        ExecuteMsg::RemoveRegistration {
+            chain_id,
+            remote_address,
+            local_address,
+        } => remove_registration(deps, info, chain_id, remote_address, local_address),
         ExecuteMsg::TopupUserBalance {} => topup_user_balance(deps, env, info),
+        ExecuteMsg::SetFeeDenom { denom, untrn_rate } => set_fee_denom(deps, info, denom, untrn_rate),
         ExecuteMsg::Autocompound { delegators_amount } => {
             autocompound(deps, env, info, delegators_amount)
         }
+        ExecuteMsg::WithdrawTreasury {
+            denom,
+            amount,
+            recipient,
+        } => withdraw_treasury(deps, info, denom, amount, recipient),
+        ExecuteMsg::SweepHostFees { chain_id, amount } => sweep_host_fees(deps, env, info, chain_id, amount),
     }
 }
 
@@ -77,6 +140,11 @@ pub fn update_config(
     if info.sender != config.admin {
         return Err(ContractError::Unauthorized {});
     }
+    if new_config.treasury_fee > Decimal::one() {
+        return Err(ContractError::InvalidTreasuryFee {
+            treasury_fee: new_config.treasury_fee,
+        });
+    }
 
     CONFIG.save(deps.storage, &new_config)?;
 
@@ -85,6 +153,7 @@ pub fn update_config(
         .add_attribute("config", format!("{:?}", config)))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn add_supported_chain(
     deps: DepsMut<NeutronQuery>,
     env: Env,
@@ -93,6 +162,11 @@ pub fn add_supported_chain(
     connection_id: String,
     denom: String,
     autocompound_cost: u128,
+    profile: Option<ChainProfile>,
+    max_icq_staleness: Option<u64>,
+    icq_update_period: Option<u64>,
+    ica_tx: Option<IcaTxConfig>,
+    host_fee: Option<HostFeeConfig>,
 ) -> Result<Response<NeutronMsg>, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     if info.sender != config.admin {
@@ -108,7 +182,7 @@ pub fn add_supported_chain(
         || info.funds[0].amount.u128() != config.neutron_register_ica_fee
     {
         return Err(ContractError::NotEnoughFunds {
-            required_amount: config.neutron_register_ica_fee.into(),
+            required_amount: config.neutron_register_ica_fee,
             actual_amount: info.funds[0].amount.u128(),
         });
     }
@@ -123,7 +197,15 @@ pub fn add_supported_chain(
         denom,
         ica_address: None,
         ica_error: None,
+        profile: profile.unwrap_or_default(),
+        max_icq_staleness: max_icq_staleness.unwrap_or(DEFAULT_MAX_ICQ_STALENESS),
+        icq_update_period: icq_update_period.unwrap_or(DEFAULT_ICQ_UPDATE_PERIOD),
+        ica_tx: ica_tx.unwrap_or_default(),
+        host_fee,
     };
+    if let Some(host_fee) = &chain.host_fee {
+        validate_host_fee(&chain, host_fee)?;
+    }
 
     SUPPORTED_CHAINS.save(deps.storage, chain_id.clone(), &chain)?;
     ICA_PORT_ID_TO_CHAIN_ID.save(deps.storage, ica_port_id.clone(), &chain_id)?;
@@ -143,6 +225,7 @@ pub fn add_supported_chain(
         .add_message(register))
 }
 
+#[allow(clippy::too_many_arguments)]
 fn update_supported_chain(
     deps: DepsMut<NeutronQuery>,
     env: Env,
@@ -151,6 +234,12 @@ fn update_supported_chain(
     connection_id: String,
     denom: String,
     autocompound_cost: u128,
+    profile: Option<ChainProfile>,
+    max_icq_staleness: Option<u64>,
+    icq_update_period: Option<u64>,
+    ica_tx: Option<IcaTxConfig>,
+    host_fee: Option<HostFeeConfig>,
+    clear_host_fee: bool,
 ) -> Result<Response<NeutronMsg>, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     if info.sender != config.admin {
@@ -169,7 +258,15 @@ fn update_supported_chain(
         denom,
         ica_address: chain.ica_address,
         ica_error: chain.ica_error,
+        profile: profile.unwrap_or(chain.profile),
+        max_icq_staleness: max_icq_staleness.unwrap_or(chain.max_icq_staleness),
+        icq_update_period: icq_update_period.unwrap_or(chain.icq_update_period),
+        ica_tx: ica_tx.unwrap_or(chain.ica_tx),
+        host_fee: updated_setting("host_fee", host_fee, clear_host_fee, &chain.host_fee)?,
     };
+    if let Some(host_fee) = &chain.host_fee {
+        validate_host_fee(&chain, host_fee)?;
+    }
 
     SUPPORTED_CHAINS.save(deps.storage, chain_id, &chain)?;
 
@@ -186,12 +283,30 @@ pub fn register_user(
 ) -> Result<Response<NeutronMsg>, ContractError> {
     let mut icq_msgs: Vec<SubMsg<NeutronMsg>> = Vec::new();
 
-    let mut next_reply_id = NEXT_REPLY_ID.load(deps.storage).unwrap();
+    let mut next_reply_id = NEXT_REPLY_ID.load(deps.storage)?;
     deps.api
         .debug(format!("WASMDEBUG: next_reply_id: {}", next_reply_id).as_str());
+    // Get config to calculate next_compound_height and validate the registrations
+    let config = CONFIG.load(deps.storage)?;
+
+    // untrn sent along tops up the balance, the module deposit of every new query is taken from it
+    let deposit = query_icq_deposit(deps.as_ref())?;
+    let sent = may_pay(&info, "untrn")?;
+    if !sent.is_zero() {
+        credit_user_balance(deps.storage, &info.sender, sent)?;
+    }
+    let mut new_query_reply_ids: Vec<u64> = vec![];
+
     for registration in registrations {
-        // TODO_NICE: handle this .load with a may_load
-        let chain = SUPPORTED_CHAINS.load(deps.storage, registration.clone().chain_id)?;
+        let chain = SUPPORTED_CHAINS
+            .may_load(deps.storage, registration.clone().chain_id)?
+            .ok_or(ContractError::ChainNotFound {})?;
+
+        validate_registration_input(
+            &chain,
+            &registration,
+            config.max_validators_per_registration,
+        )?;
 
         let chain_id = registration.clone().chain_id;
         let remote_address = registration.clone().address;
@@ -204,8 +319,7 @@ pub fn register_user(
                     chain_id.clone(),
                     remote_address.clone(),
                 ),
-            )
-            .unwrap()
+            )?
             .is_some()
         {
             return Err(ContractError::ChainAlreadyRegisteredForUser {
@@ -215,55 +329,104 @@ pub fn register_user(
             });
         }
 
-        // Get config to calculate next_compound_height
-        let config = CONFIG.load(deps.storage)?;
+        if let Some((_, owner_reg)) = user_chain_registrations()
+            .idx
+            .remote_address
+            .item(deps.storage, (chain_id.clone(), remote_address.clone()))?
+        {
+            return Err(ContractError::RemoteAddressAlreadyRegistered {
+                chain_id: chain_id.clone(),
+                remote_address: remote_address.clone(),
+                owner: owner_reg.local_address.to_string(),
+            });
+        }
+
+        // One query per group of validators, so none of them goes over the module's key limit
+        let mut shards = shard_validators(&registration.validators, config.max_kv_query_keys)?.into_iter();
+        let primary_validators = shards.next().unwrap_or_default();
+        let primary_reply_id = next_reply_id;
+        next_reply_id += 1;
+        let icq_shards: Vec<IcqShard> = shards
+            .map(|validators| {
+                let reply_id = next_reply_id;
+                next_reply_id += 1;
+                IcqShard {
+                    validators,
+                    reply_id,
+                    icq_id: None,
+                }
+            })
+            .collect();
 
         let user_chain_reg = UserChainRegistration {
             chain_id: chain_id.clone(),
             local_address: info.clone().sender,
             remote_address: remote_address.clone(),
             validators: registration.clone().validators,
-            delegator_delegations_reply_id: next_reply_id,
+            delegator_delegations_reply_id: primary_reply_id,
             delegator_delegations_icq_id: None,
             next_compound_height: env.block.height + config.autocompound_threshold,
+            // Waits for the grant once every query is registered, see reply.rs
+            status: RegistrationStatus::PendingQuery,
+            grant_expiration: None,
+            grant_allowance: None,
+            icq_shards: icq_shards.clone(),
+            fallback_validator: registration.fallback_validator.clone(),
+            max_commission: registration.max_commission,
+            compounded_commissions: vec![],
+            withdraw_address: None,
+            billing: registration.billing.clone().unwrap_or_default(),
+            compound_failures: 0,
+            in_flight: None,
+            failed_compound: None,
         };
-        user_chain_registrations().save(
-            deps.storage,
-            (
-                info.clone().sender,
-                chain_id.clone(),
-                remote_address.clone(),
-            ),
-            &user_chain_reg,
-        )?;
-        REPLY_ID_TO_USER_CHAIN_REGISTRATION
-            .save(
-                deps.storage,
-                next_reply_id,
-                &(
-                    user_chain_reg.local_address,
-                    user_chain_reg.chain_id,
-                    user_chain_reg.remote_address,
-                ),
-            )
-            .unwrap();
-
-        // ICQ stuff:
-        let icq_keys = create_all_icq_keys_for_user(
+        let reg_key = (
+            info.clone().sender,
+            chain_id.clone(),
             remote_address.clone(),
-            registration.clone().validators,
-            None,
-        ).unwrap();
-        let icq_msg = NeutronMsg::register_interchain_query(
-            QueryPayload::KV(icq_keys),
-            chain.connection_id,
-            5)
-            .unwrap();
-
-        let sub_msg = SubMsg::reply_on_success(icq_msg, next_reply_id);
-        icq_msgs.push(sub_msg);
+        );
+        user_chain_registrations().save(deps.storage, reg_key.clone(), &user_chain_reg)?;
+
+        // ICQ stuff, the grant and withdraw address keys only go in the primary query:
+        let grantee = chain.ica_address.as_ref().map(|a| a.to_string());
+        let grant_msg_type_urls = required_msg_type_urls(&user_chain_reg.billing, user_chain_reg.fallback_validator.as_ref());
+        let user_queries = std::iter::once((primary_reply_id, primary_validators, grantee))
+            .chain(icq_shards.into_iter().map(|s| (s.reply_id, s.validators, None)));
+        for (reply_id, validators, grantee) in user_queries {
+            REPLY_ID_TO_USER_CHAIN_REGISTRATION.save(deps.storage, reply_id, &reg_key)?;
+
+            let icq_keys = create_all_icq_keys_for_user(
+                &chain.profile,
+                remote_address.clone(),
+                validators,
+                None,
+                grantee,
+                &grant_msg_type_urls,
+                reply_id == primary_reply_id,
+            )?;
+            let icq_msg = NeutronMsg::register_interchain_query(
+                QueryPayload::KV(icq_keys),
+                chain.connection_id.clone(),
+                chain.icq_update_period,
+            )?;
+            // Failures are recorded on the registration instead of reverting the whole registration
+            icq_msgs.push(SubMsg::reply_always(icq_msg, reply_id));
+            new_query_reply_ids.push(reply_id);
+        }
 
-        next_reply_id += 1;
+        // Validator records and current rewards are queried once per validator, for every registration
+        for validator in registration.validators.iter() {
+            if let Some(sub_msg) = acquire_validator_query(
+                deps.storage,
+                &chain_id,
+                &chain,
+                validator,
+                &mut next_reply_id,
+            )? {
+                new_query_reply_ids.push(sub_msg.id);
+                icq_msgs.push(sub_msg);
+            }
+        }
 
         /*let converted_addr_bytes = decode_and_convert(&remote_address).unwrap();
         let delegation_key = create_delegation_key(converted_addr_bytes).unwrap();
@@ -281,13 +444,155 @@ pub fn register_user(
         icq_msgs.push(staking_delegation_icq_msg);*/
     }
 
-    NEXT_REPLY_ID.save(deps.storage, &next_reply_id).unwrap();
+    NEXT_REPLY_ID.save(deps.storage, &next_reply_id)?;
+    let charged = charge_query_deposits(deps.storage, &info.sender, &new_query_reply_ids, deposit)?;
 
     Ok(Response::new()
         .add_attribute("action", "register_user")
+        .add_attribute("query_deposits", charged.to_string())
         .add_submessages(icq_msgs))
 }
 
+/// Changes the validators of a registration in place, its queries are updated and its schedule is kept.
+/// The fallback validator and max commission come with a flag that clears them
+#[allow(clippy::too_many_arguments)]
+pub fn update_registration(
+    deps: DepsMut<NeutronQuery>,
+    _env: Env,
+    info: MessageInfo,
+    chain_id: String,
+    remote_address: String,
+    validators: Option<Vec<String>>,
+    fallback_validator: Option<String>,
+    clear_fallback_validator: bool,
+    max_commission: Option<Decimal>,
+    clear_max_commission: bool,
+    billing: Option<BillingMode>,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let reg_key = (info.sender.clone(), chain_id.clone(), remote_address.clone());
+    let mut registration = user_chain_registrations()
+        .may_load(deps.storage, reg_key.clone())?
+        .ok_or_else(|| ContractError::RegistrationNotFound {
+            chain_id: chain_id.clone(),
+            remote_address: remote_address.clone(),
+        })?;
+    let chain = SUPPORTED_CHAINS
+        .may_load(deps.storage, chain_id.clone())?
+        .ok_or(ContractError::ChainNotFound {})?;
+    let config = CONFIG.load(deps.storage)?;
+
+    let validators = validators.unwrap_or_else(|| registration.validators.clone());
+    let fallback_validator = updated_setting(
+        "fallback_validator",
+        fallback_validator,
+        clear_fallback_validator,
+        &registration.fallback_validator,
+    )?;
+    let max_commission =
+        updated_setting("max_commission", max_commission, clear_max_commission, &registration.max_commission)?;
+    let billing = billing.unwrap_or_else(|| registration.billing.clone());
+    validate_registration_input(
+        &chain,
+        &UserChainRegistrationInput {
+            chain_id,
+            address: remote_address,
+            validators: validators.clone(),
+            fallback_validator: fallback_validator.clone(),
+            max_commission,
+            billing: Some(billing.clone()),
+        },
+        config.max_validators_per_registration,
+    )?;
+    registration.fallback_validator = fallback_validator;
+    registration.max_commission = max_commission;
+    registration.billing = billing;
+    // The user has looked into the failing compounds, try again. The next ICQ result re-evaluates the grant
+    if registration.status == (RegistrationStatus::Paused { reason: PauseReason::CompoundFailures }) {
+        registration.status = RegistrationStatus::Active;
+        registration.compound_failures = 0;
+    }
+
+    // untrn sent along tops up the balance, new queries are paid from it like in register_user
+    let deposit = query_icq_deposit(deps.as_ref())?;
+    let sent = may_pay(&info, "untrn")?;
+    if !sent.is_zero() {
+        credit_user_balance(deps.storage, &info.sender, sent)?;
+    }
+
+    // Kept validators go on with the historical rewards period of the last result
+    let historical_ranges = query_covered_user_query_data(deps.as_ref(), &chain.profile, &registration, chain.icq_update_period)
+        .map(|(user_query_data, _)| user_query_data.historical_ranges())
+        .unwrap_or_default();
+
+    let mut next_reply_id = NEXT_REPLY_ID.load(deps.storage)?;
+    let (msgs, new_query_reply_ids) = update_registration_queries(
+        deps.storage,
+        &chain,
+        &mut registration,
+        validators,
+        &historical_ranges,
+        config.max_kv_query_keys,
+        &mut next_reply_id,
+    )?;
+    NEXT_REPLY_ID.save(deps.storage, &next_reply_id)?;
+    let charged = charge_query_deposits(deps.storage, &info.sender, &new_query_reply_ids, deposit)?;
+    user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_registration")
+        .add_attribute("query_deposits", charged.to_string())
+        .add_submessages(msgs))
+}
+
+// An optional setting of an update is kept when not set, or cleared with its flag
+fn updated_setting<T: Clone>(
+    field: &str,
+    value: Option<T>,
+    clear: bool,
+    current: &Option<T>,
+) -> Result<Option<T>, ContractError> {
+    match (value, clear) {
+        (Some(_), true) => Err(ContractError::ConflictingUpdate { field: field.to_string() }),
+        (Some(value), false) => Ok(Some(value)),
+        (None, true) => Ok(None),
+        (None, false) => Ok(current.clone()),
+    }
+}
+
+pub fn remove_registration(
+    deps: DepsMut<NeutronQuery>,
+    info: MessageInfo,
+    chain_id: String,
+    remote_address: String,
+    local_address: Option<String>,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    // The admin can free a remote address held by a user who doesn't own it
+    let local_address = match local_address {
+        Some(_) if info.sender != config.admin => return Err(ContractError::Unauthorized {}),
+        Some(local_address) => deps.api.addr_validate(&local_address)?,
+        None => info.sender,
+    };
+    let reg_key = (local_address.clone(), chain_id.clone(), remote_address.clone());
+    let registration = user_chain_registrations()
+        .may_load(deps.storage, reg_key.clone())?
+        .ok_or_else(|| ContractError::RegistrationNotFound {
+            chain_id: chain_id.clone(),
+            remote_address: remote_address.clone(),
+        })?;
+
+    // Compounds in flight are settled without the registration, see sudo.rs
+    let msgs = remove_registration_queries(deps.storage, &registration)?;
+    user_chain_registrations().remove(deps.storage, reg_key)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_registration")
+        .add_attribute("local_address", local_address.to_string())
+        .add_attribute("chain_id", chain_id)
+        .add_attribute("remote_address", remote_address)
+        .add_submessages(msgs))
+}
+
 /*fn create_delegation_key(delegator: AddressBytes) -> StdResult<AddressBytes> {
     let mut key: Vec<u8> = vec![STAKING_DELEGATION_KEY_PREFIX];
     key.extend_from_slice(delegator.as_slice());
@@ -296,24 +601,62 @@ pub fn register_user(
 }*/
 
 pub fn topup_user_balance(
-    _deps: DepsMut<NeutronQuery>,
+    deps: DepsMut<NeutronQuery>,
     _env: Env,
     info: MessageInfo,
 ) -> Result<Response<NeutronMsg>, ContractError> {
-    must_pay(&info, "untrn")?;
-
-    // Topup the balance for a specific user
-    USER_BALANCES.update(
-        _deps.storage,
-        info.sender,
-        |balance| -> Result<_, ContractError> {
-            Ok(balance.unwrap_or_default() + info.funds[0].amount)
-        },
-    )?;
+    if info.funds.is_empty() {
+        return Err(PaymentError::NoFunds {}.into());
+    }
+
+    // Topup the balance for a specific user, untrn and every whitelisted fee denom are kept apart
+    for fund in info.funds.iter() {
+        if fund.denom == "untrn" {
+            credit_user_balance(deps.storage, &info.sender, fund.amount)?;
+        } else if FEE_DENOMS.has(deps.storage, fund.denom.clone()) {
+            USER_FEE_BALANCES.update(
+                deps.storage,
+                (info.sender.clone(), fund.denom.clone()),
+                |balance| -> Result<_, ContractError> { Ok(balance.unwrap_or_default() + fund.amount) },
+            )?;
+        } else {
+            return Err(ContractError::UnsupportedFeeDenom {
+                denom: fund.denom.clone(),
+            });
+        }
+    }
 
     Ok(Response::new().add_attribute("action", "topup_user_balance"))
 }
 
+pub fn set_fee_denom(
+    deps: DepsMut<NeutronQuery>,
+    info: MessageInfo,
+    denom: String,
+    untrn_rate: Option<Decimal>,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+    if denom == "untrn" {
+        return Err(ContractError::UnsupportedFeeDenom { denom });
+    }
+
+    match untrn_rate {
+        Some(untrn_rate) if untrn_rate.is_zero() => {
+            return Err(ContractError::InvalidFeeDenomRate { denom, untrn_rate });
+        }
+        Some(untrn_rate) => FEE_DENOMS.save(deps.storage, denom.clone(), &untrn_rate)?,
+        None => FEE_DENOMS.remove(deps.storage, denom.clone()),
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "set_fee_denom")
+        .add_attribute("denom", denom)
+        .add_attribute("untrn_rate", untrn_rate.map(|r| r.to_string()).unwrap_or_default()))
+}
+
 pub fn autocompound(
     deps: DepsMut<NeutronQuery>,
     env: Env,
@@ -324,101 +667,427 @@ pub fn autocompound(
     // sorted by next_compound_height ASC, with next_compound_height <= current_height, as much as delegators_amount.
     // so we rely on the fact that the next_compound_height is updated after each autocompound and the next iteration we will have the next users to autocompound.
     let registrations = get_due_user_chain_registrations(&deps.as_ref(), &env, delegators_amount)?;
+    // Every ICA tx pays at least this to the relayers
+    let min_ibc_fee = query_min_ibc_fee(deps.as_ref())?.min_fee;
+    let config = CONFIG.load(deps.storage)?;
+    let mut next_reply_id = NEXT_REPLY_ID.load(deps.storage)?;
     
     deps.api.debug(format!("WASMDEBUG: registrations: {:?}", registrations).as_str());
 
     let mut delegate_submsgs: Vec<SubMsg<NeutronMsg>> = vec![];
-    let mut keeper_fee: u128 = 0;
+    let mut stale_registrations: Vec<String> = vec![];
+    let mut incomplete_registrations: Vec<String> = vec![];
+    let mut unfunded_registrations: Vec<String> = vec![];
+    let mut unrelayed_registrations: Vec<String> = vec![];
+    // Relayer fees are paid from the untrn the contract holds on top of what it owes, never from the users' funds
+    let mut reserve = query_reserve(deps.as_ref(), &env)?;
+    let mut compound_events: Vec<Event> = vec![];
 
     for registration in registrations {
-        let mut balance = USER_BALANCES
-            .load(deps.as_ref().storage, registration.clone().local_address)
-            .unwrap_or_default();
-
         let supported_chain = SUPPORTED_CHAINS
             .load(deps.as_ref().storage, registration.clone().chain_id)
             .map_err(|_| StdError::not_found("Chain not found"))?;
+        let Some(ica_address) = supported_chain.clone().ica_address else {
+            deps.api.debug(format!("WASMDEBUG: ICA not ready for chain: {}", registration.clone().chain_id).as_str());
+            continue;
+        };
+
+        let registration_key = (
+            registration.local_address.clone(),
+            registration.chain_id.clone(),
+            registration.remote_address.clone(),
+        );
+
+        // The grant may have expired since the last ICQ result, the MsgExec would fail on the host chain
+        if let Some(expiration) = registration.grant_expiration {
+            if expiration <= env.block.time {
+                let mut expired = registration.clone();
+                expired.status = RegistrationStatus::Paused { reason: PauseReason::GrantExpired };
+                user_chain_registrations().save(deps.storage, registration_key, &expired)?;
+                continue;
+            }
+        }
+
+        // The key set is rewritten after each result, wait for results that hold every key the reward math needs.
+        // The shared validator queries are submitted on their own, so this is checked again on the latest results
+        let complete = registration.delegator_delegations_icq_id.is_some()
+            && query_covered_user_query_data(deps.as_ref(), &supported_chain.profile, &registration, supported_chain.icq_update_period)
+                .map(|(_, coverage)| coverage.is_complete())
+                .unwrap_or(false);
+        if !complete {
+            incomplete_registrations.push(format!("{}/{}", registration.chain_id, registration.remote_address));
+            continue;
+        }
+
+        // Does this user has any rewards to compound?
+        let calculate_rewards = query_calculate_reward(
+            deps.as_ref(),
+            env.clone(),
+            registration.clone().local_address.to_string(),
+            registration.clone().chain_id,
+            registration.clone().remote_address,
+        )?;
+
+        // Rewards computed from an old ICQ result would delegate amounts the user may no longer have
+        if calculate_rewards.data_age.stale {
+            deps.api.debug(format!("WASMDEBUG: Stale ICQ data ({} blocks) for user: {}", calculate_rewards.data_age.age, registration.clone().local_address).as_str());
+            stale_registrations.push(format!("{}/{}", registration.chain_id, registration.remote_address));
+            continue;
+        }
+
+        // Tracks what is left of a capped StakeAuthorization while we queue delegations
+        let mut grant_allowance = registration.grant_allowance.clone();
+        let mut grant_exhausted = false;
+        let mut sent_txs = 0;
+        // Locks the registration until every compound tx is settled, or can no longer land on the host chain
+        let in_flight_expires = env.block.time.plus_seconds(supported_chain.ica_tx.timeout_seconds);
+        // Host-billed registrations pay from the rewards on the host chain instead of a balance on Neutron
+        let host_fee = match registration.billing {
+            BillingMode::HostChain => supported_chain.host_fee.as_ref(),
+            BillingMode::Prepaid => None,
+        };
 
         // Since a user could have staking position with more than one validator, we iterate over all of them
         for validator in registration.clone().validators {
-            // Only if the given user has enough topped up balance to cover protocol fees
-            /*if balance.u128() < supported_chain.autocompound_cost {
-                continue;
-            }*/
-
-            // Does this user has any rewards to compound?
-            let calculate_rewards = query_calculate_reward(
-                deps.as_ref(),
-                env.clone(),
-                registration.clone().local_address.to_string(),
-                registration.clone().chain_id,
-                registration.clone().remote_address,
-            )?;
-            let calculate_reward = calculate_rewards.rewards.into_iter().find(|r| r.validator == validator).unwrap();
+            let reward = calculate_rewards.rewards.iter().find(|r| r.validator == validator);
+            let reward_amount = reward
+                .and_then(|r| r.reward.iter().find(|c| c.denom == supported_chain.denom))
+                .map(|c| c.amount)
+                .unwrap_or_default();
+            let host_fee_amount = host_fee
+                .map(|host_fee| host_fee_amount(host_fee, reward_amount))
+                .unwrap_or_default();
+            let delegation_amount = reward_amount - host_fee_amount;
 
             // If there are not enough rewards to compound, continue
             // TODO_NICE: This could be use a threshold like at least > 0.1 (100000 udenom). Make this configurable.
-            if calculate_reward.reward.first().unwrap().amount.is_zero() {
+            if delegation_amount.is_zero() {
                 deps.api.debug(format!("WASMDEBUG: No rewards to autocompound for user: {}", registration.clone().local_address).as_str());
                 deps.api.debug(format!("WASMDEBUG: No rewards to autocompound for validator: {}", validator).as_str());
                 deps.api.debug(format!("WASMDEBUG: No rewards to autocompound for chain: {}", registration.clone().chain_id).as_str());
                 continue;
             }
 
-            let half_autocompound_cost = supported_chain
-                .autocompound_cost
-                .checked_div(2u128)
-                .unwrap();
+            // New stake on a jailed or unbonded validator earns nothing, and the user may not want more on one above
+            // their commission ceiling. The reward goes to the fallback or stays on the host chain
+            let mut delegate_to = validator.clone();
+            if let Some(reason) = reward.and_then(|r| compound_skip_reason(r, registration.max_commission)) {
+                let fallback = registration.fallback_validator.clone().filter(|fallback| {
+                    fallback != &validator
+                        && !calculate_rewards.rewards.iter().any(|r| {
+                            &r.validator == fallback && compound_skip_reason(r, registration.max_commission).is_some()
+                        })
+                });
+                let event = Event::new(if fallback.is_some() { "compound_redirected" } else { "compound_held_back" })
+                    .add_attribute("local_address", registration.local_address.to_string())
+                    .add_attribute("chain_id", registration.chain_id.clone())
+                    .add_attribute("remote_address", registration.remote_address.clone())
+                    .add_attribute("validator", validator.clone())
+                    .add_attribute("reason", reason)
+                    .add_attribute("amount", reward_amount.to_string());
+                match fallback {
+                    Some(fallback) => {
+                        compound_events.push(event.add_attribute("fallback_validator", fallback.clone()));
+                        delegate_to = fallback;
+                    }
+                    None => {
+                        compound_events.push(event);
+                        continue;
+                    }
+                }
+            }
+
+            let relayer_fee = ica_tx_relayer_fee(&supported_chain.ica_tx, &min_ibc_fee);
+            if reserve < relayer_fee {
+                deps.api.debug(format!("WASMDEBUG: Reserve too low to pay the relayers for user: {}", registration.clone().local_address).as_str());
+                unrelayed_registrations.push(format!("{}/{}", registration.chain_id, registration.remote_address));
+                break;
+            }
+
+            // The host chain refuses a delegation above the remaining allowance, pause instead of failing every compound
+            let mut remaining_allowance = grant_allowance.clone();
+            if !consume_grant_allowance(&mut remaining_allowance, &coin(delegation_amount.u128(), supported_chain.denom.clone())) {
+                deps.api.debug(format!("WASMDEBUG: Grant allowance exhausted for user: {}", registration.clone().local_address).as_str());
+                grant_exhausted = true;
+                break;
+            }
+
+            // Delegating to the fallback only withdraws its own rewards, and the host fee is sent before the delegation
+            // withdraws anything, so the validator's rewards are withdrawn first
+            let mut pre_msgs = vec![];
+            let sends_host_fee = host_fee.is_some() && !host_fee_amount.is_zero();
+            if delegate_to != validator || sends_host_fee {
+                pre_msgs.push(get_withdraw_reward_msg(registration.remote_address.clone(), validator.clone()));
+            }
+            let mut escrowed_fee = None;
+            let mut ica_host_fee = None;
+            if let Some(host_fee) = host_fee {
+                // The fee leaves the delegator in the same MsgExec, the keeper is not paid on Neutron for it
+                if sends_host_fee {
+                    let collector = host_fee.collector.clone().unwrap_or_else(|| ica_address.to_string());
+                    pre_msgs.push(get_host_fee_send_msg(
+                        registration.remote_address.clone(),
+                        collector,
+                        host_fee_amount.u128(),
+                        supported_chain.denom.clone(),
+                    ));
+                    // Only counted once the host chain acknowledges the compound, see sudo_response
+                    if host_fee.collector.is_none() {
+                        ica_host_fee = Some(host_fee_amount);
+                    }
+                }
+            } else {
+                // Only if the given user has enough topped up balance, in untrn or a whitelisted denom, to cover protocol fees
+                let Some(fee) = charge_fee(deps.storage, &registration.local_address, supported_chain.autocompound_cost)? else {
+                    deps.api.debug(format!("WASMDEBUG: Not enough balance to autocompound for user: {}", registration.clone().local_address).as_str());
+                    unfunded_registrations.push(format!("{}/{}", registration.chain_id, registration.remote_address));
+                    break;
+                };
+                // Relayers are paid by the ICA tx fee, the autocompound cost is split between the treasury and the keeper
+                // in whatever the user paid it, once the delegation is acknowledged. See sudo_response
+                escrowed_fee = Some(EscrowedFee {
+                    treasury_fee: fee.amount.mul_floor(config.treasury_fee),
+                    keeper: info.sender.clone(),
+                    fee,
+                });
+            }
+            grant_allowance = remaining_allowance;
 
             // Here we know that user can autocompound.
             // Get the delegate submsg accordingly.
             let submsg = get_delegate_submsg(
                 supported_chain.clone().ica_id,
-                supported_chain.clone().ica_address.unwrap().to_string(), 
+                ica_address.to_string(),
                 supported_chain.clone().connection_id,
                 registration.clone().remote_address,
-                validator, // TODO: We should iter validators, querying the cumulated rewards foreach of them
-                calculate_reward.reward.first().unwrap().amount.u128(),
+                delegate_to,
+                delegation_amount.u128(),
                 supported_chain.clone().denom,
-                half_autocompound_cost,
-                None, // TODO: timeout by Config struct, or default defined on helpers.rs?
+                &supported_chain.ica_tx,
+                &min_ibc_fee,
+                pre_msgs,
+                next_reply_id,
+            )?;
+            track_ica_tx(
+                deps.storage,
+                next_reply_id,
+                IcaTxKind::Compound {
+                    local_address: registration.local_address.clone(),
+                    chain_id: registration.chain_id.clone(),
+                    remote_address: registration.remote_address.clone(),
+                    escrowed_fee,
+                    in_flight_expires: Some(in_flight_expires),
+                    host_fee: ica_host_fee,
+                },
+                &supported_chain.ica_tx,
+                &min_ibc_fee,
             )?;
+            next_reply_id += 1;
+            reserve -= relayer_fee;
             delegate_submsgs.push(submsg);
-
-            // Decrease in memory balance for the current user inside the validators iteration
-            /*balance = balance
-                .checked_sub(Uint128::new(supported_chain.autocompound_cost))
-                .unwrap();*/
-            keeper_fee += half_autocompound_cost;
+            sent_txs += 1;
         }
 
-        // Save the new USER_BALANCES for the current user
-        //USER_BALANCES.save(deps.storage, registration.clone().local_address, &balance)?;
+        // The next ICQ result brings the allowance the host chain actually has left
+        let mut updated = registration.clone();
+        updated.grant_allowance = grant_allowance;
+        if grant_exhausted {
+            updated.status = RegistrationStatus::Paused { reason: PauseReason::GrantExhausted };
+        }
+        // Commission changes are reported against the rates of the last compound, see query_commission_changes
+        if sent_txs > 0 {
+            updated.next_compound_height = env.block.height + config.autocompound_threshold;
+            updated.in_flight = Some(InFlight {
+                txs: sent_txs,
+                expires: in_flight_expires,
+            });
+            updated.compounded_commissions = calculate_rewards
+                .rewards
+                .iter()
+                .map(|r| ValidatorCommission {
+                    validator: r.validator.clone(),
+                    rate: r.commission_rate,
+                })
+                .collect();
+        }
+        if updated != registration {
+            user_chain_registrations().save(deps.storage, registration_key, &updated)?;
+        }
     }
 
-    // Return a response only if there are any msgs to send, otherwise throw a ContractError.
-    if !delegate_submsgs.is_empty() {
-        // Bank message to send total_fees to info.sender keeper
-        let bank_msg = BankMsg::Send {
-            to_address: info.sender.to_string(),
-            amount: vec![coin(keeper_fee, "untrn")],
-        };
+    NEXT_REPLY_ID.save(deps.storage, &next_reply_id)?;
+
+    // Return a response only if there are any msgs to send or rewards held back to report, otherwise throw a ContractError.
+    if !delegate_submsgs.is_empty() || !compound_events.is_empty() {
         Ok(Response::new()
             .add_attribute("action", "autocompound")
-            .add_submessages(delegate_submsgs)
-            .add_message(bank_msg))
+            .add_attribute("stale_registrations", stale_registrations.join(","))
+            .add_attribute("incomplete_registrations", incomplete_registrations.join(","))
+            .add_attribute("unfunded_registrations", unfunded_registrations.join(","))
+            .add_attribute("unrelayed_registrations", unrelayed_registrations.join(","))
+            .add_events(compound_events)
+            .add_submessages(delegate_submsgs))
+    } else if !stale_registrations.is_empty() {
+        Err(ContractError::StaleIcqData {
+            registrations: stale_registrations.join(","),
+        })
+    } else if !incomplete_registrations.is_empty() {
+        Err(ContractError::IncompleteIcqData {
+            registrations: incomplete_registrations.join(","),
+        })
+    } else if !unfunded_registrations.is_empty() {
+        Err(ContractError::InsufficientFeeBalance {
+            registrations: unfunded_registrations.join(","),
+        })
+    } else if !unrelayed_registrations.is_empty() {
+        Err(ContractError::InsufficientRelayerReserve {
+            registrations: unrelayed_registrations.join(","),
+        })
     } else {
         Err(ContractError::NoRewardsToAutocompound {})
     }
 }
 
+pub fn withdraw_treasury(
+    deps: DepsMut<NeutronQuery>,
+    info: MessageInfo,
+    denom: String,
+    amount: Option<Uint128>,
+    recipient: Option<String>,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+    let recipient = match recipient {
+        Some(recipient) => deps.api.addr_validate(&recipient)?,
+        None => config.admin,
+    };
+
+    // Only what the treasury holds, the rest of the contract balance belongs to the users
+    let available = TREASURY.may_load(deps.storage, denom.clone())?.unwrap_or_default();
+    let amount = amount.unwrap_or(available);
+    if amount.is_zero() || amount > available {
+        return Err(ContractError::InsufficientTreasury {
+            denom,
+            available,
+            requested: amount,
+        });
+    }
+    if amount == available {
+        TREASURY.remove(deps.storage, denom.clone());
+    } else {
+        TREASURY.save(deps.storage, denom.clone(), &(available - amount))?;
+    }
+    if denom == "untrn" {
+        update_ledger(deps.storage, |ledger| {
+            ledger.treasury = ledger.treasury.checked_sub(amount)?;
+            Ok(())
+        })?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "withdraw_treasury")
+        .add_attribute("recipient", recipient.to_string())
+        .add_attribute("amount", format!("{}{}", amount, denom))
+        .add_message(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![Coin { denom, amount }],
+        }))
+}
+
+/// Transfers host fees collected by a chain ICA back to the contract on Neutron
+pub fn sweep_host_fees(
+    deps: DepsMut<NeutronQuery>,
+    env: Env,
+    info: MessageInfo,
+    chain_id: String,
+    amount: Option<Uint128>,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let chain = SUPPORTED_CHAINS
+        .may_load(deps.storage, chain_id.clone())?
+        .ok_or(ContractError::ChainNotFound {})?;
+    // Fees sent to another collector never reach the ICA
+    let host_fee = chain.host_fee.as_ref().filter(|host_fee| host_fee.collector.is_none());
+    let (Some(ica_address), Some(sweep_channel), Some(sweep_denom)) = (
+        chain.ica_address.clone(),
+        host_fee.and_then(|host_fee| host_fee.sweep_channel.clone()),
+        host_fee.and_then(|host_fee| host_fee.sweep_denom.clone()),
+    ) else {
+        return Err(ContractError::HostFeeSweepNotConfigured { chain_id });
+    };
+
+    let collected = HOST_FEES.may_load(deps.storage, chain_id.clone())?.unwrap_or_default();
+    let amount = amount.unwrap_or(collected);
+    if amount.is_zero() {
+        return Err(ContractError::NoHostFeesToSweep { chain_id });
+    }
+    if amount > collected {
+        return Err(ContractError::InsufficientHostFees {
+            chain_id,
+            available: collected,
+            requested: amount,
+        });
+    }
+    // Credited back if the sweep fails or times out, see settle_ica_tx
+    HOST_FEES.save(deps.storage, chain_id.clone(), &(collected - amount))?;
+
+    let min_ibc_fee = query_min_ibc_fee(deps.as_ref())?.min_fee;
+    let relayer_fee = ica_tx_relayer_fee(&chain.ica_tx, &min_ibc_fee);
+    let reserve = query_reserve(deps.as_ref(), &env)?;
+    if reserve < relayer_fee {
+        return Err(ContractError::InsufficientReserve {
+            required: relayer_fee,
+            available: reserve,
+        });
+    }
+    let reply_id = NEXT_REPLY_ID.load(deps.storage)?;
+    NEXT_REPLY_ID.save(deps.storage, &(reply_id + 1))?;
+    track_ica_tx(
+        deps.storage,
+        reply_id,
+        IcaTxKind::Sweep {
+            chain_id: chain_id.clone(),
+            amount,
+            treasury_denom: Some(sweep_denom),
+        },
+        &chain.ica_tx,
+        &min_ibc_fee,
+    )?;
+    let submsg = get_sweep_submsg(
+        chain.ica_id.clone(),
+        ica_address.to_string(),
+        chain.connection_id.clone(),
+        sweep_channel,
+        env.contract.address.to_string(),
+        amount.u128(),
+        chain.denom.clone(),
+        env.block.time.plus_seconds(chain.ica_tx.timeout_seconds),
+        &chain.ica_tx,
+        &min_ibc_fee,
+        reply_id,
+    );
+
+    Ok(Response::new()
+        .add_attribute("action", "sweep_host_fees")
+        .add_attribute("chain_id", chain_id)
+        .add_attribute("amount", amount.to_string())
+        .add_submessage(submsg))
+}
+
 #[cfg(test)]
 mod tests {
     mod test_update_config {
         use cosmwasm_std::testing::{mock_env, mock_info};
-        use cosmwasm_std::{coins, Addr};
+        use cosmwasm_std::{coins, Addr, Decimal};
 
-        use crate::execute::execute;
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg};
         use crate::state::CONFIG;
@@ -437,6 +1106,9 @@ mod tests {
                     admin: info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
             .unwrap();
@@ -448,27 +1120,127 @@ mod tests {
                     admin: Addr::unchecked(&new_admin),
                     neutron_register_ica_fee: new_fee,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: 10,
+                    max_kv_query_keys: 32,
+                    treasury_fee: Decimal::percent(10),
                 },
             };
 
-            let res = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+            let res = execute_checked(&mut deps, mock_env(), info.clone(), msg).unwrap();
             assert_eq!(0, res.messages.len());
 
             let config = CONFIG.load(deps.as_ref().storage).unwrap();
             assert_eq!(config.admin, new_admin);
             assert_eq!(config.neutron_register_ica_fee, new_fee);
+            assert_eq!(config.treasury_fee, Decimal::percent(10));
+
+            let msg = ExecuteMsg::UpdateConfig {
+                config: crate::state::Config {
+                    treasury_fee: Decimal::percent(101),
+                    ..config
+                },
+            };
+            assert_eq!(
+                execute_checked(&mut deps, mock_env(), mock_info(&new_admin, &[]), msg).unwrap_err(),
+                ContractError::InvalidTreasuryFee { treasury_fee: Decimal::percent(101) }
+            );
+        }
+    }
+
+    mod test_withdraw_treasury {
+        use cosmwasm_std::testing::{mock_env, mock_info};
+        use cosmwasm_std::{coins, from_json, BankMsg, CosmosMsg, Uint128};
+
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
+        use crate::helpers::{credit_user_balance, update_ledger};
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, TreasuryResponse};
+        use crate::query::query;
+        use crate::state::{TREASURY, USER_BALANCES};
+        use crate::testing::helpers::{mock_neutron_dependencies, MockDeps};
+
+        #[test]
+        fn test_withdraw_treasury() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &[]);
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            TREASURY.save(deps.as_mut().storage, "untrn".to_string(), &Uint128::new(300)).unwrap();
+            update_ledger(deps.as_mut().storage, |ledger| {
+                ledger.treasury += Uint128::new(300);
+                Ok(())
+            })
+            .unwrap();
+            // User deposits sit in the same bank balance, but are not the treasury's
+            credit_user_balance(deps.as_mut().storage, &creator_info.sender, Uint128::new(1000)).unwrap();
+            deps.querier.set_balance(&mock_env().contract.address, coins(1300, "untrn"));
+
+            let withdraw = |deps: &mut MockDeps, sender: &str, amount: Option<u128>| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(sender, &[]),
+                    ExecuteMsg::WithdrawTreasury {
+                        denom: "untrn".to_string(),
+                        amount: amount.map(Uint128::new),
+                        recipient: Some("treasury_multisig".to_string()),
+                    },
+                )
+            };
+
+            assert_eq!(withdraw(&mut deps, "user", None).unwrap_err(), ContractError::Unauthorized {});
+            assert_eq!(
+                withdraw(&mut deps, "creator", Some(301)).unwrap_err(),
+                ContractError::InsufficientTreasury {
+                    denom: "untrn".to_string(),
+                    available: Uint128::new(300),
+                    requested: Uint128::new(301),
+                }
+            );
+
+            let res = withdraw(&mut deps, "creator", Some(100)).unwrap();
+            assert_eq!(
+                res.messages[0].msg,
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "treasury_multisig".to_string(),
+                    amount: coins(100, "untrn"),
+                })
+            );
+            withdraw(&mut deps, "creator", None).unwrap();
+
+            let treasury: TreasuryResponse =
+                from_json(query(deps.as_ref(), mock_env(), QueryMsg::Treasury {}).unwrap()).unwrap();
+            assert!(treasury.balances.is_empty());
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, creator_info.sender).unwrap(),
+                Uint128::new(1000)
+            );
         }
     }
 
     mod test_add_supported_chain {
-        use cosmwasm_std::coins;
+        use cosmwasm_std::{coins, Decimal, MessageInfo, Uint128};
         use cosmwasm_std::testing::{mock_env, mock_info};
 
-        use crate::execute::execute;
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg};
-        use crate::state::SUPPORTED_CHAINS;
-        use crate::testing::helpers::mock_neutron_dependencies;
+        use crate::state::{HostFeeConfig, IcaTxConfig, SUPPORTED_CHAINS};
+        use crate::testing::helpers::{mock_neutron_dependencies, MockDeps};
 
         #[test]
         fn test_add_supported_chain() {
@@ -483,6 +1255,9 @@ mod tests {
                     admin: info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
                 .unwrap();
@@ -492,9 +1267,14 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
 
-            let res = execute(deps.as_mut(), mock_env(), info.clone(), msg).unwrap();
+            let res = execute_checked(&mut deps, mock_env(), info.clone(), msg).unwrap();
             assert_eq!(1, res.messages.len());
 
             let chains = SUPPORTED_CHAINS
@@ -508,21 +1288,93 @@ mod tests {
                 .collect::<Vec<_>>();
             assert_eq!(chains.len(), 1);
 
-            let chain = chains.get(0).unwrap();
+            let chain = chains.first().unwrap();
             assert_eq!(chain.0, "chain_id");
             assert_eq!(chain.1.connection_id, "connection_id");
+            assert_eq!(chain.1.ica_tx, IcaTxConfig::default());
+
+            // Fees, timeout and memo of the ICA txs are set per chain
+            let ica_tx = IcaTxConfig {
+                ack_fee: 2000,
+                timeout_fee: 2000,
+                timeout_seconds: 3600,
+                memo: "restake".to_string(),
+            };
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                info.clone(),
+                ExecuteMsg::UpdateSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: Some(ica_tx.clone()),
+                    host_fee: None,
+                    clear_host_fee: None,
+                },
+            )
+            .unwrap();
+            let chain = SUPPORTED_CHAINS.load(deps.as_ref().storage, "chain_id".to_string()).unwrap();
+            assert_eq!(chain.ica_tx, ica_tx);
+
+            // The host fee is kept when not set and only removed with its flag
+            let update_host_fee = |deps: &mut MockDeps, info: &MessageInfo, host_fee: Option<HostFeeConfig>, clear: bool| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::UpdateSupportedChain {
+                        chain_id: "chain_id".to_string(),
+                        connection_id: "connection_id".to_string(),
+                        denom: "denom".to_string(),
+                        autocompound_cost: 100000,
+                        profile: None,
+                        max_icq_staleness: None,
+                        icq_update_period: None,
+                        ica_tx: None,
+                        host_fee,
+                        clear_host_fee: Some(clear),
+                    },
+                )
+            };
+            let host_fee = HostFeeConfig {
+                percentage: Decimal::percent(5),
+                flat: Uint128::zero(),
+                collector: None,
+                sweep_channel: None,
+                sweep_denom: None,
+                grant_spend_limit: None,
+            };
+            update_host_fee(&mut deps, &info, Some(host_fee.clone()), false).unwrap();
+            update_host_fee(&mut deps, &info, None, false).unwrap();
+            let chain = SUPPORTED_CHAINS.load(deps.as_ref().storage, "chain_id".to_string()).unwrap();
+            assert_eq!(chain.host_fee, Some(host_fee.clone()));
+            assert_eq!(chain.ica_tx, ica_tx);
+
+            let err = update_host_fee(&mut deps, &info, Some(host_fee), true).unwrap_err();
+            assert_eq!(err, ContractError::ConflictingUpdate { field: "host_fee".to_string() });
+
+            update_host_fee(&mut deps, &info, None, true).unwrap();
+            let chain = SUPPORTED_CHAINS.load(deps.as_ref().storage, "chain_id".to_string()).unwrap();
+            assert_eq!(chain.host_fee, None);
         }
     }
 
     mod test_register_user {
-        use cosmwasm_std::{coins, Order, StdResult};
+        use cosmwasm_std::{coins, Addr, Order, StdResult, Uint128};
         use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
 
-        use crate::execute::execute;
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg};
-        use crate::state::{NEXT_REPLY_ID, user_chain_registrations};
-        use crate::testing::helpers::mock_neutron_dependencies;
+        use crate::helpers::release_validator_query;
+        use crate::state::{BillingMode, NEXT_REPLY_ID, REPLY_ID_TO_USER_CHAIN_REGISTRATION, user_chain_registrations, USER_BALANCES, VALIDATOR_QUERIES};
+        use crate::testing::helpers::{mock_neutron_dependencies, MockDeps};
 
         #[test]
         fn test_register_user() {
@@ -537,6 +1389,9 @@ mod tests {
                     admin: creator_info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
                 .unwrap();
@@ -546,9 +1401,14 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(
-                deps.as_mut(),
+            execute_checked(
+                &mut deps,
                 mock_env(),
                 creator_info.clone(),
                 add_supported_chain_msg,
@@ -556,11 +1416,12 @@ mod tests {
                 .unwrap();
 
             let mock_api = MockApi::default().with_prefix("cosmos");
+            let valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
             let remote_user_addr = mock_api.addr_make("remote_user");
 
             let info = mock_info("local_user", &coins(1000000, "untrn"));
-            let validator1 = mock_api.addr_make("validator1");
-            let validator2 = mock_api.addr_make("validator2");
+            let validator1 = valoper_mock_api.addr_make("validator1");
+            let validator2 = valoper_mock_api.addr_make("validator2");
             let register_user_msg = ExecuteMsg::RegisterUser {
                 registrations: vec![crate::msg::UserChainRegistrationInput {
                     chain_id: "chain_id".to_string(),
@@ -569,10 +1430,14 @@ mod tests {
                         validator1.clone().to_string(),
                         validator2.clone().to_string(),
                     ],
+                    fallback_validator: None,
+                    max_commission: None,
+                    billing: None,
                 }],
             };
-            let res = execute(deps.as_mut(), mock_env(), info.clone(), register_user_msg).unwrap();
-            assert_eq!(1, res.messages.len());
+            let res = execute_checked(&mut deps, mock_env(), info.clone(), register_user_msg).unwrap();
+            // The user query and one shared query per validator
+            assert_eq!(3, res.messages.len());
 
             let registrations = user_chain_registrations()
                 .range(deps.as_ref().storage, None, None, Order::Ascending)
@@ -580,7 +1445,7 @@ mod tests {
                 .collect::<Vec<_>>();
             assert_eq!(registrations.len(), 1);
 
-            let registration = registrations.get(0).unwrap();
+            let registration = registrations.first().unwrap();
             assert_eq!(
                 registration.0,
                 (
@@ -605,30 +1470,869 @@ mod tests {
                 .unwrap();
             assert_eq!(user_registrations_by_local_address.len(), 1);
             assert_eq!(
-                user_registrations_by_local_address.get(0).unwrap().1,
+                user_registrations_by_local_address.first().unwrap().1,
                 registration.1
             );
 
             let next_reply_id = NEXT_REPLY_ID.load(deps.as_ref().storage).unwrap();
-            assert_eq!(next_reply_id, 2);
-        }
-    }
+            assert_eq!(next_reply_id, 4);
 
-    mod test_topup_user_balance {
-        use crate::execute::execute;
-        use crate::msg::ExecuteMsg;
-        use crate::state::USER_BALANCES;
-        use crate::testing::helpers::mock_neutron_dependencies;
+            // Another user delegating to validator1 reuses its query
+            let other_remote_user_addr = mock_api.addr_make("other_remote_user");
+            let validator3 = valoper_mock_api.addr_make("validator3");
+            let register_user_msg = ExecuteMsg::RegisterUser {
+                registrations: vec![crate::msg::UserChainRegistrationInput {
+                    chain_id: "chain_id".to_string(),
+                    address: other_remote_user_addr.to_string(),
+                    validators: vec![validator1.to_string(), validator3.to_string()],
+                    fallback_validator: None,
+                    max_commission: None,
+                    billing: None,
+                }],
+            };
+            let res = execute_checked(&mut deps, mock_env(), mock_info("other_local_user", &[]), register_user_msg).unwrap();
+            assert_eq!(2, res.messages.len());
+
+            let ref_count = |validator: &Addr| {
+                VALIDATOR_QUERIES
+                    .load(deps.as_ref().storage, ("chain_id".to_string(), validator.to_string()))
+                    .unwrap()
+                    .ref_count
+            };
+            assert_eq!(ref_count(&validator1), 2);
+            assert_eq!(ref_count(&validator2), 1);
+            assert_eq!(ref_count(&validator3), 1);
+        }
+
+        #[test]
+        fn test_register_user_validation() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: Some(2),
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+
+            let add_supported_chain_msg = ExecuteMsg::AddSupportedChain {
+                chain_id: "chain_id".to_string(),
+                connection_id: "connection_id".to_string(),
+                denom: "denom".to_string(),
+                autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
+            };
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info.clone(),
+                add_supported_chain_msg,
+            )
+                .unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let osmo_user_addr = MockApi::default().with_prefix("osmo").addr_make("remote_user").to_string();
+            let valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
+            let validator1 = valoper_mock_api.addr_make("validator1").to_string();
+            let validator2 = valoper_mock_api.addr_make("validator2").to_string();
+            let validator3 = valoper_mock_api.addr_make("validator3").to_string();
+
+            let info = mock_info("local_user", &coins(1000000, "untrn"));
+            let mut register = |chain_id: &str, address: &str, validators: Vec<String>| {
+                execute_checked(
+                    &mut deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::RegisterUser {
+                        registrations: vec![crate::msg::UserChainRegistrationInput {
+                            chain_id: chain_id.to_string(),
+                            address: address.to_string(),
+                            validators,
+                            fallback_validator: None,
+                            max_commission: None,
+                            billing: None,
+                        }],
+                    },
+                )
+            };
+
+            assert_eq!(
+                register("unknown_chain", &remote_user_addr, vec![validator1.clone()]).unwrap_err(),
+                ContractError::ChainNotFound {}
+            );
+            assert_eq!(
+                register("chain_id", "not_an_address", vec![validator1.clone()]).unwrap_err(),
+                ContractError::InvalidRemoteAddress {
+                    address: "not_an_address".to_string(),
+                    expected_prefix: "cosmos".to_string(),
+                }
+            );
+            assert_eq!(
+                register("chain_id", &osmo_user_addr, vec![validator1.clone()]).unwrap_err(),
+                ContractError::InvalidRemoteAddress {
+                    address: osmo_user_addr.clone(),
+                    expected_prefix: "cosmos".to_string(),
+                }
+            );
+            assert_eq!(
+                register("chain_id", &remote_user_addr, vec![]).unwrap_err(),
+                ContractError::NoValidators {}
+            );
+            assert_eq!(
+                register("chain_id", &remote_user_addr, vec![remote_user_addr.clone()]).unwrap_err(),
+                ContractError::InvalidValidatorAddress {
+                    validator: remote_user_addr.clone(),
+                    expected_prefix: "cosmosvaloper".to_string(),
+                }
+            );
+            assert_eq!(
+                register("chain_id", &remote_user_addr, vec![validator1.clone(), validator1.clone()]).unwrap_err(),
+                ContractError::DuplicateValidator {
+                    validator: validator1.clone(),
+                }
+            );
+            assert_eq!(
+                register("chain_id", &remote_user_addr, vec![validator1.clone(), validator2.clone(), validator3]).unwrap_err(),
+                ContractError::TooManyValidators { max: 2, actual: 3 }
+            );
+
+            let res = register("chain_id", &remote_user_addr, vec![validator1.clone(), validator2]).unwrap();
+            assert_eq!(3, res.messages.len());
+
+            // The chain has no host fee to bill on
+            let err = execute_checked(
+                &mut deps,
+                mock_env(),
+                mock_info("other_local_user", &[]),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![crate::msg::UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: MockApi::default().with_prefix("cosmos").addr_make("other_remote_user").to_string(),
+                        validators: vec![validator1],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: Some(BillingMode::HostChain),
+                    }],
+                },
+            )
+            .unwrap_err();
+            assert_eq!(err, ContractError::HostFeeNotSupported { chain_id: "chain_id".to_string() });
+        }
+
+        #[test]
+        fn test_register_user_sharded() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: Some(10), // 2 validators and the grant and withdraw address keys, or 3 validators in a shard
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+                .unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
+            let validators: Vec<String> = (1..=5)
+                .map(|i| valoper_mock_api.addr_make(&format!("validator{}", i)).to_string())
+                .collect();
+
+            let info = mock_info("local_user", &coins(1000000, "untrn"));
+            let res = execute_checked(
+                &mut deps,
+                mock_env(),
+                info.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![crate::msg::UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_user_addr.clone(),
+                        validators: validators.clone(),
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+                .unwrap();
+            // 2 user queries and 5 validator queries
+            assert_eq!(7, res.messages.len());
+
+            let registration = user_chain_registrations()
+                .load(
+                    deps.as_ref().storage,
+                    (info.sender.clone(), "chain_id".to_string(), remote_user_addr.clone()),
+                )
+                .unwrap();
+            assert_eq!(registration.primary_validators(), validators[..2].to_vec());
+            assert_eq!(registration.icq_shards.len(), 1);
+            assert_eq!(registration.icq_shards[0].validators, validators[2..].to_vec());
+            assert_eq!(registration.user_queries().len(), 2);
+
+            // Every shard reply finds its way back to the registration
+            for shard in registration.icq_shards.iter() {
+                assert!(REPLY_ID_TO_USER_CHAIN_REGISTRATION.has(deps.as_ref().storage, shard.reply_id));
+            }
+        }
+
+        #[test]
+        fn test_register_user_query_deposits() {
+            let mut deps = mock_neutron_dependencies();
+            deps.querier.set_query_deposit(coins(1000, "untrn"));
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+                .unwrap();
+
+            let user_api = MockApi::default().with_prefix("cosmos");
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string();
+            let register = |deps: &mut MockDeps, local: &str, funds: u128, remote: &str| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(local, &coins(funds, "untrn")),
+                    ExecuteMsg::RegisterUser {
+                        registrations: vec![crate::msg::UserChainRegistrationInput {
+                            chain_id: "chain_id".to_string(),
+                            address: user_api.addr_make(remote).to_string(),
+                            validators: vec![validator.clone()],
+                            fallback_validator: None,
+                            max_commission: None,
+                            billing: None,
+                        }],
+                    },
+                )
+            };
+
+            // The user query and the validator query, the change stays in the balance
+            let res = register(&mut deps, "first_user", 2500, "first_remote").unwrap();
+            assert_eq!(2, res.messages.len());
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("first_user")).unwrap(), Uint128::new(500));
+
+            // The validator query is shared, only the user query is paid
+            let res = register(&mut deps, "second_user", 1000, "second_remote").unwrap();
+            assert_eq!(1, res.messages.len());
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("second_user")).unwrap(), Uint128::zero());
+
+            // The validator query deposit goes back to the first user once nobody uses it
+            assert!(release_validator_query(deps.as_mut().storage, "chain_id", &validator).unwrap().is_none());
+            release_validator_query(deps.as_mut().storage, "chain_id", &validator).unwrap();
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("first_user")).unwrap(), Uint128::new(1500));
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("second_user")).unwrap(), Uint128::zero());
+
+            assert_eq!(
+                register(&mut deps, "third_user", 0, "third_remote").unwrap_err(),
+                ContractError::NotEnoughFunds { required_amount: 2000, actual_amount: 0 }
+            );
+        }
+
+        #[test]
+        fn test_register_user_key_limit_too_low() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: Some(3),
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+                .unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string();
+            let err = execute_checked(
+                &mut deps,
+                mock_env(),
+                mock_info("local_user", &[]),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![crate::msg::UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_user_addr,
+                        validators: vec![validator],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+                .unwrap_err();
+            assert_eq!(err, ContractError::KvQueryKeyLimitTooLow { max_kv_query_keys: 3 });
+        }
+    }
+
+    mod test_update_registration {
+        use cosmwasm_std::{coins, CosmosMsg, Decimal};
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use neutron_sdk::bindings::msg::NeutronMsg;
+
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, UserChainRegistrationInput};
+        use crate::reply::reply;
+        use crate::state::{user_chain_registrations, RegistrationStatus, VALIDATOR_QUERIES};
+        use crate::testing::helpers::{mock_neutron_dependencies, mock_register_query_reply, MockDeps};
+
+        #[test]
+        fn test_update_registration() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: Some(10), // 2 validators in the primary query
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info,
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+                .unwrap();
+
+            let remote_address = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
+            let v: Vec<String> = (1..=3)
+                .map(|i| valoper_mock_api.addr_make(&format!("validator{}", i)).to_string())
+                .collect();
+            let info = mock_info("local_user", &[]);
+            let update = |deps: &mut MockDeps, validators: Option<Vec<String>>| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::UpdateRegistration {
+                        chain_id: "chain_id".to_string(),
+                        remote_address: remote_address.clone(),
+                        validators,
+                        fallback_validator: None,
+                        max_commission: None,
+                        clear_fallback_validator: None,
+                        clear_max_commission: None,
+                        billing: None,
+                    },
+                )
+            };
+
+            assert_eq!(
+                update(&mut deps, None).unwrap_err(),
+                ContractError::RegistrationNotFound {
+                    chain_id: "chain_id".to_string(),
+                    remote_address: remote_address.clone(),
+                }
+            );
+
+            let mut env = mock_env();
+            env.block.height += 1;
+            execute_checked(
+                &mut deps,
+                env,
+                info.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_address.clone(),
+                        validators: v[..2].to_vec(),
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+                .unwrap();
+            let reg_key = (info.sender.clone(), "chain_id".to_string(), remote_address.clone());
+            let registered = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+
+            assert_eq!(update(&mut deps, None).unwrap_err(), ContractError::RegistrationPending {});
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(registered.delegator_delegations_reply_id, Ok(1))).unwrap();
+
+            // A third validator needs its validator query and a shard, the primary query gets new keys
+            let res = update(&mut deps, Some(v.clone())).unwrap();
+            assert_eq!(3, res.messages.len());
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(updated.validators, v);
+            assert_eq!(updated.primary_validators(), v[..2].to_vec());
+            assert_eq!(updated.icq_shards.len(), 1);
+            assert_eq!(updated.icq_shards[0].validators, v[2..].to_vec());
+            assert_eq!(updated.next_compound_height, registered.next_compound_height);
+            assert_eq!(updated.delegator_delegations_icq_id, Some(1));
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(updated.icq_shards[0].reply_id, Ok(5))).unwrap();
+
+            // Back to a single validator, the shard goes away
+            let res = update(&mut deps, Some(v[..1].to_vec())).unwrap();
+            let removed: Vec<u64> = res
+                .messages
+                .iter()
+                .filter_map(|m| match &m.msg {
+                    CosmosMsg::Custom(NeutronMsg::RemoveInterchainQuery { query_id }) => Some(*query_id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(removed, vec![5]);
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(updated.validators, v[..1].to_vec());
+            assert!(updated.icq_shards.is_empty());
+            assert!(!VALIDATOR_QUERIES.has(deps.as_ref().storage, ("chain_id".to_string(), v[1].clone())));
+            assert!(!VALIDATOR_QUERIES.has(deps.as_ref().storage, ("chain_id".to_string(), v[2].clone())));
+
+            // Only the fallback changes, the validators stay as they are
+            let set_fallback = |deps: &mut MockDeps, fallback: &str| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::UpdateRegistration {
+                        chain_id: "chain_id".to_string(),
+                        remote_address: remote_address.clone(),
+                        validators: None,
+                        fallback_validator: Some(fallback.to_string()),
+                        max_commission: None,
+                        clear_fallback_validator: None,
+                        clear_max_commission: None,
+                        billing: None,
+                    },
+                )
+            };
+            assert_eq!(
+                set_fallback(&mut deps, "not_a_validator").unwrap_err(),
+                ContractError::InvalidValidatorAddress {
+                    validator: "not_a_validator".to_string(),
+                    expected_prefix: "cosmosvaloper".to_string(),
+                }
+            );
+            set_fallback(&mut deps, &v[2]).unwrap();
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(updated.validators, v[..1].to_vec());
+            assert_eq!(updated.fallback_validator, Some(v[2].clone()));
+
+            let set_max_commission = |deps: &mut MockDeps, max_commission: Decimal| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::UpdateRegistration {
+                        chain_id: "chain_id".to_string(),
+                        remote_address: remote_address.clone(),
+                        validators: None,
+                        fallback_validator: None,
+                        max_commission: Some(max_commission),
+                        clear_fallback_validator: None,
+                        clear_max_commission: None,
+                        billing: None,
+                    },
+                )
+            };
+            assert_eq!(
+                set_max_commission(&mut deps, Decimal::percent(101)).unwrap_err(),
+                ContractError::InvalidMaxCommission { max_commission: Decimal::percent(101) }
+            );
+            set_max_commission(&mut deps, Decimal::percent(5)).unwrap();
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(updated.max_commission, Some(Decimal::percent(5)));
+            assert_eq!(updated.fallback_validator, Some(v[2].clone()));
+
+            // Cleared with their flags, one at a time
+            let clear = |deps: &mut MockDeps, fallback_validator: Option<String>, clear_fallback: bool, clear_max_commission: bool| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    info.clone(),
+                    ExecuteMsg::UpdateRegistration {
+                        chain_id: "chain_id".to_string(),
+                        remote_address: remote_address.clone(),
+                        validators: None,
+                        fallback_validator,
+                        max_commission: None,
+                        clear_fallback_validator: Some(clear_fallback),
+                        clear_max_commission: Some(clear_max_commission),
+                        billing: None,
+                    },
+                )
+            };
+            assert_eq!(
+                clear(&mut deps, Some(v[0].clone()), true, false).unwrap_err(),
+                ContractError::ConflictingUpdate { field: "fallback_validator".to_string() }
+            );
+            clear(&mut deps, None, true, false).unwrap();
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(updated.fallback_validator, None);
+            assert_eq!(updated.max_commission, Some(Decimal::percent(5)));
+            clear(&mut deps, None, false, true).unwrap();
+            let updated = user_chain_registrations().load(deps.as_ref().storage, reg_key).unwrap();
+            assert_eq!(updated.max_commission, None);
+        }
+
+        #[test]
+        fn test_update_registration_retries_failed_queries() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+                .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info,
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+                .unwrap();
+
+            let remote_address = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string();
+            let info = mock_info("local_user", &[]);
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                info.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_address.clone(),
+                        validators: vec![validator.clone()],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+                .unwrap();
+            let reg_key = (info.sender.clone(), "chain_id".to_string(), remote_address.clone());
+            let validator_key = ("chain_id".to_string(), validator.clone());
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            let validator_query = VALIDATOR_QUERIES.load(deps.as_ref().storage, validator_key.clone()).unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(registration.delegator_delegations_reply_id, Err("out of gas".to_string()))).unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(validator_query.reply_id, Err("out of gas".to_string()))).unwrap();
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert!(matches!(registration.status, RegistrationStatus::Failed { .. }));
+
+            // Both queries are registered again, the validator query keeps its reference
+            let res = execute_checked(
+                &mut deps,
+                mock_env(),
+                info,
+                ExecuteMsg::UpdateRegistration {
+                    chain_id: "chain_id".to_string(),
+                    remote_address,
+                    validators: None,
+                    fallback_validator: None,
+                    max_commission: None,
+                    clear_fallback_validator: None,
+                    clear_max_commission: None,
+                    billing: None,
+                },
+            )
+                .unwrap();
+            assert_eq!(2, res.messages.len());
+            assert!(res
+                .messages
+                .iter()
+                .all(|m| matches!(m.msg, CosmosMsg::Custom(NeutronMsg::RegisterInterchainQuery { .. }))));
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(registration.status, RegistrationStatus::PendingQuery);
+            let validator_query = VALIDATOR_QUERIES.load(deps.as_ref().storage, validator_key.clone()).unwrap();
+            assert_eq!(validator_query.ref_count, 1);
+            assert_eq!(validator_query.icq_error, None);
+
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(registration.delegator_delegations_reply_id, Ok(7))).unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(validator_query.reply_id, Ok(8))).unwrap();
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key).unwrap();
+            assert_eq!(registration.delegator_delegations_icq_id, Some(7));
+            assert_eq!(registration.status, RegistrationStatus::GrantMissing);
+            assert_eq!(VALIDATOR_QUERIES.load(deps.as_ref().storage, validator_key).unwrap().icq_id, Some(8));
+        }
+    }
+
+    mod test_remove_registration {
+        use cosmwasm_std::{coins, Addr, CosmosMsg, Uint128};
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use neutron_sdk::bindings::msg::NeutronMsg;
+
+        use crate::error::ContractError;
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, UserChainRegistrationInput};
+        use crate::reply::reply;
+        use crate::state::{user_chain_registrations, RegistrationStatus, USER_BALANCES, VALIDATOR_QUERIES};
+        use crate::testing::helpers::{execute_checked, mock_neutron_dependencies, mock_register_query_reply, MockDeps};
+
+        #[test]
+        fn test_remove_registration() {
+            let mut deps = mock_neutron_dependencies();
+            deps.querier.set_query_deposit(coins(1000, "untrn"));
+            let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                creator_info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+            .unwrap();
+
+            let remote_address = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string();
+            let register = |deps: &mut MockDeps, local: &str| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(local, &coins(2000, "untrn")),
+                    ExecuteMsg::RegisterUser {
+                        registrations: vec![UserChainRegistrationInput {
+                            chain_id: "chain_id".to_string(),
+                            address: remote_address.clone(),
+                            validators: vec![validator.clone()],
+                            fallback_validator: None,
+                            max_commission: None,
+                            billing: None,
+                        }],
+                    },
+                )
+            };
+            let remove = |deps: &mut MockDeps, sender: &str, local_address: Option<&str>| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(sender, &[]),
+                    ExecuteMsg::RemoveRegistration {
+                        chain_id: "chain_id".to_string(),
+                        remote_address: remote_address.clone(),
+                        local_address: local_address.map(|a| a.to_string()),
+                    },
+                )
+            };
+            let removed_queries = |msgs: &[cosmwasm_std::SubMsg<NeutronMsg>]| -> Vec<u64> {
+                msgs.iter()
+                    .filter_map(|m| match &m.msg {
+                        CosmosMsg::Custom(NeutronMsg::RemoveInterchainQuery { query_id }) => Some(*query_id),
+                        _ => None,
+                    })
+                    .collect()
+            };
+
+            // Someone else holds the remote address, only the admin can free it
+            register(&mut deps, "squatter").unwrap();
+            let squatter_key = (Addr::unchecked("squatter"), "chain_id".to_string(), remote_address.clone());
+            let registration = user_chain_registrations().load(deps.as_ref().storage, squatter_key.clone()).unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(registration.delegator_delegations_reply_id, Ok(1))).unwrap();
+            let validator_query = VALIDATOR_QUERIES
+                .load(deps.as_ref().storage, ("chain_id".to_string(), validator.clone()))
+                .unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(validator_query.reply_id, Ok(2))).unwrap();
+            assert!(matches!(
+                register(&mut deps, "owner").unwrap_err(),
+                ContractError::RemoteAddressAlreadyRegistered { .. }
+            ));
+            assert_eq!(remove(&mut deps, "owner", Some("squatter")).unwrap_err(), ContractError::Unauthorized {});
+            assert!(matches!(remove(&mut deps, "owner", None).unwrap_err(), ContractError::RegistrationNotFound { .. }));
+
+            let res = remove(&mut deps, "creator", Some("squatter")).unwrap();
+            assert_eq!(removed_queries(&res.messages), vec![1, 2]);
+            assert!(!user_chain_registrations().has(deps.as_ref().storage, squatter_key));
+            assert!(!VALIDATOR_QUERIES.has(deps.as_ref().storage, ("chain_id".to_string(), validator.clone())));
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("squatter")).unwrap(),
+                Uint128::new(2000)
+            );
+
+            // A registration whose query failed to register is removed by its owner and registered again
+            register(&mut deps, "owner").unwrap();
+            let owner_key = (Addr::unchecked("owner"), "chain_id".to_string(), remote_address.clone());
+            let registration = user_chain_registrations().load(deps.as_ref().storage, owner_key.clone()).unwrap();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(registration.delegator_delegations_reply_id, Err("out of gas".to_string())),
+            )
+            .unwrap();
+            // The module took no deposit for the failed query
+            let contract = mock_env().contract.address;
+            let balance = deps.as_ref().querier.query_balance(&contract, "untrn").unwrap().amount;
+            deps.querier.set_balance(&contract, coins(balance.u128() + 1000, "untrn"));
+            let validator_query = VALIDATOR_QUERIES
+                .load(deps.as_ref().storage, ("chain_id".to_string(), validator.clone()))
+                .unwrap();
+            reply(deps.as_mut(), mock_env(), mock_register_query_reply(validator_query.reply_id, Ok(3))).unwrap();
+            let registration = user_chain_registrations().load(deps.as_ref().storage, owner_key.clone()).unwrap();
+            assert!(matches!(registration.status, RegistrationStatus::Failed { .. }));
+
+            let res = remove(&mut deps, "owner", None).unwrap();
+            assert_eq!(removed_queries(&res.messages), vec![3]);
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("owner")).unwrap(), Uint128::new(2000));
+            register(&mut deps, "owner").unwrap();
+        }
+    }
+
+    mod test_topup_user_balance {
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, UserBalanceResponse};
+        use crate::query::query;
+        use crate::state::USER_BALANCES;
+        use crate::testing::helpers::{mock_neutron_dependencies, MockDeps};
         use cosmwasm_std::testing::{mock_env, mock_info};
-        use cosmwasm_std::{coins, Uint128};
+        use cosmwasm_std::{coin, coins, from_json, Decimal, Uint128};
 
         #[test]
         fn test_topup_user_balance() {
             let mut deps = mock_neutron_dependencies();
             let info = mock_info("creator", &coins(1000000, "untrn"));
 
-            let res = execute(
-                deps.as_mut(),
+            let res = execute_checked(
+                &mut deps,
                 mock_env(),
                 info.clone(),
                 ExecuteMsg::TopupUserBalance {},
@@ -641,23 +2345,77 @@ mod tests {
                 .unwrap();
             assert_eq!(balance, Uint128::new(1000000));
         }
+
+        #[test]
+        fn test_topup_fee_denom() {
+            let mut deps = mock_neutron_dependencies();
+            let creator_info = mock_info("creator", &[]);
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                creator_info.clone(),
+                InstantiateMsg {
+                    admin: creator_info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+
+            let user_info = mock_info("user", &[coin(1000, "untrn"), coin(50, "ibc/ATOM")]);
+            let topup = |deps: &mut MockDeps, info| {
+                execute_checked(deps, mock_env(), info, ExecuteMsg::TopupUserBalance {})
+            };
+            let set_fee_denom = |deps: &mut MockDeps, sender: &str, untrn_rate| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(sender, &[]),
+                    ExecuteMsg::SetFeeDenom { denom: "ibc/ATOM".to_string(), untrn_rate },
+                )
+            };
+
+            // Not whitelisted yet
+            assert_eq!(
+                topup(&mut deps, user_info.clone()).unwrap_err(),
+                ContractError::UnsupportedFeeDenom { denom: "ibc/ATOM".to_string() }
+            );
+
+            assert_eq!(set_fee_denom(&mut deps, "user", Some(Decimal::percent(1000))).unwrap_err(), ContractError::Unauthorized {});
+            assert_eq!(
+                set_fee_denom(&mut deps, "creator", Some(Decimal::zero())).unwrap_err(),
+                ContractError::InvalidFeeDenomRate { denom: "ibc/ATOM".to_string(), untrn_rate: Decimal::zero() }
+            );
+            set_fee_denom(&mut deps, "creator", Some(Decimal::percent(1000))).unwrap();
+
+            topup(&mut deps, user_info).unwrap();
+            let balance: UserBalanceResponse = from_json(
+                query(deps.as_ref(), mock_env(), QueryMsg::UserBalance { address: "user".to_string() }).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(balance.balance, 1000);
+            assert_eq!(balance.fee_balances, vec![coin(50, "ibc/ATOM")]);
+        }
     }
 
-    /*mod test_autocompound {
+    mod test_sweep_host_fees {
         use cosmwasm_std::testing::{mock_env, mock_info};
-        use cosmwasm_std::{coins, Uint128};
+        use cosmwasm_std::{coins, Addr, Decimal, Uint128};
 
-        use crate::execute::execute;
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg};
-        use crate::state::{Chain, SUPPORTED_CHAINS, USER_BALANCES, user_chain_registrations};
-        use crate::testing::helpers::mock_neutron_dependencies;
+        use crate::state::{HostFeeConfig, HOST_FEES, SUPPORTED_CHAINS};
+        use crate::testing::helpers::{mock_neutron_dependencies, MockDeps};
 
         #[test]
-        fn test_autocompound() {
+        fn test_sweep_host_fees() {
             let mut deps = mock_neutron_dependencies();
             let creator_info = mock_info("creator", &coins(1000000, "untrn"));
-
             instantiate(
                 deps.as_mut(),
                 mock_env(),
@@ -666,80 +2424,308 @@ mod tests {
                     admin: creator_info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
             .unwrap();
 
-            let add_supported_chain_msg = ExecuteMsg::AddSupportedChain {
-                chain_id: "chain_id".to_string(),
-                connection_id: "connection_id".to_string(),
-                denom: "denom".to_string(),
-                autocompound_cost: 100000,
+            let add_chain = |deps: &mut MockDeps, chain_id: &str, sweep_channel: Option<String>| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    creator_info.clone(),
+                    ExecuteMsg::AddSupportedChain {
+                        chain_id: chain_id.to_string(),
+                        connection_id: "connection_id".to_string(),
+                        denom: "uatom".to_string(),
+                        autocompound_cost: 100000,
+                        profile: None,
+                        max_icq_staleness: None,
+                        icq_update_period: None,
+                        ica_tx: None,
+                        host_fee: Some(HostFeeConfig {
+                            percentage: Decimal::percent(5),
+                            flat: Uint128::zero(),
+                            collector: None,
+                            sweep_channel,
+                            sweep_denom: Some("ibc/uatom".to_string()),
+                            grant_spend_limit: None,
+                        }),
+                    },
+                )
+                .unwrap();
+                SUPPORTED_CHAINS
+                    .update(deps.as_mut().storage, chain_id.to_string(), |chain| -> Result<_, ContractError> {
+                        let mut chain = chain.unwrap();
+                        chain.ica_address = Some(Addr::unchecked("ica_address"));
+                        Ok(chain)
+                    })
+                    .unwrap();
             };
-            execute(
-                deps.as_mut(),
+            add_chain(&mut deps, "chain_id", Some("channel-0".to_string()));
+            add_chain(&mut deps, "no_channel", None);
+
+            let sweep_amount = |deps: &mut MockDeps, sender: &str, chain_id: &str, amount: Option<u128>| {
+                execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info(sender, &[]),
+                    ExecuteMsg::SweepHostFees {
+                        chain_id: chain_id.to_string(),
+                        amount: amount.map(Uint128::new),
+                    },
+                )
+            };
+            let sweep = |deps: &mut MockDeps, sender: &str, chain_id: &str| sweep_amount(deps, sender, chain_id, None);
+
+            assert_eq!(sweep(&mut deps, "user", "chain_id").unwrap_err(), ContractError::Unauthorized {});
+            assert_eq!(
+                sweep(&mut deps, "creator", "no_channel").unwrap_err(),
+                ContractError::HostFeeSweepNotConfigured { chain_id: "no_channel".to_string() }
+            );
+            assert_eq!(
+                sweep(&mut deps, "creator", "chain_id").unwrap_err(),
+                ContractError::NoHostFeesToSweep { chain_id: "chain_id".to_string() }
+            );
+
+            HOST_FEES.save(deps.as_mut().storage, "chain_id".to_string(), &Uint128::new(1500)).unwrap();
+            // Never more than the ICA collected
+            assert_eq!(
+                sweep_amount(&mut deps, "creator", "chain_id", Some(1501)).unwrap_err(),
+                ContractError::InsufficientHostFees {
+                    chain_id: "chain_id".to_string(),
+                    available: Uint128::new(1500),
+                    requested: Uint128::new(1501),
+                }
+            );
+            assert_eq!(
+                sweep(&mut deps, "creator", "chain_id").unwrap_err(),
+                ContractError::InsufficientReserve {
+                    required: Uint128::new(2000),
+                    available: Uint128::zero(),
+                }
+            );
+            assert_eq!(HOST_FEES.load(deps.as_ref().storage, "chain_id".to_string()).unwrap(), Uint128::new(1500));
+            // Relayer fees are paid from the contract's own untrn
+            deps.querier.set_balance(&mock_env().contract.address, coins(10000, "untrn"));
+            let res = sweep(&mut deps, "creator", "chain_id").unwrap();
+            assert_eq!(res.messages.len(), 1);
+            assert_eq!(HOST_FEES.load(deps.as_ref().storage, "chain_id".to_string()).unwrap(), Uint128::zero());
+        }
+    }
+
+    mod test_autocompound {
+        use cosmos_sdk_proto::cosmos::authz::v1beta1::MsgExec;
+        use cosmos_sdk_proto::cosmos::bank::v1beta1::MsgSend;
+        use cosmos_sdk_proto::cosmos::distribution::v1beta1::MsgWithdrawDelegatorReward;
+        use cosmos_sdk_proto::cosmos::staking::v1beta1::MsgDelegate;
+        use cosmos_sdk_proto::traits::Message;
+        use cosmos_sdk_proto::Any;
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, CosmosMsg, Decimal, Response, Uint128};
+        use neutron_sdk::bindings::msg::NeutronMsg;
+
+        use crate::error::ContractError;
+        use crate::helpers::{host_fee_amount, MSG_DELEGATE_TYPE_URL, MSG_SEND_TYPE_URL, MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL};
+        use crate::msg::ExecuteMsg;
+        use crate::state::{
+            user_chain_registrations, BillingMode, HostFeeConfig, InFlight, DEFAULT_ICA_TIMEOUT_SECONDS, HOST_FEES, LEDGER,
+            USER_BALANCES,
+        };
+        use crate::testing::helpers::{
+            execute_checked, mock_compound_ready, MockDeps, MockValidator, MOCK_COMPOUND_COST, MOCK_DELEGATOR, MOCK_ICA_ADDRESS,
+            MOCK_REWARD,
+        };
+
+        const VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
+
+        // Relayer fees are paid from `reserve`, the contract's own untrn
+        fn autocompound(deps: &mut MockDeps, reserve: u128) -> Result<Response<NeutronMsg>, ContractError> {
+            let contract = mock_env().contract.address;
+            let balance = deps.as_ref().querier.query_balance(&contract, "untrn").unwrap().amount;
+            deps.querier.set_balance(&contract, coins(balance.u128() + reserve, "untrn"));
+            execute_checked(
+                deps,
                 mock_env(),
-                creator_info.clone(),
-                add_supported_chain_msg,
+                mock_info("keeper", &[]),
+                ExecuteMsg::Autocompound { delegators_amount: 10 },
             )
-            .unwrap();
+        }
 
-            let local_user_info = mock_info("local_user", &coins(1000000, "untrn"));
+        // The messages of the MsgExec in each SubmitTx
+        fn exec_msgs(res: &Response<NeutronMsg>) -> Vec<Vec<Any>> {
+            res.messages
+                .iter()
+                .filter_map(|m| match &m.msg {
+                    CosmosMsg::Custom(NeutronMsg::SubmitTx { msgs, .. }) => {
+                        Some(MsgExec::decode(msgs[0].value.as_slice()).unwrap().msgs)
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
 
-            let chain = Chain {
-                connection_id: "connection_id".to_string(),
-                ica_id: "ica_id".to_string(),
-                ica_port_id: "ica_port_id".to_string(),
-                autocompound_cost: 100000,
-                denom: "denom".to_string(),
-                ica_address: None,
-                ica_error: None,
+        #[test]
+        fn test_autocompound() {
+            let validator = MockValidator {
+                address: VALIDATOR.to_string(),
+                icq_id: 2,
+                jailed: false,
             };
-            SUPPORTED_CHAINS
-                .save(deps.as_mut().storage, "chain_id".to_string(), &chain)
-                .unwrap();
+            let (mut deps, reg_key) = mock_compound_ready(&[validator], None, BillingMode::Prepaid, None);
+
+            let res = autocompound(&mut deps, 10000).unwrap();
+            let txs = exec_msgs(&res);
+            assert_eq!(txs.len(), 1);
+            assert_eq!(txs[0].len(), 1);
+            assert_eq!(txs[0][0].type_url, MSG_DELEGATE_TYPE_URL);
+            let delegate = MsgDelegate::decode(txs[0][0].value.as_slice()).unwrap();
+            assert_eq!(delegate.delegator_address, MOCK_DELEGATOR);
+            assert_eq!(delegate.validator_address, VALIDATOR);
+            assert_eq!(delegate.amount.unwrap().amount, MOCK_REWARD.to_string());
+
+            // The cost is escrowed until the host chain acknowledges the compound
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, reg_key.0.clone()).unwrap(),
+                Uint128::new(1000000 - MOCK_COMPOUND_COST)
+            );
+            assert_eq!(LEDGER.load(deps.as_ref().storage).unwrap().escrowed_fees, Uint128::new(MOCK_COMPOUND_COST));
 
-            USER_BALANCES
-                .save(
-                    deps.as_mut().storage,
-                    local_user_info.clone().sender,
-                    &Uint128::new(1000000),
-                )
-                .unwrap();
+            // The registration is locked until the tx is settled, and not due before the threshold
+            let env = mock_env();
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key).unwrap();
+            assert_eq!(
+                registration.in_flight,
+                Some(InFlight {
+                    txs: 1,
+                    expires: env.block.time.plus_seconds(DEFAULT_ICA_TIMEOUT_SECONDS),
+                })
+            );
+            assert_eq!(registration.next_compound_height, env.block.height + 100);
+            assert_eq!(autocompound(&mut deps, 10000).unwrap_err(), ContractError::NoRewardsToAutocompound {});
+        }
 
-            user_chain_registrations().save(
-                deps.as_mut().storage,
-                (
-                    local_user_info.clone().sender,
-                    "chain_id".to_string(),
-                    "remote_user".to_string(),
-                ),
-                &crate::state::UserChainRegistration {
-                    local_address: local_user_info.sender,
-                    chain_id: "chain_id".to_string(),
-                    remote_address: "remote_user".to_string(),
-                    validators: vec!["validator".to_string()],
-                    delegator_delegations_reply_id: 1,
-                    delegator_delegations_icq_id: Some(1337),
-                    next_compound_height: 1,
+        #[test]
+        fn test_jailed_validator_is_redirected_to_fallback() {
+            let fallback = MockApi::default().with_prefix("cosmosvaloper").addr_make("fallback").to_string();
+            let jailed = MockValidator {
+                address: VALIDATOR.to_string(),
+                icq_id: 2,
+                jailed: true,
+            };
+            let (mut deps, _) = mock_compound_ready(&[jailed], Some(fallback.clone()), BillingMode::Prepaid, None);
+
+            let res = autocompound(&mut deps, 10000).unwrap();
+            assert_eq!(res.events[0].ty, "compound_redirected");
+            let txs = exec_msgs(&res);
+            assert_eq!(txs.len(), 1);
+            // The rewards of the jailed validator are withdrawn in the same MsgExec, then delegated to the fallback
+            assert_eq!(txs[0].len(), 2);
+            assert_eq!(txs[0][0].type_url, MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL);
+            assert_eq!(
+                MsgWithdrawDelegatorReward::decode(txs[0][0].value.as_slice()).unwrap(),
+                MsgWithdrawDelegatorReward {
+                    delegator_address: MOCK_DELEGATOR.to_string(),
+                    validator_address: VALIDATOR.to_string(),
+                }
+            );
+            assert_eq!(txs[0][1].type_url, MSG_DELEGATE_TYPE_URL);
+            let delegate = MsgDelegate::decode(txs[0][1].value.as_slice()).unwrap();
+            assert_eq!(delegate.validator_address, fallback);
+            assert_eq!(delegate.amount.unwrap().amount, MOCK_REWARD.to_string());
+        }
+
+        #[test]
+        fn test_jailed_validator_without_fallback_is_held_back() {
+            let jailed = MockValidator {
+                address: VALIDATOR.to_string(),
+                icq_id: 2,
+                jailed: true,
+            };
+            let (mut deps, reg_key) = mock_compound_ready(&[jailed], None, BillingMode::Prepaid, None);
+
+            let res = autocompound(&mut deps, 10000).unwrap();
+            assert!(res.messages.is_empty());
+            assert_eq!(res.events.len(), 1);
+            assert_eq!(res.events[0].ty, "compound_held_back");
+            assert!(res.events[0].attributes.iter().any(|a| a.key == "reason" && a.value == "jailed"));
+            // Nothing was sent, nothing is charged
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, reg_key.0).unwrap(), Uint128::new(1000000));
+        }
+
+        #[test]
+        fn test_relayer_fees_need_a_reserve() {
+            let validators = [
+                MockValidator {
+                    address: VALIDATOR.to_string(),
+                    icq_id: 2,
+                    jailed: false,
                 },
-            ).unwrap();
-            
-            
-            // TOOD: Mock rewards on dst_chain
-           // let remote_user_info = mock_info("remote_user", &coins(1000000, "untrn"));
-
-            let keeper_info = mock_info("keeper", &vec![]);
-            let res = execute(
-                deps.as_mut(),
-                mock_env(),
-                keeper_info.clone(),
-                ExecuteMsg::Autocompound {
-                    delegators_amount: 1,
+                MockValidator {
+                    address: MockApi::default().with_prefix("cosmosvaloper").addr_make("validator2").to_string(),
+                    icq_id: 3,
+                    jailed: false,
                 },
-            )
-            .unwrap();
-            assert_eq!(0, res.messages.len());
+            ];
+            let (mut deps, reg_key) = mock_compound_ready(&validators, None, BillingMode::Prepaid, None);
+
+            // The user's balance is not touched to pay the relayers
+            assert_eq!(
+                autocompound(&mut deps, 0).unwrap_err(),
+                ContractError::InsufficientRelayerReserve {
+                    registrations: format!("chain_id/{}", MOCK_DELEGATOR),
+                }
+            );
+
+            // Enough for the ack and timeout fees of one tx, the second validator waits for the next round
+            let res = autocompound(&mut deps, 2000).unwrap();
+            assert_eq!(exec_msgs(&res).len(), 1);
+            let ledger = LEDGER.load(deps.as_ref().storage).unwrap();
+            assert_eq!(ledger.relayer_escrow, Uint128::new(2000));
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, reg_key.0).unwrap(),
+                Uint128::new(1000000 - MOCK_COMPOUND_COST)
+            );
         }
-    }*/
+
+        #[test]
+        fn test_host_fee_is_sent_from_withdrawn_rewards() {
+            let validator = MockValidator {
+                address: VALIDATOR.to_string(),
+                icq_id: 2,
+                jailed: false,
+            };
+            let host_fee = HostFeeConfig {
+                percentage: Decimal::percent(5),
+                flat: Uint128::zero(),
+                collector: None,
+                sweep_channel: None,
+                sweep_denom: None,
+                grant_spend_limit: None,
+            };
+            let (mut deps, reg_key) =
+                mock_compound_ready(&[validator], None, BillingMode::HostChain, Some(host_fee.clone()));
+
+            let res = autocompound(&mut deps, 10000).unwrap();
+            let txs = exec_msgs(&res);
+            assert_eq!(txs.len(), 1);
+            // The delegator may hold nothing liquid, the rewards are withdrawn before the fee leaves
+            let fee = host_fee_amount(&host_fee, Uint128::new(MOCK_REWARD));
+            assert_eq!(txs[0].len(), 3);
+            assert_eq!(txs[0][0].type_url, MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL);
+            assert_eq!(txs[0][1].type_url, MSG_SEND_TYPE_URL);
+            let send = MsgSend::decode(txs[0][1].value.as_slice()).unwrap();
+            assert_eq!(send.to_address, MOCK_ICA_ADDRESS);
+            assert_eq!(send.amount[0].amount, fee.to_string());
+            assert_eq!(txs[0][2].type_url, MSG_DELEGATE_TYPE_URL);
+            let delegate = MsgDelegate::decode(txs[0][2].value.as_slice()).unwrap();
+            assert_eq!(delegate.amount.unwrap().amount, (Uint128::new(MOCK_REWARD) - fee).to_string());
+
+            // Nothing is charged on Neutron, and the fee only counts once the host chain acknowledges the compound
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, reg_key.0).unwrap(), Uint128::new(1000000));
+            assert!(!HOST_FEES.has(deps.as_ref().storage, "chain_id".to_string()));
+        }
+    }
 }
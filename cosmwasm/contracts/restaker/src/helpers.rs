@@ -1,18 +1,898 @@
 use cosmos_sdk_proto::Any;
 use cosmos_sdk_proto::cosmos::{base::v1beta1::Coin, staking::v1beta1::MsgDelegate};
-use cosmos_sdk_proto::cosmos::authz::v1beta1::MsgExec;
+use cosmos_sdk_proto::cosmos::authz::v1beta1::{GenericAuthorization, Grant, MsgExec, MsgGrant};
+use cosmos_sdk_proto::cosmos::bank::v1beta1::{MsgSend, SendAuthorization};
+use cosmos_sdk_proto::cosmos::distribution::v1beta1::MsgWithdrawDelegatorReward;
+use cosmos_sdk_proto::ibc::applications::transfer::v1::MsgTransfer;
+use cosmos_sdk_proto::cosmos::staking::v1beta1::{
+    stake_authorization, AuthorizationType, StakeAuthorization,
+};
+use cosmos_sdk_proto::prost;
 use cosmos_sdk_proto::traits::Message;
-use cosmwasm_std::{coins, Binary, Deps, Env, StdError, SubMsg, Order};
+use cosmwasm_std::{
+    coin, coins, Addr, BankMsg, Binary, Decimal, Deps, Env, Order, QueryRequest, StdError, StdResult, Storage, SubMsg,
+    Timestamp, Uint128,
+};
 use neutron_sdk::bindings::query::NeutronQuery;
+use neutron_sdk::bindings::types::RegisteredQuery;
+use neutron_sdk::interchain_queries::types::QueryPayload;
+use neutron_sdk::proto_types::neutron::interchainqueries::QueryParamsRequest;
+use neutron_sdk::NeutronError;
 use neutron_sdk::bindings::{
     msg::{IbcFee, NeutronMsg},
     types::ProtobufAny,
 };
+use neutron_sdk::sudo::msg::RequestPacket;
+use serde::Deserialize;
 
 use crate::error::ContractError;
-use crate::state::{user_chain_registrations, UserChainRegistration};
+use crate::icq::reconstruct::AuthzGrant;
+use crate::msg::{IcqDataAge, RewardResponse, UserChainRegistrationInput};
+use crate::icq::keys::{
+    create_all_icq_keys_for_user, create_all_icq_keys_for_validator, ValidatorHistoricalRange,
+    USER_KEYS_PER_REGISTRATION, USER_KEYS_PER_VALIDATOR,
+};
+use crate::state::{
+    user_chain_registrations, BillingMode, Chain, EscrowedFee, HostFeeConfig, IcaTx, IcaTxConfig, IcaTxKind, Ledger, IcqShard, PauseReason, QueryDeposit, RegistrationStatus, SdkVersion,
+    UserChainRegistration, ValidatorQuery, ICA_TXS, ICQ_RESULT_COVERAGE, LEDGER, QUERY_DEPOSITS, REPLY_ID_TO_ICA_TX,
+    QUERY_ID_TO_USER_CHAIN_REGISTRATION, QUERY_ID_TO_VALIDATOR_QUERY,
+    REPLY_ID_TO_USER_CHAIN_REGISTRATION, REPLY_ID_TO_VALIDATOR_QUERY, FEE_DENOMS, USER_BALANCES,
+    TREASURY, USER_FEE_BALANCES, VALIDATOR_QUERIES,
+};
+
+pub const MSG_DELEGATE_TYPE_URL: &str = "/cosmos.staking.v1beta1.MsgDelegate";
+pub const MSG_SEND_TYPE_URL: &str = "/cosmos.bank.v1beta1.MsgSend";
+pub const MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL: &str = "/cosmos.distribution.v1beta1.MsgWithdrawDelegatorReward";
+pub const MSG_TRANSFER_TYPE_URL: &str = "/ibc.applications.transfer.v1.MsgTransfer";
+pub const STAKE_AUTHORIZATION_TYPE_URL: &str = "/cosmos.staking.v1beta1.StakeAuthorization";
+pub const SEND_AUTHORIZATION_TYPE_URL: &str = "/cosmos.bank.v1beta1.SendAuthorization";
+pub const GENERIC_AUTHORIZATION_TYPE_URL: &str = "/cosmos.authz.v1beta1.GenericAuthorization";
+pub const MSG_GRANT_TYPE_URL: &str = "/cosmos.authz.v1beta1.MsgGrant";
 
-const DEFAULT_TIMEOUT_SECONDS: u64 = 60 * 60 * 24 * 7 * 2; // 2 weeks TODO: this is a lot, how much? Or we just deprecate this and we always pass it from above.
+const INTERCHAIN_QUERIES_PARAMS_PATH: &str = "/neutron.interchainqueries.Query/Params";
+
+const SUGGESTED_GRANT_DURATION_SECONDS: u64 = 60 * 60 * 24 * 365; // 1 year
+
+
+/// Keeps std errors as they are, so `not_found` and friends survive the conversion
+pub fn neutron_err(err: NeutronError) -> StdError {
+    match err {
+        NeutronError::Std(std_err) => std_err,
+        err => StdError::generic_err(err.to_string()),
+    }
+}
+
+/// Same for contract errors raised in entry points that return std errors (sudo, reply)
+pub fn contract_err(err: ContractError) -> StdError {
+    match err {
+        ContractError::Std(std_err) => std_err,
+        err => StdError::generic_err(err.to_string()),
+    }
+}
+
+/// Checks the remote address and validators against the chain's bech32 prefixes before anything is registered
+pub fn validate_registration_input(
+    chain: &Chain,
+    registration: &UserChainRegistrationInput,
+    max_validators: u64,
+) -> Result<(), ContractError> {
+    let validator_prefix = format!("{}valoper", chain.profile.bech32_prefix);
+
+    validate_remote_address(chain, &registration.address)?;
+
+    if registration.validators.is_empty() {
+        return Err(ContractError::NoValidators {});
+    }
+    if registration.validators.len() as u64 > max_validators {
+        return Err(ContractError::TooManyValidators {
+            max: max_validators,
+            actual: registration.validators.len() as u64,
+        });
+    }
+
+    for (i, validator) in registration.validators.iter().enumerate() {
+        if !is_bech32_with_prefix(validator, &validator_prefix) {
+            return Err(ContractError::InvalidValidatorAddress {
+                validator: validator.clone(),
+                expected_prefix: validator_prefix,
+            });
+        }
+        if registration.validators[..i].contains(validator) {
+            return Err(ContractError::DuplicateValidator {
+                validator: validator.clone(),
+            });
+        }
+    }
+
+    if let Some(max_commission) = registration.max_commission {
+        if max_commission > Decimal::one() {
+            return Err(ContractError::InvalidMaxCommission { max_commission });
+        }
+    }
+
+    if let Some(fallback) = &registration.fallback_validator {
+        if !is_bech32_with_prefix(fallback, &validator_prefix) {
+            return Err(ContractError::InvalidValidatorAddress {
+                validator: fallback.clone(),
+                expected_prefix: validator_prefix,
+            });
+        }
+    }
+
+    if registration.billing == Some(BillingMode::HostChain) && chain.host_fee.is_none() {
+        return Err(ContractError::HostFeeNotSupported {
+            chain_id: registration.chain_id.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn validate_remote_address(chain: &Chain, address: &str) -> Result<(), ContractError> {
+    if !is_bech32_with_prefix(address, &chain.profile.bech32_prefix) {
+        return Err(ContractError::InvalidRemoteAddress {
+            address: address.to_string(),
+            expected_prefix: chain.profile.bech32_prefix.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// The messages the chain ICA executes on behalf of a registered remote address
+pub fn required_msg_type_urls(billing: &BillingMode, fallback_validator: Option<&String>) -> Vec<&'static str> {
+    let mut msg_type_urls = match billing {
+        BillingMode::Prepaid => vec![MSG_DELEGATE_TYPE_URL],
+        // The host fee is sent from the delegator in the same MsgExec
+        BillingMode::HostChain => vec![MSG_DELEGATE_TYPE_URL, MSG_SEND_TYPE_URL],
+    };
+    // A redirected compound withdraws the rewards of the skipped validator, delegating to the fallback only
+    // withdraws the fallback's
+    if fallback_validator.is_some() {
+        msg_type_urls.push(MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL);
+    }
+    msg_type_urls
+}
+
+pub fn validate_host_fee(chain: &Chain, host_fee: &HostFeeConfig) -> Result<(), ContractError> {
+    if host_fee.percentage > Decimal::one() {
+        return Err(ContractError::InvalidHostFee {
+            percentage: host_fee.percentage,
+        });
+    }
+    if let Some(collector) = &host_fee.collector {
+        validate_remote_address(chain, collector)?;
+    }
+    Ok(())
+}
+
+/// The part of `reward` a host-billed compound sends to the collector, never more than the reward itself
+pub fn host_fee_amount(host_fee: &HostFeeConfig, reward: Uint128) -> Uint128 {
+    reward.mul_floor(host_fee.percentage).saturating_add(host_fee.flat).min(reward)
+}
+
+/// Suggests an authorization for `msg_type_url`, and the protobuf encoded MsgGrant the remote address has to sign.
+/// Delegations are restricted to `validators` with a StakeAuthorization when they are known, host fee sends to the
+/// collector with a SendAuthorization when `host_fee_send` gives the collector and a spend limit.
+/// The SendAuthorization of a 0.45 chain has no allow list, only the spend limit bounds it there.
+pub fn build_msg_grant(
+    granter: &str,
+    grantee: &str,
+    msg_type_url: &str,
+    validators: &[String],
+    host_fee_send: Option<(&str, &cosmwasm_std::Coin)>,
+    sdk_version: SdkVersion,
+    expiration: Timestamp,
+) -> (String, Binary) {
+    let authorization = if let (MSG_SEND_TYPE_URL, Some((collector, spend_limit))) = (msg_type_url, host_fee_send) {
+        let mut value = SendAuthorization {
+            spend_limit: vec![Coin {
+                denom: spend_limit.denom.clone(),
+                amount: spend_limit.amount.to_string(),
+            }],
+        }
+        .encode_to_vec();
+        // The allow list (cosmos-sdk 0.47) is not in the generated SendAuthorization yet, it is field 2.
+        // 0.45 rejects the unknown field
+        if sdk_version != SdkVersion::V045 {
+            prost::encoding::string::encode(2, &collector.to_string(), &mut value);
+        }
+        Any {
+            type_url: SEND_AUTHORIZATION_TYPE_URL.to_string(),
+            value,
+        }
+    } else if msg_type_url == MSG_DELEGATE_TYPE_URL && !validators.is_empty() {
+        Any {
+            type_url: STAKE_AUTHORIZATION_TYPE_URL.to_string(),
+            value: StakeAuthorization {
+                max_tokens: None,
+                authorization_type: AuthorizationType::Delegate as i32,
+                validators: Some(stake_authorization::Policy::AllowList(
+                    stake_authorization::Validators {
+                        address: validators.to_vec(),
+                    },
+                )),
+            }
+            .encode_to_vec(),
+        }
+    } else {
+        Any {
+            type_url: GENERIC_AUTHORIZATION_TYPE_URL.to_string(),
+            value: GenericAuthorization {
+                msg: msg_type_url.to_string(),
+            }
+            .encode_to_vec(),
+        }
+    };
+    let authorization_type_url = authorization.type_url.clone();
+
+    let msg_grant = MsgGrant {
+        granter: granter.to_string(),
+        grantee: grantee.to_string(),
+        grant: Some(Grant {
+            authorization: Some(authorization),
+            expiration: Some(cosmos_sdk_proto::Timestamp {
+                seconds: expiration.seconds() as i64,
+                nanos: expiration.subsec_nanos() as i32,
+            }),
+        }),
+    };
+
+    (authorization_type_url, Binary::from(msg_grant.encode_to_vec()))
+}
+
+pub fn suggested_grant_expiration(env: &Env) -> Timestamp {
+    env.block.time.plus_seconds(SUGGESTED_GRANT_DURATION_SECONDS)
+}
+
+// Stargate responses are JSON encoded, only the deposit is of interest
+#[derive(Deserialize)]
+struct InterchainQueriesParamsResponse {
+    params: InterchainQueriesParams,
+}
+
+#[derive(Deserialize)]
+struct InterchainQueriesParams {
+    query_deposit: Vec<cosmwasm_std::Coin>,
+}
+
+/// The deposit the interchainqueries module takes from the contract for every registered query
+pub fn query_icq_deposit(deps: Deps<NeutronQuery>) -> Result<Uint128, ContractError> {
+    let resp: InterchainQueriesParamsResponse = deps.querier.query(&QueryRequest::Stargate {
+        path: INTERCHAIN_QUERIES_PARAMS_PATH.to_string(),
+        data: Binary::from(QueryParamsRequest {}.encode_to_vec()),
+    })?;
+
+    resp.params
+        .query_deposit
+        .into_iter()
+        .try_fold(Uint128::zero(), |total, c| {
+            if c.denom != "untrn" {
+                return Err(ContractError::UnsupportedQueryDeposit { denom: c.denom });
+            }
+            Ok(total + c.amount)
+        })
+}
+
+/// Moves untrn between the ledger buckets. A bucket going below zero means it lost track of the records, the
+/// movement fails instead of panicking
+pub fn update_ledger(storage: &mut dyn Storage, action: impl FnOnce(&mut Ledger) -> StdResult<()>) -> StdResult<()> {
+    let mut ledger = LEDGER.may_load(storage)?.unwrap_or_default();
+    action(&mut ledger)?;
+    LEDGER.save(storage, &ledger)
+}
+
+/// The ledger buckets as the records they summarize add them up
+pub fn tally_ledger(storage: &dyn Storage) -> StdResult<Ledger> {
+    let user_balances = USER_BALANCES
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, balance)| balance))
+        .sum::<StdResult<Uint128>>()?;
+    let icq_deposits = QUERY_DEPOSITS
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, deposit)| deposit.amount))
+        .sum::<StdResult<Uint128>>()?;
+    let treasury = TREASURY.may_load(storage, "untrn".to_string())?.unwrap_or_default();
+    let ica_txs = ICA_TXS
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, ica_tx)| ica_tx))
+        .chain(
+            REPLY_ID_TO_ICA_TX
+                .range(storage, None, None, Order::Ascending)
+                .map(|item| item.map(|(_, ica_tx)| ica_tx)),
+        )
+        .collect::<StdResult<Vec<_>>>()?;
+    let relayer_escrow = ica_txs.iter().map(|ica_tx| ica_tx.ack_fee + ica_tx.timeout_fee).sum();
+    let escrowed_fees = ica_txs
+        .iter()
+        .filter_map(|ica_tx| match &ica_tx.kind {
+            IcaTxKind::Compound { escrowed_fee: Some(escrowed), .. } if escrowed.fee.denom == "untrn" => {
+                Some(escrowed.fee.amount)
+            }
+            _ => None,
+        })
+        .sum();
+
+    Ok(Ledger {
+        user_balances,
+        escrowed_fees,
+        icq_deposits,
+        treasury,
+        relayer_escrow,
+    })
+}
+
+/// Adds untrn sent by the user to their balance
+pub fn credit_user_balance(storage: &mut dyn Storage, user: &Addr, amount: Uint128) -> StdResult<()> {
+    USER_BALANCES.update(storage, user.clone(), |balance| -> StdResult<_> {
+        Ok(balance.unwrap_or_default() + amount)
+    })?;
+    update_ledger(storage, |ledger| {
+        ledger.user_balances = ledger.user_balances.checked_add(amount)?;
+        Ok(())
+    })
+}
+
+/// Takes the deposits of the queries registered under `reply_ids` from the payer's balance
+pub fn charge_query_deposits(
+    storage: &mut dyn Storage,
+    payer: &Addr,
+    reply_ids: &[u64],
+    deposit: Uint128,
+) -> Result<Uint128, ContractError> {
+    let total = deposit * Uint128::from(reply_ids.len() as u64);
+    let balance = USER_BALANCES.may_load(storage, payer.clone())?.unwrap_or_default();
+    if balance < total {
+        return Err(ContractError::NotEnoughFunds {
+            required_amount: total.u128(),
+            actual_amount: balance.u128(),
+        });
+    }
+    USER_BALANCES.save(storage, payer.clone(), &(balance - total))?;
+    update_ledger(storage, |ledger| {
+        ledger.user_balances = ledger.user_balances.checked_sub(total)?;
+        ledger.icq_deposits = ledger.icq_deposits.checked_add(total)?;
+        Ok(())
+    })?;
+
+    for reply_id in reply_ids {
+        QUERY_DEPOSITS.save(
+            storage,
+            *reply_id,
+            &QueryDeposit {
+                payer: payer.clone(),
+                amount: deposit,
+            },
+        )?;
+    }
+
+    Ok(total)
+}
+
+/// Takes `cost` untrn worth of fees from the user, in untrn first, then in the first fee denom that covers it.
+/// The fee is escrowed until the compound it pays for is settled. None when no balance is enough.
+pub fn charge_fee(storage: &mut dyn Storage, user: &Addr, cost: u128) -> Result<Option<cosmwasm_std::Coin>, ContractError> {
+    let balance = USER_BALANCES.may_load(storage, user.clone())?.unwrap_or_default();
+    if balance.u128() >= cost {
+        USER_BALANCES.save(storage, user.clone(), &(balance - Uint128::new(cost)))?;
+        update_ledger(storage, |ledger| {
+            ledger.user_balances = ledger.user_balances.checked_sub(Uint128::new(cost))?;
+            ledger.escrowed_fees = ledger.escrowed_fees.checked_add(Uint128::new(cost))?;
+            Ok(())
+        })?;
+        return Ok(Some(coin(cost, "untrn")));
+    }
+
+    let fee_balances = USER_FEE_BALANCES
+        .prefix(user.clone())
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (denom, balance) in fee_balances {
+        // Balances of a denom taken off the whitelist stay, but can't pay anymore
+        let Some(untrn_rate) = FEE_DENOMS.may_load(storage, denom.clone())? else {
+            continue;
+        };
+        let amount = Uint128::new(cost)
+            .checked_div_ceil(untrn_rate)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
+        if balance >= amount {
+            USER_FEE_BALANCES.save(storage, (user.clone(), denom.clone()), &(balance - amount))?;
+            return Ok(Some(coin(amount.u128(), denom)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Pays out the fee of an acknowledged compound, the treasury keeps its share and the keeper gets the rest
+pub fn settle_escrowed_fee(storage: &mut dyn Storage, escrowed: &EscrowedFee) -> StdResult<Option<BankMsg>> {
+    let EscrowedFee { fee, keeper, treasury_fee } = escrowed;
+    if fee.denom == "untrn" {
+        update_ledger(storage, |ledger| {
+            ledger.escrowed_fees = ledger.escrowed_fees.checked_sub(fee.amount)?;
+            ledger.treasury = ledger.treasury.checked_add(*treasury_fee)?;
+            Ok(())
+        })?;
+    }
+    if !treasury_fee.is_zero() {
+        TREASURY.update(storage, fee.denom.clone(), |balance| -> StdResult<_> {
+            Ok(balance.unwrap_or_default() + treasury_fee)
+        })?;
+    }
+
+    let keeper_fee = fee.amount - treasury_fee;
+    if keeper_fee.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(BankMsg::Send {
+        to_address: keeper.to_string(),
+        amount: coins(keeper_fee.u128(), fee.denom.clone()),
+    }))
+}
+
+/// Credits the fee of a failed compound back to the user, in the denom it was paid in
+pub fn refund_escrowed_fee(storage: &mut dyn Storage, user: &Addr, escrowed: &EscrowedFee) -> StdResult<()> {
+    let fee = &escrowed.fee;
+    if fee.denom != "untrn" {
+        return USER_FEE_BALANCES.update(storage, (user.clone(), fee.denom.clone()), |balance| -> StdResult<_> {
+            Ok(balance.unwrap_or_default() + fee.amount)
+        }).map(|_| ());
+    }
+    update_ledger(storage, |ledger| {
+        ledger.escrowed_fees = ledger.escrowed_fees.checked_sub(fee.amount)?;
+        Ok(())
+    })?;
+    credit_user_balance(storage, user, fee.amount)
+}
+
+/// Credits the deposit of a removed query back to whoever paid it
+pub fn refund_query_deposit(storage: &mut dyn Storage, reply_id: u64) -> StdResult<Option<QueryDeposit>> {
+    let Some(deposit) = QUERY_DEPOSITS.may_load(storage, reply_id)? else {
+        return Ok(None);
+    };
+    QUERY_DEPOSITS.remove(storage, reply_id);
+    credit_user_balance(storage, &deposit.payer, deposit.amount)?;
+    update_ledger(storage, |ledger| {
+        ledger.icq_deposits = ledger.icq_deposits.checked_sub(deposit.amount)?;
+        Ok(())
+    })?;
+
+    Ok(Some(deposit))
+}
+
+/// Records an ICA tx about to be submitted with `reply_id`, its relayer fees go to the feerefunder module meanwhile
+pub fn track_ica_tx(
+    storage: &mut dyn Storage,
+    reply_id: u64,
+    kind: IcaTxKind,
+    ica_tx: &IcaTxConfig,
+    min_ibc_fee: &IbcFee,
+) -> StdResult<()> {
+    let fee = ica_tx_fee(ica_tx, min_ibc_fee);
+    let untrn = |fee: &[cosmwasm_std::Coin]| {
+        fee.iter()
+            .filter(|c| c.denom == "untrn")
+            .map(|c| c.amount)
+            .sum::<Uint128>()
+    };
+    let ica_tx = IcaTx {
+        kind,
+        ack_fee: untrn(&fee.ack_fee),
+        timeout_fee: untrn(&fee.timeout_fee),
+    };
+    update_ledger(storage, |ledger| {
+        ledger.relayer_escrow = ledger.relayer_escrow.checked_add(ica_tx.ack_fee + ica_tx.timeout_fee)?;
+        Ok(())
+    })?;
+    REPLY_ID_TO_ICA_TX.save(storage, reply_id, &ica_tx)
+}
+
+/// Forgets the ICA tx a packet belongs to once it is acknowledged or timed out, the feerefunder module has
+/// paid one of its fees to the relayer and refunded the other by then
+pub fn release_ica_tx(storage: &mut dyn Storage, request: &RequestPacket) -> StdResult<Option<IcaTx>> {
+    let (Some(channel), Some(sequence)) = (request.source_channel.clone(), request.sequence) else {
+        return Ok(None);
+    };
+    let Some(ica_tx) = ICA_TXS.may_load(storage, (channel.clone(), sequence))? else {
+        return Ok(None);
+    };
+    ICA_TXS.remove(storage, (channel, sequence));
+    update_ledger(storage, |ledger| {
+        ledger.relayer_escrow = ledger.relayer_escrow.checked_sub(ica_tx.ack_fee + ica_tx.timeout_fee)?;
+        Ok(())
+    })?;
+
+    Ok(Some(ica_tx))
+}
+
+/// Splits validators into groups whose keys fit in one KV query, the first group goes with the grant key
+pub fn shard_validators(
+    validators: &[String],
+    max_kv_query_keys: u64,
+) -> Result<Vec<Vec<String>>, ContractError> {
+    let primary_capacity =
+        max_kv_query_keys.saturating_sub(USER_KEYS_PER_REGISTRATION) / USER_KEYS_PER_VALIDATOR;
+    if primary_capacity == 0 {
+        return Err(ContractError::KvQueryKeyLimitTooLow { max_kv_query_keys });
+    }
+    let shard_capacity = (max_kv_query_keys / USER_KEYS_PER_VALIDATOR) as usize;
+
+    let (primary, rest) = validators.split_at(validators.len().min(primary_capacity as usize));
+    Ok(std::iter::once(primary.to_vec())
+        .chain(rest.chunks(shard_capacity).map(|c| c.to_vec()))
+        .collect())
+}
+
+/// Takes a reference on the shared query of `validator`, registering it when no registration uses it yet
+/// or when its last registration failed
+pub fn acquire_validator_query(
+    storage: &mut dyn Storage,
+    chain_id: &str,
+    chain: &Chain,
+    validator: &str,
+    next_reply_id: &mut u64,
+) -> Result<Option<SubMsg<NeutronMsg>>, ContractError> {
+    let key = (chain_id.to_string(), validator.to_string());
+
+    let existing = VALIDATOR_QUERIES.may_load(storage, key.clone())?;
+    if let Some(mut validator_query) = existing.clone().filter(|q| q.icq_error.is_none()) {
+        validator_query.ref_count += 1;
+        VALIDATOR_QUERIES.save(storage, key, &validator_query)?;
+        return Ok(None);
+    }
+
+    let ref_count = existing.as_ref().map_or(0, |q| q.ref_count) + 1;
+    register_validator_query(storage, chain, key, existing, ref_count, next_reply_id).map(Some)
+}
+
+/// Registers the shared query of `validator` again if it failed to register, its references are kept
+pub fn retry_validator_query(
+    storage: &mut dyn Storage,
+    chain_id: &str,
+    chain: &Chain,
+    validator: &str,
+    next_reply_id: &mut u64,
+) -> Result<Option<SubMsg<NeutronMsg>>, ContractError> {
+    let key = (chain_id.to_string(), validator.to_string());
+    let Some(failed) = VALIDATOR_QUERIES.may_load(storage, key.clone())?.filter(|q| q.icq_error.is_some()) else {
+        return Ok(None);
+    };
+
+    let ref_count = failed.ref_count;
+    register_validator_query(storage, chain, key, Some(failed), ref_count, next_reply_id).map(Some)
+}
+
+fn register_validator_query(
+    storage: &mut dyn Storage,
+    chain: &Chain,
+    key: (String, String),
+    failed: Option<ValidatorQuery>,
+    ref_count: u64,
+    next_reply_id: &mut u64,
+) -> Result<SubMsg<NeutronMsg>, ContractError> {
+    let reply_id = *next_reply_id;
+    *next_reply_id += 1;
+    if let Some(failed) = failed {
+        REPLY_ID_TO_VALIDATOR_QUERY.remove(storage, failed.reply_id);
+    }
+    VALIDATOR_QUERIES.save(
+        storage,
+        key.clone(),
+        &ValidatorQuery {
+            reply_id,
+            icq_id: None,
+            ref_count,
+            icq_error: None,
+        },
+    )?;
+    REPLY_ID_TO_VALIDATOR_QUERY.save(storage, reply_id, &key)?;
+
+    let icq_msg = NeutronMsg::register_interchain_query(
+        QueryPayload::KV(create_all_icq_keys_for_validator(&chain.profile, key.1)?),
+        chain.connection_id.clone(),
+        chain.icq_update_period,
+    )?;
+
+    Ok(SubMsg::reply_always(icq_msg, reply_id))
+}
+
+/// Drops a reference on the shared query of `validator`, removing the query (and refunding its deposit) once no registration uses it
+pub fn release_validator_query(
+    storage: &mut dyn Storage,
+    chain_id: &str,
+    validator: &str,
+) -> StdResult<Option<NeutronMsg>> {
+    let key = (chain_id.to_string(), validator.to_string());
+    let Some(mut validator_query) = VALIDATOR_QUERIES.may_load(storage, key.clone())? else {
+        return Ok(None);
+    };
+
+    validator_query.ref_count = validator_query.ref_count.saturating_sub(1);
+    if validator_query.ref_count > 0 {
+        VALIDATOR_QUERIES.save(storage, key, &validator_query)?;
+        return Ok(None);
+    }
+
+    VALIDATOR_QUERIES.remove(storage, key);
+    REPLY_ID_TO_VALIDATOR_QUERY.remove(storage, validator_query.reply_id);
+    refund_query_deposit(storage, validator_query.reply_id)?;
+    Ok(validator_query.icq_id.map(|icq_id| {
+        QUERY_ID_TO_VALIDATOR_QUERY.remove(storage, icq_id);
+        NeutronMsg::remove_interchain_query(icq_id)
+    }))
+}
+
+/// Points the queries of a registration at a new validator set. Registered queries get their keys updated,
+/// extra shards are registered and surplus ones removed, validator queries are acquired and released to match.
+/// Queries of a failed registration that could not be registered are registered again.
+/// Returns the messages and the reply ids of the newly registered queries, whose deposits are still to be charged.
+#[allow(clippy::too_many_arguments)]
+pub fn update_registration_queries(
+    storage: &mut dyn Storage,
+    chain: &Chain,
+    registration: &mut UserChainRegistration,
+    validators: Vec<String>,
+    historical_ranges: &[ValidatorHistoricalRange],
+    max_kv_query_keys: u64,
+    next_reply_id: &mut u64,
+) -> Result<(Vec<SubMsg<NeutronMsg>>, Vec<u64>), ContractError> {
+    let primary_icq_id = registration.delegator_delegations_icq_id;
+    let failed = matches!(registration.status, RegistrationStatus::Failed { .. });
+    if primary_icq_id.is_none() && !failed {
+        return Err(ContractError::RegistrationPending {});
+    }
+    let reg_key = (
+        registration.local_address.clone(),
+        registration.chain_id.clone(),
+        registration.remote_address.clone(),
+    );
+    let mut msgs: Vec<SubMsg<NeutronMsg>> = vec![];
+    let mut new_query_reply_ids: Vec<u64> = vec![];
+    let grant_msg_type_urls = required_msg_type_urls(&registration.billing, registration.fallback_validator.as_ref());
+
+    let user_keys = |validators: &[String], grantee: Option<String>, primary: bool| {
+        let ranges = historical_ranges
+            .iter()
+            .filter(|r| validators.contains(&r.validator))
+            .cloned()
+            .collect();
+        create_all_icq_keys_for_user(
+            &chain.profile,
+            registration.remote_address.clone(),
+            validators.to_vec(),
+            Some(ranges),
+            grantee,
+            &grant_msg_type_urls,
+            primary,
+        )
+    };
+
+    for validator in validators.iter().filter(|v| !registration.validators.contains(v)) {
+        if let Some(sub_msg) =
+            acquire_validator_query(storage, &registration.chain_id, chain, validator, next_reply_id)?
+        {
+            new_query_reply_ids.push(sub_msg.id);
+            msgs.push(sub_msg);
+        }
+    }
+    for validator in registration.validators.iter().filter(|v| !validators.contains(v)) {
+        if let Some(msg) = release_validator_query(storage, &registration.chain_id, validator)? {
+            msgs.push(SubMsg::new(msg));
+        }
+    }
+    for validator in validators.iter().filter(|v| failed && registration.validators.contains(v)) {
+        if let Some(sub_msg) = retry_validator_query(storage, &registration.chain_id, chain, validator, next_reply_id)? {
+            new_query_reply_ids.push(sub_msg.id);
+            msgs.push(sub_msg);
+        }
+    }
+
+    let mut groups = shard_validators(&validators, max_kv_query_keys)?.into_iter();
+    let primary_validators = groups.next().unwrap_or_default();
+    let grantee = chain.ica_address.as_ref().map(|a| a.to_string());
+    let primary_keys = user_keys(&primary_validators, grantee, true)?;
+    match primary_icq_id {
+        Some(primary_icq_id) => msgs.push(SubMsg::new(NeutronMsg::update_interchain_query(
+            primary_icq_id,
+            Some(primary_keys),
+            Some(chain.icq_update_period),
+            None,
+        )?)),
+        // The primary query failed to register, it is registered again like a failed shard
+        None => {
+            REPLY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, registration.delegator_delegations_reply_id);
+            let reply_id = *next_reply_id;
+            *next_reply_id += 1;
+            REPLY_ID_TO_USER_CHAIN_REGISTRATION.save(storage, reply_id, &reg_key)?;
+            msgs.push(SubMsg::reply_always(
+                NeutronMsg::register_interchain_query(
+                    QueryPayload::KV(primary_keys),
+                    chain.connection_id.clone(),
+                    chain.icq_update_period,
+                )?,
+                reply_id,
+            ));
+            new_query_reply_ids.push(reply_id);
+            registration.delegator_delegations_reply_id = reply_id;
+        }
+    }
+
+    let mut old_shards = std::mem::take(&mut registration.icq_shards).into_iter();
+    for shard_validators in groups {
+        let keys = user_keys(&shard_validators, None, false)?;
+        match old_shards.next() {
+            Some(IcqShard { reply_id, icq_id: Some(icq_id), .. }) => {
+                msgs.push(SubMsg::new(NeutronMsg::update_interchain_query(
+                    icq_id,
+                    Some(keys),
+                    Some(chain.icq_update_period),
+                    None,
+                )?));
+                registration.icq_shards.push(IcqShard {
+                    validators: shard_validators,
+                    reply_id,
+                    icq_id: Some(icq_id),
+                });
+            }
+            // A shard that failed to register is registered again
+            old_shard => {
+                if let Some(old_shard) = old_shard {
+                    REPLY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, old_shard.reply_id);
+                }
+                let reply_id = *next_reply_id;
+                *next_reply_id += 1;
+                REPLY_ID_TO_USER_CHAIN_REGISTRATION.save(storage, reply_id, &reg_key)?;
+                msgs.push(SubMsg::reply_always(
+                    NeutronMsg::register_interchain_query(
+                        QueryPayload::KV(keys),
+                        chain.connection_id.clone(),
+                        chain.icq_update_period,
+                    )?,
+                    reply_id,
+                ));
+                new_query_reply_ids.push(reply_id);
+                registration.icq_shards.push(IcqShard {
+                    validators: shard_validators,
+                    reply_id,
+                    icq_id: None,
+                });
+            }
+        }
+    }
+    for surplus in old_shards {
+        REPLY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, surplus.reply_id);
+        refund_query_deposit(storage, surplus.reply_id)?;
+        if let Some(icq_id) = surplus.icq_id {
+            QUERY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, icq_id);
+            msgs.push(SubMsg::new(NeutronMsg::remove_interchain_query(icq_id)));
+        }
+    }
+
+    if !new_query_reply_ids.is_empty() && matches!(registration.status, RegistrationStatus::Failed { .. }) {
+        registration.status = RegistrationStatus::PendingQuery;
+    }
+    registration.validators = validators;
+    // The last result was taken with the old key set
+    if let Some(primary_icq_id) = primary_icq_id {
+        ICQ_RESULT_COVERAGE.remove(storage, primary_icq_id);
+    }
+
+    Ok((msgs, new_query_reply_ids))
+}
+
+/// Removes every query of a registration, crediting their deposits back, and drops its references on the
+/// validator queries
+pub fn remove_registration_queries(
+    storage: &mut dyn Storage,
+    registration: &UserChainRegistration,
+) -> StdResult<Vec<SubMsg<NeutronMsg>>> {
+    let mut msgs = vec![];
+    let user_queries = std::iter::once((registration.delegator_delegations_reply_id, registration.delegator_delegations_icq_id))
+        .chain(registration.icq_shards.iter().map(|s| (s.reply_id, s.icq_id)));
+    for (reply_id, icq_id) in user_queries {
+        REPLY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, reply_id);
+        // A query that failed to register had its deposit refunded already
+        refund_query_deposit(storage, reply_id)?;
+        if let Some(icq_id) = icq_id {
+            QUERY_ID_TO_USER_CHAIN_REGISTRATION.remove(storage, icq_id);
+            ICQ_RESULT_COVERAGE.remove(storage, icq_id);
+            msgs.push(SubMsg::new(NeutronMsg::remove_interchain_query(icq_id)));
+        }
+    }
+    for validator in &registration.validators {
+        if let Some(msg) = release_validator_query(storage, &registration.chain_id, validator)? {
+            msgs.push(SubMsg::new(msg));
+        }
+    }
+
+    Ok(msgs)
+}
+
+/// Ages the last submitted ICQ result in local blocks, a query without any result yet is always stale
+pub fn icq_data_age(env: &Env, chain: &Chain, registered_query: &RegisteredQuery) -> IcqDataAge {
+    let last_submitted_local_height = registered_query.last_submitted_result_local_height;
+    let age = env.block.height.saturating_sub(last_submitted_local_height);
+
+    IcqDataAge {
+        last_submitted_local_height,
+        last_submitted_remote_height: registered_query
+            .last_submitted_result_remote_height
+            .revision_height,
+        age,
+        stale: last_submitted_local_height == 0 || age > chain.max_icq_staleness,
+    }
+}
+
+/// Looks for the MsgDelegate grant from the remote address to the chain ICA among the grants seen over ICQ. The other
+/// `msg_type_urls` the compounds execute, like the host fee MsgSend, have to be granted and unexpired as well
+pub fn evaluate_delegate_grant<'a>(
+    grants: &'a [AuthzGrant],
+    granter: &str,
+    ica_address: Option<&str>,
+    msg_type_urls: &[&str],
+    block_time: Timestamp,
+) -> (RegistrationStatus, Option<&'a AuthzGrant>) {
+    let Some(ica_address) = ica_address else {
+        return (RegistrationStatus::GrantMissing, None);
+    };
+    let find_grant = |msg_type_url: &str| {
+        grants
+            .iter()
+            .find(|g| g.granter == granter && g.grantee == ica_address && g.msg_type_url == msg_type_url)
+    };
+    let grant = find_grant(MSG_DELEGATE_TYPE_URL);
+    let other_grants: Vec<_> = msg_type_urls
+        .iter()
+        .filter(|msg_type_url| **msg_type_url != MSG_DELEGATE_TYPE_URL)
+        .map(|msg_type_url| find_grant(msg_type_url))
+        .collect();
+
+    let expired = |g: &AuthzGrant| g.expiration.is_some_and(|expiration| expiration <= block_time);
+
+    let status = match grant {
+        None => RegistrationStatus::GrantMissing,
+        Some(_) if other_grants.iter().any(|g| g.is_none()) => RegistrationStatus::GrantMissing,
+        Some(grant) if expired(grant) || other_grants.iter().flatten().any(|g| expired(g)) => {
+            RegistrationStatus::Paused { reason: PauseReason::GrantExpired }
+        }
+        Some(AuthzGrant { max_tokens: Some(max_tokens), .. }) if max_tokens.amount.is_zero() => {
+            RegistrationStatus::Paused { reason: PauseReason::GrantExhausted }
+        }
+        Some(_) => RegistrationStatus::Active,
+    };
+    (status, grant)
+}
+
+/// Takes `amount` out of a StakeAuthorization allowance, returns false when the host chain would refuse the delegation.
+/// No allowance means the grant has no spend limit.
+pub fn consume_grant_allowance(allowance: &mut Option<cosmwasm_std::Coin>, amount: &cosmwasm_std::Coin) -> bool {
+    match allowance {
+        None => true,
+        Some(allowance) if allowance.denom != amount.denom || allowance.amount < amount.amount => false,
+        Some(allowance) => {
+            allowance.amount -= amount.amount;
+            true
+        }
+    }
+}
+
+/// Why a reward should not be compounded into its validator, None when it can be
+pub fn compound_skip_reason(reward: &RewardResponse, max_commission: Option<Decimal>) -> Option<String> {
+    reward.inactive_reason.clone().or_else(|| {
+        max_commission
+            .filter(|max| reward.commission_rate > *max)
+            .map(|max| format!("commission {} above {}", reward.commission_rate, max))
+    })
+}
+
+// Cosmos addresses are 20 bytes, module and contract accounts are 32 bytes
+fn is_bech32_with_prefix(address: &str, prefix: &str) -> bool {
+    match bech32::decode(address) {
+        Ok((hrp, data)) => {
+            hrp.as_str() == prefix && address == address.to_lowercase() && (data.len() == 20 || data.len() == 32)
+        }
+        Err(_) => false,
+    }
+}
 
 pub fn get_due_user_chain_registrations(
     deps: &Deps<NeutronQuery>,
@@ -24,16 +904,18 @@ pub fn get_due_user_chain_registrations(
     
     let reggies = user_chain_registrations()
         .range(deps.storage, None, None, Order::Ascending)
-        .filter_map(|item| {
-            let reg = item.unwrap().1;
-            if reg.next_compound_height <= current_height {
-                Some(reg)
-            } else {
-                None
+        .filter(|item| match item {
+            Ok((_, reg)) => {
+                // A registration whose last compound isn't settled yet would delegate the same rewards twice
+                reg.next_compound_height <= current_height
+                    && reg.status == RegistrationStatus::Active
+                    && !reg.is_in_flight(env.block.time)
             }
+            Err(_) => true,
         })
         .take(delegators_amount as usize)
-        .collect::<Vec<UserChainRegistration>>();
+        .map(|item| item.map(|(_, reg)| reg))
+        .collect::<StdResult<Vec<UserChainRegistration>>>()?;
     
     /*let result = user_chain_registrations()
         .idx
@@ -50,6 +932,7 @@ pub fn get_due_user_chain_registrations(
     Ok(reggies)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn get_delegate_submsg(
     interchain_account_id: String,
     interchain_account_address: String,
@@ -58,8 +941,10 @@ pub fn get_delegate_submsg(
     validator: String,
     delegation_amount: u128,
     delegation_denom: String,
-    relayer_fee: u128,
-    timeout: Option<u64>,
+    ica_tx: &IcaTxConfig,
+    min_ibc_fee: &IbcFee,
+    pre_msgs: Vec<Any>, // Executed before the delegation, see get_withdraw_reward_msg and get_host_fee_send_msg
+    reply_id: u64,      // See track_ica_tx
 ) -> Result<SubMsg<NeutronMsg>, ContractError> {
     // Get the delegator address from the storage & form the Delegate message.
 
@@ -73,8 +958,7 @@ pub fn get_delegate_submsg(
     };
 
     // Serialize the Delegate message.
-    let mut buf = Vec::new();
-    buf.reserve(delegate_msg.encoded_len());
+    let mut buf = Vec::with_capacity(delegate_msg.encoded_len());
 
     if let Err(e) = delegate_msg.encode(&mut buf) {
         return Err(ContractError::Std(StdError::generic_err(format!(
@@ -85,16 +969,15 @@ pub fn get_delegate_submsg(
 
     // Put the serialized Delegate message to a types.Any protobuf message.
     let delegate_msg = Any {
-        type_url: "/cosmos.staking.v1beta1.MsgDelegate".to_string(),
+        type_url: MSG_DELEGATE_TYPE_URL.to_string(),
         value: buf,
     };
    
     let authz_exec_msg = MsgExec {
         grantee: interchain_account_address,
-        msgs: vec![delegate_msg], 
+        msgs: pre_msgs.into_iter().chain(std::iter::once(delegate_msg)).collect(),
     };
-    let mut buf = Vec::new();
-    buf.reserve(authz_exec_msg.encoded_len());
+    let mut buf = Vec::with_capacity(authz_exec_msg.encoded_len());
     
     if let Err(e) = authz_exec_msg.encode(&mut buf) {
         return Err(ContractError::Std(StdError::generic_err(format!(
@@ -114,23 +997,449 @@ pub fn get_delegate_submsg(
     // See more info about fees here: https://docs.neutron.org/neutron/modules/interchain-txs/messages#msgsubmittx
     // and here: https://docs.neutron.org/neutron/modules/feerefunder/overview
     // TODO_NICE: Relayers should be paid as the Keeper network addys!
-    let fee = IbcFee {
-        recv_fee: vec![], // must be empty
-        // ack_fee: vec![],
-        ack_fee: coins(relayer_fee, "untrn"),
-        // timeout_fee: vec![],
-        timeout_fee: coins(relayer_fee, "untrn"),
-    };
+    let fee = ica_tx_fee(ica_tx, min_ibc_fee);
 
     // Form the neutron SubmitTx message containing the binary Delegate message.
     let cosmos_msg = NeutronMsg::submit_tx(
         connection_id,
         interchain_account_id.clone(),
         vec![authz_exec_msg],
-        "InterChadz ruleZ".to_string(),
-        timeout.unwrap_or(DEFAULT_TIMEOUT_SECONDS),
+        ica_tx.memo.clone(),
+        ica_tx.timeout_seconds,
         fee,
     );
 
-    Ok(SubMsg::new(cosmos_msg))
+    Ok(SubMsg::reply_on_success(cosmos_msg, reply_id))
+}
+
+/// Withdraws the rewards of `validator` to the delegator, for a compound delegating them to another validator
+pub fn get_withdraw_reward_msg(delegator: String, validator: String) -> Any {
+    Any {
+        type_url: MSG_WITHDRAW_DELEGATOR_REWARD_TYPE_URL.to_string(),
+        value: MsgWithdrawDelegatorReward {
+            delegator_address: delegator,
+            validator_address: validator,
+        }
+        .encode_to_vec(),
+    }
+}
+
+/// The MsgSend of a host-billed compound, paying the host fee from the delegator's rewards to the collector
+pub fn get_host_fee_send_msg(
+    delegator: String,
+    collector: String,
+    amount: u128,
+    denom: String,
+) -> Any {
+    Any {
+        type_url: MSG_SEND_TYPE_URL.to_string(),
+        value: MsgSend {
+            from_address: delegator,
+            to_address: collector,
+            amount: vec![Coin {
+                denom,
+                amount: amount.to_string(),
+            }],
+        }
+        .encode_to_vec(),
+    }
+}
+
+/// An ICA tx transferring the host fees the ICA holds to `receiver` on Neutron
+#[allow(clippy::too_many_arguments)]
+pub fn get_sweep_submsg(
+    interchain_account_id: String,
+    interchain_account_address: String,
+    connection_id: String,
+    source_channel: String,
+    receiver: String,
+    amount: u128,
+    denom: String,
+    timeout_timestamp: Timestamp,
+    ica_tx: &IcaTxConfig,
+    min_ibc_fee: &IbcFee,
+    reply_id: u64, // See track_ica_tx
+) -> SubMsg<NeutronMsg> {
+    let transfer_msg = MsgTransfer {
+        source_port: "transfer".to_string(),
+        source_channel,
+        token: Some(Coin {
+            denom,
+            amount: amount.to_string(),
+        }),
+        sender: interchain_account_address,
+        receiver,
+        timeout_height: None,
+        timeout_timestamp: timeout_timestamp.nanos(),
+    };
+    let transfer_msg = ProtobufAny {
+        type_url: MSG_TRANSFER_TYPE_URL.to_string(),
+        value: Binary::from(transfer_msg.encode_to_vec()),
+    };
+
+    SubMsg::reply_on_success(
+        NeutronMsg::submit_tx(
+            connection_id,
+            interchain_account_id,
+            vec![transfer_msg],
+            ica_tx.memo.clone(),
+            ica_tx.timeout_seconds,
+            ica_tx_fee(ica_tx, min_ibc_fee),
+        ),
+        reply_id,
+    )
+}
+
+/// The untrn an ICA tx of a chain sends to the feerefunder module, see ica_tx_fee
+pub fn ica_tx_relayer_fee(ica_tx: &IcaTxConfig, min_ibc_fee: &IbcFee) -> Uint128 {
+    let fee = ica_tx_fee(ica_tx, min_ibc_fee);
+    fee.ack_fee
+        .iter()
+        .chain(fee.timeout_fee.iter())
+        .filter(|c| c.denom == "untrn")
+        .map(|c| c.amount)
+        .sum()
+}
+
+/// untrn the contract holds on top of its liabilities, nothing else can pay the relayers of its ICA txs
+pub fn query_reserve(deps: Deps<NeutronQuery>, env: &Env) -> StdResult<Uint128> {
+    let ledger = LEDGER.may_load(deps.storage)?.unwrap_or_default();
+    let bank_balance = deps.querier.query_balance(&env.contract.address, "untrn")?.amount;
+    Ok(bank_balance.saturating_sub(ledger.liabilities()))
+}
+
+/// The relayer fee of a chain, raised to Neutron's MinIbcFee so the transaction is never refused for it
+pub fn ica_tx_fee(ica_tx: &IcaTxConfig, min_ibc_fee: &IbcFee) -> IbcFee {
+    IbcFee {
+        recv_fee: vec![], // must be empty
+        ack_fee: at_least_untrn(ica_tx.ack_fee, &min_ibc_fee.ack_fee),
+        timeout_fee: at_least_untrn(ica_tx.timeout_fee, &min_ibc_fee.timeout_fee),
+    }
+}
+
+// Other denoms of the minimum are paid as they are
+fn at_least_untrn(amount: u128, min_fee: &[cosmwasm_std::Coin]) -> Vec<cosmwasm_std::Coin> {
+    let min_untrn = min_fee
+        .iter()
+        .find(|c| c.denom == "untrn")
+        .map(|c| c.amount.u128())
+        .unwrap_or_default();
+    let amount = amount.max(min_untrn);
+    (amount > 0)
+        .then(|| coin(amount, "untrn"))
+        .into_iter()
+        .chain(min_fee.iter().filter(|c| c.denom != "untrn").cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    mod test_ica_tx_fee {
+        use cosmwasm_std::coin;
+        use neutron_sdk::bindings::msg::IbcFee;
+
+        use crate::helpers::ica_tx_fee;
+        use crate::state::IcaTxConfig;
+
+        #[test]
+        fn test_ica_tx_fee() {
+            let min_ibc_fee = IbcFee {
+                recv_fee: vec![],
+                ack_fee: vec![coin(1000, "untrn")],
+                timeout_fee: vec![coin(1000, "untrn"), coin(5, "ibc/ATOM")],
+            };
+            let ica_tx = IcaTxConfig {
+                ack_fee: 2500,
+                timeout_fee: 500,
+                ..IcaTxConfig::default()
+            };
+
+            // The ack fee is above the minimum, the timeout fee is raised to it
+            let fee = ica_tx_fee(&ica_tx, &min_ibc_fee);
+            assert!(fee.recv_fee.is_empty());
+            assert_eq!(fee.ack_fee, vec![coin(2500, "untrn")]);
+            assert_eq!(fee.timeout_fee, vec![coin(1000, "untrn"), coin(5, "ibc/ATOM")]);
+
+            // Nothing configured and no minimum, nothing paid
+            let fee = ica_tx_fee(&IcaTxConfig::default(), &IbcFee {
+                recv_fee: vec![],
+                ack_fee: vec![],
+                timeout_fee: vec![],
+            });
+            assert!(fee.ack_fee.is_empty());
+            assert!(fee.timeout_fee.is_empty());
+        }
+    }
+
+    mod test_update_ledger {
+        use cosmwasm_std::testing::mock_dependencies;
+        use cosmwasm_std::{Addr, StdError, Uint128};
+
+        use crate::helpers::{credit_user_balance, update_ledger};
+        use crate::state::LEDGER;
+
+        #[test]
+        fn test_update_ledger_underflow() {
+            let mut deps = mock_dependencies();
+            credit_user_balance(deps.as_mut().storage, &Addr::unchecked("user"), Uint128::new(100)).unwrap();
+
+            // A bucket never goes below zero, the movement fails and leaves the ledger as it was
+            let err = update_ledger(deps.as_mut().storage, |ledger| {
+                ledger.user_balances = ledger.user_balances.checked_sub(Uint128::new(101))?;
+                Ok(())
+            })
+            .unwrap_err();
+            assert!(matches!(err, StdError::Overflow { .. }));
+            assert_eq!(LEDGER.load(deps.as_ref().storage).unwrap().user_balances, Uint128::new(100));
+        }
+    }
+
+    mod test_host_fee_amount {
+        use cosmwasm_std::{Decimal, Uint128};
+
+        use crate::helpers::host_fee_amount;
+        use crate::state::HostFeeConfig;
+
+        #[test]
+        fn test_host_fee_amount() {
+            let host_fee = HostFeeConfig {
+                percentage: Decimal::percent(5),
+                flat: Uint128::new(100),
+                collector: None,
+                sweep_channel: None,
+                sweep_denom: None,
+                grant_spend_limit: None,
+            };
+            assert_eq!(host_fee_amount(&host_fee, Uint128::new(10000)), Uint128::new(600));
+            // Never more than the reward
+            assert_eq!(host_fee_amount(&host_fee, Uint128::new(50)), Uint128::new(50));
+        }
+    }
+
+    mod test_evaluate_delegate_grant {
+        use cosmwasm_std::Timestamp;
+
+        use crate::helpers::{evaluate_delegate_grant, MSG_DELEGATE_TYPE_URL, MSG_SEND_TYPE_URL};
+        use crate::icq::reconstruct::AuthzGrant;
+        use crate::state::{PauseReason, RegistrationStatus};
+
+        fn grant(msg_type_url: &str, expiration: u64) -> AuthzGrant {
+            AuthzGrant {
+                granter: "granter".to_string(),
+                grantee: "ica".to_string(),
+                msg_type_url: msg_type_url.to_string(),
+                authorization_type_url: "/cosmos.authz.v1beta1.GenericAuthorization".to_string(),
+                expiration: Some(Timestamp::from_seconds(expiration)),
+                max_tokens: None,
+            }
+        }
+
+        #[test]
+        fn test_host_fee_send_grant_is_required() {
+            let now = Timestamp::from_seconds(100);
+            let required = [MSG_DELEGATE_TYPE_URL, MSG_SEND_TYPE_URL];
+            let status = |grants: &[AuthzGrant]| evaluate_delegate_grant(grants, "granter", Some("ica"), &required, now).0;
+
+            assert_eq!(status(&[grant(MSG_DELEGATE_TYPE_URL, 200)]), RegistrationStatus::GrantMissing);
+            assert_eq!(
+                status(&[grant(MSG_DELEGATE_TYPE_URL, 200), grant(MSG_SEND_TYPE_URL, 100)]),
+                RegistrationStatus::Paused { reason: PauseReason::GrantExpired }
+            );
+            assert_eq!(
+                status(&[grant(MSG_DELEGATE_TYPE_URL, 200), grant(MSG_SEND_TYPE_URL, 200)]),
+                RegistrationStatus::Active
+            );
+            // The delegate grant is the one the registration keeps track of
+            let grants = [grant(MSG_SEND_TYPE_URL, 200), grant(MSG_DELEGATE_TYPE_URL, 300)];
+            let (_, delegate_grant) = evaluate_delegate_grant(&grants, "granter", Some("ica"), &required, now);
+            assert_eq!(delegate_grant.unwrap().msg_type_url, MSG_DELEGATE_TYPE_URL);
+        }
+    }
+
+    mod test_build_msg_grant {
+        use cosmos_sdk_proto::cosmos::authz::v1beta1::MsgGrant;
+        use cosmos_sdk_proto::cosmos::bank::v1beta1::SendAuthorization;
+        use cosmos_sdk_proto::prost::{self, Message};
+        use cosmwasm_std::{coin, Timestamp};
+
+        use crate::helpers::{build_msg_grant, MSG_SEND_TYPE_URL, SEND_AUTHORIZATION_TYPE_URL};
+        use crate::state::SdkVersion;
+
+        // Field 2 of a SendAuthorization, the generated type has no allow list
+        fn allow_list(mut value: &[u8]) -> Vec<String> {
+            let mut allow_list = vec![];
+            while !value.is_empty() {
+                let (tag, wire_type) = prost::encoding::decode_key(&mut value).unwrap();
+                if tag == 2 {
+                    let mut address = String::new();
+                    prost::encoding::string::merge(wire_type, &mut address, &mut value, Default::default()).unwrap();
+                    allow_list.push(address);
+                } else {
+                    prost::encoding::skip_field(wire_type, tag, &mut value, Default::default()).unwrap();
+                }
+            }
+            allow_list
+        }
+
+        #[test]
+        fn test_host_fee_send_grant_per_sdk_version() {
+            let spend_limit = coin(1000, "uatom");
+            for (sdk_version, expected_allow_list) in [
+                (SdkVersion::V045, vec![]),
+                (SdkVersion::V047, vec!["collector".to_string()]),
+                (SdkVersion::V050, vec!["collector".to_string()]),
+            ] {
+                let (authorization_type_url, msg_grant) = build_msg_grant(
+                    "granter",
+                    "ica",
+                    MSG_SEND_TYPE_URL,
+                    &[],
+                    Some(("collector", &spend_limit)),
+                    sdk_version,
+                    Timestamp::from_seconds(100),
+                );
+                assert_eq!(authorization_type_url, SEND_AUTHORIZATION_TYPE_URL);
+
+                let msg_grant = MsgGrant::decode(msg_grant.as_slice()).unwrap();
+                let authorization = msg_grant.grant.unwrap().authorization.unwrap();
+                assert_eq!(authorization.type_url, SEND_AUTHORIZATION_TYPE_URL);
+                let send_authorization = SendAuthorization::decode(authorization.value.as_slice()).unwrap();
+                assert_eq!(send_authorization.spend_limit.len(), 1);
+                assert_eq!(send_authorization.spend_limit[0].amount, "1000");
+                // 0.45 has no allow list, its SendAuthorization only takes the spend limit
+                assert_eq!(allow_list(&authorization.value), expected_allow_list, "{:?}", sdk_version);
+            }
+        }
+    }
+
+    mod test_charge_fee {
+        use cosmwasm_std::testing::mock_dependencies;
+        use cosmwasm_std::{coin, Addr, Decimal, Uint128};
+
+        use crate::helpers::{charge_fee, credit_user_balance};
+        use crate::state::{FEE_DENOMS, USER_BALANCES, USER_FEE_BALANCES};
+
+        #[test]
+        fn test_charge_fee() {
+            let mut deps = mock_dependencies();
+            let storage = deps.as_mut().storage;
+            let user = Addr::unchecked("user");
+            credit_user_balance(storage, &user, Uint128::new(30)).unwrap();
+            USER_FEE_BALANCES.save(storage, (user.clone(), "ibc/ATOM".to_string()), &Uint128::new(5)).unwrap();
+            USER_FEE_BALANCES.save(storage, (user.clone(), "ibc/OSMO".to_string()), &Uint128::new(100)).unwrap();
+            FEE_DENOMS.save(storage, "ibc/ATOM".to_string(), &Decimal::percent(1000)).unwrap();
+
+            // untrn first
+            assert_eq!(charge_fee(storage, &user, 25).unwrap(), Some(coin(25, "untrn")));
+            // Then the whitelisted denoms, rounded up: 25 untrn at 10 untrn per unit is 3 units
+            assert_eq!(charge_fee(storage, &user, 25).unwrap(), Some(coin(3, "ibc/ATOM")));
+            // Not whitelisted, the OSMO balance can't pay
+            assert_eq!(charge_fee(storage, &user, 25).unwrap(), None);
+            assert_eq!(USER_BALANCES.load(storage, user.clone()).unwrap(), Uint128::new(5));
+            assert_eq!(USER_FEE_BALANCES.load(storage, (user, "ibc/ATOM".to_string())).unwrap(), Uint128::new(2));
+        }
+    }
+
+    mod test_validator_query {
+        use cosmwasm_std::testing::mock_dependencies;
+        use cosmwasm_std::CosmosMsg;
+        use neutron_sdk::bindings::msg::NeutronMsg;
+
+        use crate::helpers::{acquire_validator_query, release_validator_query, retry_validator_query};
+        use crate::state::{
+            Chain, ChainProfile, IcaTxConfig, ValidatorQuery, QUERY_ID_TO_VALIDATOR_QUERY, REPLY_ID_TO_VALIDATOR_QUERY,
+            VALIDATOR_QUERIES,
+        };
+
+        const VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
+
+        fn chain() -> Chain {
+            Chain {
+                connection_id: "connection_id".to_string(),
+                ica_id: "ica_id".to_string(),
+                ica_port_id: "ica_port_id".to_string(),
+                autocompound_cost: 0,
+                denom: "denom".to_string(),
+                ica_address: None,
+                ica_error: None,
+                profile: ChainProfile::default(),
+                max_icq_staleness: 100,
+                icq_update_period: 10,
+                ica_tx: IcaTxConfig::default(),
+                host_fee: None,
+            }
+        }
+
+        fn validator_query(storage: &dyn cosmwasm_std::Storage) -> Option<ValidatorQuery> {
+            VALIDATOR_QUERIES.may_load(storage, ("chain_id".to_string(), VALIDATOR.to_string())).unwrap()
+        }
+
+        #[test]
+        fn test_acquire_and_release() {
+            let mut deps = mock_dependencies();
+            let storage = deps.as_mut().storage;
+            let mut next_reply_id = 10;
+
+            // The first registration registers the query, the second one shares it
+            let sub_msg = acquire_validator_query(storage, "chain_id", &chain(), VALIDATOR, &mut next_reply_id).unwrap();
+            assert_eq!(sub_msg.unwrap().id, 10);
+            assert!(acquire_validator_query(storage, "chain_id", &chain(), VALIDATOR, &mut next_reply_id)
+                .unwrap()
+                .is_none());
+            assert_eq!(next_reply_id, 11);
+            assert_eq!(validator_query(storage).unwrap().ref_count, 2);
+
+            // Set by the register query reply
+            let mut query = validator_query(storage).unwrap();
+            query.icq_id = Some(7);
+            VALIDATOR_QUERIES.save(storage, ("chain_id".to_string(), VALIDATOR.to_string()), &query).unwrap();
+            QUERY_ID_TO_VALIDATOR_QUERY
+                .save(storage, 7, &("chain_id".to_string(), VALIDATOR.to_string()))
+                .unwrap();
+
+            // Still used by one registration
+            assert_eq!(release_validator_query(storage, "chain_id", VALIDATOR).unwrap(), None);
+            assert_eq!(validator_query(storage).unwrap().ref_count, 1);
+
+            // The last one removes it
+            assert_eq!(
+                release_validator_query(storage, "chain_id", VALIDATOR).unwrap(),
+                Some(NeutronMsg::remove_interchain_query(7))
+            );
+            assert!(validator_query(storage).is_none());
+            assert!(!REPLY_ID_TO_VALIDATOR_QUERY.has(storage, 10));
+            assert!(!QUERY_ID_TO_VALIDATOR_QUERY.has(storage, 7));
+            assert_eq!(release_validator_query(storage, "chain_id", VALIDATOR).unwrap(), None);
+        }
+
+        #[test]
+        fn test_failed_query_is_registered_again() {
+            let mut deps = mock_dependencies();
+            let storage = deps.as_mut().storage;
+            let mut next_reply_id = 10;
+
+            acquire_validator_query(storage, "chain_id", &chain(), VALIDATOR, &mut next_reply_id).unwrap();
+            let mut query = validator_query(storage).unwrap();
+            query.icq_error = Some("failed".to_string());
+            VALIDATOR_QUERIES.save(storage, ("chain_id".to_string(), VALIDATOR.to_string()), &query).unwrap();
+
+            // The next registration registers it again and keeps the first one's reference
+            let sub_msg = acquire_validator_query(storage, "chain_id", &chain(), VALIDATOR, &mut next_reply_id)
+                .unwrap()
+                .unwrap();
+            assert_eq!(sub_msg.id, 11);
+            assert!(matches!(sub_msg.msg, CosmosMsg::Custom(NeutronMsg::RegisterInterchainQuery { .. })));
+            let query = validator_query(storage).unwrap();
+            assert_eq!(query.ref_count, 2);
+            assert_eq!(query.icq_error, None);
+            assert!(!REPLY_ID_TO_VALIDATOR_QUERY.has(storage, 10));
+            assert!(REPLY_ID_TO_VALIDATOR_QUERY.has(storage, 11));
+
+            // Only failed queries are retried
+            assert!(retry_validator_query(storage, "chain_id", &chain(), VALIDATOR, &mut next_reply_id)
+                .unwrap()
+                .is_none());
+            assert_eq!(next_reply_id, 12);
+        }
+    }
 }
@@ -1,151 +1,315 @@
 use bech32::{Bech32, Hrp};
 use byteorder::{ByteOrder, LittleEndian};
-use cosmwasm_std::Binary;
+use cosmwasm_std::{Binary, StdError};
 use neutron_sdk::bindings::types::KVKey;
 use neutron_sdk::interchain_queries::helpers::{decode_and_convert, length_prefix};
-use neutron_sdk::interchain_queries::v045::types::STAKING_STORE_KEY;
-use neutron_sdk::interchain_queries::v047::helpers::create_delegation_key;
-use neutron_sdk::interchain_queries::v047::helpers::create_validator_key;
-use neutron_sdk::NeutronResult;
+use neutron_sdk::interchain_queries::{v045, v047};
+use neutron_sdk::{NeutronError, NeutronResult};
 
-pub const DISTRIBUTION_STORE_PREFIX: &str = "distribution";
+use crate::state::{ChainProfile, SdkVersion};
+
+// x/distribution has no constants in neutron-sdk, these are the same in 0.45, 0.47 and 0.50
+const DISTRIBUTION_STORE_DELEGATOR_WITHDRAW_ADDRESS_PREFIX: u8 = 0x03;
 const DISTRIBUTION_STORE_DELEGATOR_STARTING_INFO_PREFIX: u8 = 0x04;
 const DISTRIBUTION_STORE_VALIDATOR_HISTORICAL_REWARDS_PREFIX: u8 = 0x05;
 const DISTRIBUTION_STORE_VALIDATOR_CURRENT_REWARDS_PREFIX: u8 = 0x06;
 
+// neutron-sdk has no v050 module, 0.50 kept the 0.47 store keys and x/staking prefixes
+const V050_STAKING_STORE_KEY: &str = "staking";
+const V050_DISTRIBUTION_STORE_KEY: &str = "distribution";
+const V050_VALIDATORS_KEY: u8 = 0x21;
+const V050_DELEGATION_KEY: u8 = 0x31;
+
+// x/authz keeps its grants under 0x01 | len(granter) | granter | len(grantee) | grantee | msg type url
+const AUTHZ_STORE_KEY: &str = "authz";
+const AUTHZ_STORE_GRANT_PREFIX: u8 = 0x01;
+
+/// The store keys, key prefixes and bech32 prefixes needed to build and parse ICQ keys of one chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreLayout {
+    pub staking_store_key: String,
+    pub distribution_store_key: String,
+    pub validators_prefix: u8,
+    pub delegation_prefix: u8,
+    pub delegator_withdraw_address_prefix: u8,
+    pub delegator_starting_info_prefix: u8,
+    pub validator_historical_rewards_prefix: u8,
+    pub validator_current_rewards_prefix: u8,
+    pub authz_store_key: String,
+    pub authz_grant_prefix: u8,
+    pub account_prefix: String,
+    pub validator_prefix: String,
+}
+
+impl StoreLayout {
+    pub fn for_profile(profile: &ChainProfile) -> StoreLayout {
+        let (staking_store_key, distribution_store_key, validators_prefix, delegation_prefix) =
+            match profile.sdk_version {
+                SdkVersion::V045 => (
+                    v045::types::STAKING_STORE_KEY,
+                    v045::types::DISTRIBUTION_STORE_KEY,
+                    v045::types::VALIDATORS_KEY,
+                    v045::types::DELEGATION_KEY,
+                ),
+                SdkVersion::V047 => (
+                    v047::types::STAKING_STORE_KEY,
+                    v047::types::DISTRIBUTION_STORE_KEY,
+                    v047::types::VALIDATORS_KEY,
+                    v047::types::DELEGATION_KEY,
+                ),
+                // 0.50 moved x/distribution to collections, but kept the legacy prefixes and
+                // length prefixed addresses, so the raw keys are unchanged.
+                SdkVersion::V050 => (
+                    V050_STAKING_STORE_KEY,
+                    V050_DISTRIBUTION_STORE_KEY,
+                    V050_VALIDATORS_KEY,
+                    V050_DELEGATION_KEY,
+                ),
+            };
+
+        StoreLayout {
+            staking_store_key: profile
+                .staking_store_key
+                .clone()
+                .unwrap_or_else(|| staking_store_key.to_string()),
+            distribution_store_key: profile
+                .distribution_store_key
+                .clone()
+                .unwrap_or_else(|| distribution_store_key.to_string()),
+            validators_prefix,
+            delegation_prefix,
+            delegator_withdraw_address_prefix: DISTRIBUTION_STORE_DELEGATOR_WITHDRAW_ADDRESS_PREFIX,
+            delegator_starting_info_prefix: DISTRIBUTION_STORE_DELEGATOR_STARTING_INFO_PREFIX,
+            validator_historical_rewards_prefix:
+                DISTRIBUTION_STORE_VALIDATOR_HISTORICAL_REWARDS_PREFIX,
+            validator_current_rewards_prefix: DISTRIBUTION_STORE_VALIDATOR_CURRENT_REWARDS_PREFIX,
+            authz_store_key: AUTHZ_STORE_KEY.to_string(),
+            authz_grant_prefix: AUTHZ_STORE_GRANT_PREFIX,
+            account_prefix: profile.bech32_prefix.clone(),
+            validator_prefix: format!("{}valoper", profile.bech32_prefix),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct ValidatorHistoricalRange {
     pub validator: String,
     pub period: u64,
 }
 
+/// Delegation, starting info and historical rewards, the most keys a validator adds to a user query
+pub const USER_KEYS_PER_VALIDATOR: u64 = 3;
+/// The grant keys (delegate, host fee send and reward withdraw) and the withdraw address key, only in the primary query
+/// of a registration
+pub const USER_KEYS_PER_REGISTRATION: u64 = 4;
+
+// Validator records and current rewards are not in here, they come from the shared per-validator queries.
+// The grant keys, one per msg type the ICA executes, are only added once the chain's ICA (the grantee) is known
 pub fn create_all_icq_keys_for_user(
+    profile: &ChainProfile,
     delegator: String,
-    validators: Vec<String>, 
+    validators: Vec<String>,
     validator_historical_range: Option<Vec<ValidatorHistoricalRange>>,
+    grantee: Option<String>,
+    grant_msg_type_urls: &[&str],
+    with_withdraw_address: bool,
 ) -> NeutronResult<Vec<KVKey>> {
-    let delegation_keys = create_delegator_delegations_query_keys(
-        delegator.clone(),
-        validators.clone(),
-    ).unwrap();
-    let validator_keys = create_validator_query_keys(validators.clone()).unwrap();
-    let delegator_starting_info_keys = create_delegator_starting_info_query_keys(
-        delegator,
-        validators.clone(),
-    ).unwrap();
-    let validator_current_rewards_keys = create_validator_current_rewards_query_keys(validators).unwrap();
-    
-    let historical_rewards_keys = if validator_historical_range.is_some() {
-        create_validator_historical_rewards_query_keys(validator_historical_range.unwrap()).unwrap() 
+    let layout = StoreLayout::for_profile(profile);
+
+    let withdraw_address_keys = if with_withdraw_address {
+        vec![create_delegator_withdraw_address_query_key(&layout, delegator.clone())?]
     } else {
         vec![]
     };
 
+    let grant_keys = match grantee {
+        Some(grantee) => grant_msg_type_urls
+            .iter()
+            .map(|msg_type_url| create_authz_grant_query_key(&layout, delegator.clone(), grantee.clone(), msg_type_url))
+            .collect::<NeutronResult<Vec<_>>>()?,
+        None => vec![],
+    };
+
+    let delegation_keys =
+        create_delegator_delegations_query_keys(&layout, delegator.clone(), validators.clone())?;
+    let delegator_starting_info_keys =
+        create_delegator_starting_info_query_keys(&layout, delegator, validators)?;
+
+    let historical_rewards_keys = match validator_historical_range {
+        Some(range) => create_validator_historical_rewards_query_keys(&layout, range)?,
+        None => vec![],
+    };
+
     let all_keys = delegation_keys
         .into_iter()
-        .chain(validator_keys.into_iter())
-        .chain(delegator_starting_info_keys.into_iter())
-        .chain(validator_current_rewards_keys.into_iter())
-        .chain(historical_rewards_keys.into_iter())
+        .chain(delegator_starting_info_keys)
+        .chain(historical_rewards_keys)
+        .chain(grant_keys)
+        .chain(withdraw_address_keys)
         .collect();
-    
+
     Ok(all_keys)
 }
 
+/// Keys of the query shared by every registration delegating to `validator`
+pub fn create_all_icq_keys_for_validator(
+    profile: &ChainProfile,
+    validator: String,
+) -> NeutronResult<Vec<KVKey>> {
+    let layout = StoreLayout::for_profile(profile);
+
+    let validator_keys = create_validator_query_keys(&layout, vec![validator.clone()])?;
+    let validator_current_rewards_keys =
+        create_validator_current_rewards_query_keys(&layout, vec![validator])?;
+
+    Ok(validator_keys
+        .into_iter()
+        .chain(validator_current_rewards_keys)
+        .collect())
+}
+
 pub fn create_delegator_delegations_query_keys(
+    layout: &StoreLayout,
     delegator: String,
     validators: Vec<String>,
 ) -> NeutronResult<Vec<KVKey>> {
-    let delegator_addr = decode_and_convert(&delegator).unwrap();
+    let delegator_addr = decode_and_convert(&delegator)?;
 
     let mut keys: Vec<KVKey> = Vec::with_capacity(validators.len());
-    
+
     for v in validators {
-        let val_addr = decode_and_convert(&v).unwrap();
+        let val_addr = decode_and_convert(&v)?;
 
         // create delegation key to get delegation structure
+        let mut key: Vec<u8> = vec![layout.delegation_prefix];
+        key.extend_from_slice(length_prefix(&delegator_addr)?.as_slice());
+        key.extend_from_slice(length_prefix(&val_addr)?.as_slice());
+
         keys.push(KVKey {
-            path: STAKING_STORE_KEY.to_string(),
-            key: Binary(create_delegation_key(&delegator_addr, &val_addr).unwrap()),
+            path: layout.staking_store_key.clone(),
+            key: Binary(key),
         });
     }
-    
+
     Ok(keys)
 }
 
-pub fn create_validator_query_keys(validators: Vec<String>) -> NeutronResult<Vec<KVKey>> {
+pub fn create_validator_query_keys(
+    layout: &StoreLayout,
+    validators: Vec<String>,
+) -> NeutronResult<Vec<KVKey>> {
     let mut keys: Vec<KVKey> = Vec::with_capacity(validators.len());
 
     for v in validators {
-        let val_addr = decode_and_convert(&v).unwrap();
+        let val_addr = decode_and_convert(&v)?;
+
+        // create validator key to get validator structure
+        let mut key: Vec<u8> = vec![layout.validators_prefix];
+        key.extend_from_slice(length_prefix(&val_addr)?.as_slice());
 
-        // create delegation key to get delegation structure
         keys.push(KVKey {
-            path: STAKING_STORE_KEY.to_string(),
-            key: Binary(create_validator_key(&val_addr).unwrap()),
+            path: layout.staking_store_key.clone(),
+            key: Binary(key),
         });
     }
 
     Ok(keys)
 }
 
-pub fn create_validator_historical_rewards_query_keys(validators: Vec<ValidatorHistoricalRange>) -> NeutronResult<Vec<KVKey>> {
+// Holds the bare address bytes of the withdraw address, the key is absent while it is the delegator itself
+pub fn create_delegator_withdraw_address_query_key(
+    layout: &StoreLayout,
+    delegator: String,
+) -> NeutronResult<KVKey> {
+    let delegator_addr = decode_and_convert(&delegator)?;
+
+    let mut key: Vec<u8> = vec![layout.delegator_withdraw_address_prefix];
+    key.extend_from_slice(length_prefix(delegator_addr)?.as_slice());
+
+    Ok(KVKey {
+        path: layout.distribution_store_key.clone(),
+        key: Binary(key),
+    })
+}
+
+pub fn create_validator_historical_rewards_query_keys(
+    layout: &StoreLayout,
+    validators: Vec<ValidatorHistoricalRange>,
+) -> NeutronResult<Vec<KVKey>> {
     let mut keys: Vec<KVKey> = Vec::with_capacity(validators.len());
 
     for v in validators {
-        let val_addr = decode_and_convert(&v.validator).unwrap();
+        let val_addr = decode_and_convert(&v.validator)?;
 
-        // create delegation key to get delegation structure
         keys.push(KVKey {
-            path: DISTRIBUTION_STORE_PREFIX.to_string(),
-            key: Binary(create_distribution_validator_historical_rewards_prefix_key(&val_addr, v.period).unwrap()),
+            path: layout.distribution_store_key.clone(),
+            key: Binary(create_distribution_validator_historical_rewards_prefix_key(
+                layout, &val_addr, v.period,
+            )?),
         });
     }
 
     Ok(keys)
 }
 
-pub fn extract_validator_address_from_validator_historic_rewards_key(key: &[u8]) -> NeutronResult<String> {
-    let validator_length = key[1] as usize;
-    let validator_addr = &key[2..(2 + validator_length)];
-    let validator_hrp = Hrp::parse("cosmosvaloper").unwrap();
-    let bech32_validator_address: String = bech32::encode::<Bech32>(validator_hrp, validator_addr).unwrap();
-    
-    decode_and_convert(&bech32_validator_address).expect(format!("Failed to decode validator address: {}", bech32_validator_address).as_str());
-
-    Ok(bech32_validator_address)
+// The period is appended little endian, as the distribution module does
+pub fn extract_validator_and_period_from_validator_historic_rewards_key(
+    layout: &StoreLayout,
+    key: &[u8],
+) -> NeutronResult<(String, u64)> {
+    let (validator_addr, end) = read_length_prefixed(key, 1)?;
+    let period: [u8; 8] = key
+        .get(end..end + 8)
+        .and_then(|p| p.try_into().ok())
+        .ok_or_else(|| {
+            NeutronError::InvalidQueryResultFormat(format!("key too short: {}", Binary::from(key)))
+        })?;
+
+    Ok((
+        encode_address(&layout.validator_prefix, validator_addr)?,
+        u64::from_le_bytes(period),
+    ))
 }
 
 pub fn create_delegator_starting_info_query_keys(
+    layout: &StoreLayout,
     delegator: String,
     validators: Vec<String>,
 ) -> NeutronResult<Vec<KVKey>> {
-    let delegator_addr = decode_and_convert(&delegator).unwrap();
+    let delegator_addr = decode_and_convert(&delegator)?;
 
     let mut keys: Vec<KVKey> = Vec::with_capacity(validators.len());
 
     for v in validators {
-        let val_addr = decode_and_convert(&v).unwrap();
+        let val_addr = decode_and_convert(&v)?;
 
-        // create delegation key to get delegation structure
         keys.push(KVKey {
-            path: DISTRIBUTION_STORE_PREFIX.to_string(),
-            key: Binary(create_distribution_store_delegator_starting_info_prefix_key(&delegator_addr, &val_addr).unwrap()),
+            path: layout.distribution_store_key.clone(),
+            key: Binary(create_distribution_store_delegator_starting_info_prefix_key(
+                layout,
+                &delegator_addr,
+                &val_addr,
+            )?),
         });
     }
 
     Ok(keys)
 }
 
-pub fn create_validator_current_rewards_query_keys(validators: Vec<String>) -> NeutronResult<Vec<KVKey>> {
+pub fn create_validator_current_rewards_query_keys(
+    layout: &StoreLayout,
+    validators: Vec<String>,
+) -> NeutronResult<Vec<KVKey>> {
     let mut keys: Vec<KVKey> = Vec::with_capacity(validators.len());
 
     for v in validators {
-        let val_addr = decode_and_convert(&v).unwrap();
+        let val_addr = decode_and_convert(&v)?;
 
-        // create delegation key to get delegation structure
         keys.push(KVKey {
-            path: DISTRIBUTION_STORE_PREFIX.to_string(),
-            key: Binary(create_distribution_store_validator_current_rewards_prefix_key(&val_addr).unwrap()),
+            path: layout.distribution_store_key.clone(),
+            key: Binary(create_distribution_store_validator_current_rewards_prefix_key(
+                layout, &val_addr,
+            )?),
         });
     }
 
@@ -153,29 +317,30 @@ pub fn create_validator_current_rewards_query_keys(validators: Vec<String>) -> N
 }
 
 fn create_distribution_store_validator_current_rewards_prefix_key<AddrBytes: AsRef<[u8]>>(
+    layout: &StoreLayout,
     validator_addr: AddrBytes,
 ) -> NeutronResult<Vec<u8>> {
-    let mut key: Vec<u8> = vec![DISTRIBUTION_STORE_VALIDATOR_CURRENT_REWARDS_PREFIX];
+    let mut key: Vec<u8> = vec![layout.validator_current_rewards_prefix];
     key.extend_from_slice(length_prefix(validator_addr)?.as_slice());
-    
+
     Ok(key)
 }
 
-pub fn extract_validator_address_from_validator_current_rewards_key(key: &[u8]) -> NeutronResult<String> {
-    let validator_length = key[1] as usize;
-    let validator_addr = &key[2..(2 + validator_length)];
-    let validator_hrp = Hrp::parse("cosmosvaloper").unwrap();
-    let bech32_validator_address: String = bech32::encode::<Bech32>(validator_hrp, validator_addr).unwrap();
-    
-    decode_and_convert(&bech32_validator_address).expect(format!("Failed to decode validator address: {}", bech32_validator_address).as_str());
+pub fn extract_validator_address_from_validator_current_rewards_key(
+    layout: &StoreLayout,
+    key: &[u8],
+) -> NeutronResult<String> {
+    let (validator_addr, _) = read_length_prefixed(key, 1)?;
 
-    Ok(bech32_validator_address)
+    encode_address(&layout.validator_prefix, validator_addr)
 }
 
 fn create_distribution_store_delegator_starting_info_prefix_key<AddrBytes: AsRef<[u8]>>(
+    layout: &StoreLayout,
     delegator_address: AddrBytes,
-    validator_addr: AddrBytes) -> NeutronResult<Vec<u8>> {
-    let mut key: Vec<u8> = vec![DISTRIBUTION_STORE_DELEGATOR_STARTING_INFO_PREFIX];
+    validator_addr: AddrBytes,
+) -> NeutronResult<Vec<u8>> {
+    let mut key: Vec<u8> = vec![layout.delegator_starting_info_prefix];
     key.extend_from_slice(length_prefix(validator_addr)?.as_slice());
     key.extend_from_slice(length_prefix(delegator_address)?.as_slice());
 
@@ -183,65 +348,369 @@ fn create_distribution_store_delegator_starting_info_prefix_key<AddrBytes: AsRef
 }
 
 // (delegator, validator)
-pub fn extract_addresses_from_starting_info_key(key: &[u8]) -> NeutronResult<(String, String)> {
-    let validator_length = key[1] as usize;
-    let validator_addr = &key[2..(2 + validator_length)];
-    let validator_hrp = Hrp::parse("cosmosvaloper").unwrap();
-    let bech32_validator_address: String = bech32::encode::<Bech32>(validator_hrp, validator_addr).unwrap();
-    
-    let delegator_length = key[2 + validator_length] as usize;
-    let delegator_addr = &key[(2 + validator_length + 1)..(2 + validator_length + 1 + delegator_length)];
-    let delegator_hrp = Hrp::parse("cosmos").unwrap();
-    let bech32_delegator_address: String = bech32::encode::<Bech32>(delegator_hrp, delegator_addr).unwrap();
-
-    decode_and_convert(&bech32_delegator_address).expect(format!("Failed to decode delegator address: {}", bech32_delegator_address).as_str());
-    decode_and_convert(&bech32_validator_address).expect(format!("Failed to decode validator address: {}", bech32_validator_address).as_str());
-
-    Ok((bech32_delegator_address, bech32_validator_address))
+pub fn extract_addresses_from_starting_info_key(
+    layout: &StoreLayout,
+    key: &[u8],
+) -> NeutronResult<(String, String)> {
+    let (validator_addr, delegator_offset) = read_length_prefixed(key, 1)?;
+    let (delegator_addr, _) = read_length_prefixed(key, delegator_offset)?;
+
+    Ok((
+        encode_address(&layout.account_prefix, delegator_addr)?,
+        encode_address(&layout.validator_prefix, validator_addr)?,
+    ))
 }
 
 fn create_distribution_validator_historical_rewards_prefix_key<AddrBytes: AsRef<[u8]>>(
+    layout: &StoreLayout,
     validator_addr: AddrBytes,
-    height: u64,
+    period: u64,
 ) -> NeutronResult<Vec<u8>> {
-    let mut key: Vec<u8> = vec![DISTRIBUTION_STORE_VALIDATOR_HISTORICAL_REWARDS_PREFIX];
+    let mut key: Vec<u8> = vec![layout.validator_historical_rewards_prefix];
     key.extend_from_slice(length_prefix(validator_addr)?.as_slice());
 
     let mut buf = [0; 8];
-    LittleEndian::write_u64(&mut buf, height);
+    LittleEndian::write_u64(&mut buf, period);
     key.extend_from_slice(&buf);
-    
+
     Ok(key)
 }
 
+pub fn create_authz_grant_query_key(
+    layout: &StoreLayout,
+    granter: String,
+    grantee: String,
+    msg_type_url: &str,
+) -> NeutronResult<KVKey> {
+    let granter_addr = decode_and_convert(&granter)?;
+    let grantee_addr = decode_and_convert(&grantee)?;
+
+    let mut key: Vec<u8> = vec![layout.authz_grant_prefix];
+    key.extend_from_slice(length_prefix(granter_addr)?.as_slice());
+    key.extend_from_slice(length_prefix(grantee_addr)?.as_slice());
+    key.extend_from_slice(msg_type_url.as_bytes());
+
+    Ok(KVKey {
+        path: layout.authz_store_key.clone(),
+        key: Binary(key),
+    })
+}
+
+// (granter, grantee, msg type url)
+pub fn extract_grant_from_authz_grant_key(
+    layout: &StoreLayout,
+    key: &[u8],
+) -> NeutronResult<(String, String, String)> {
+    let (granter_addr, grantee_offset) = read_length_prefixed(key, 1)?;
+    let (grantee_addr, msg_type_offset) = read_length_prefixed(key, grantee_offset)?;
+    let msg_type_url = String::from_utf8(key[msg_type_offset..].to_vec())?;
+
+    Ok((
+        encode_address(&layout.account_prefix, granter_addr)?,
+        encode_address(&layout.account_prefix, grantee_addr)?,
+        msg_type_url,
+    ))
+}
+
+// Reads a length prefixed address starting at `offset`, returns it with the offset right after it
+fn read_length_prefixed(key: &[u8], offset: usize) -> NeutronResult<(&[u8], usize)> {
+    let length = *key.get(offset).ok_or_else(|| {
+        NeutronError::InvalidQueryResultFormat(format!("key too short: {}", Binary::from(key)))
+    })? as usize;
+    let start = offset + 1;
+    let addr = key.get(start..(start + length)).ok_or_else(|| {
+        NeutronError::InvalidQueryResultFormat(format!("key too short: {}", Binary::from(key)))
+    })?;
+
+    Ok((addr, start + length))
+}
+
+pub fn encode_address(prefix: &str, addr: &[u8]) -> NeutronResult<String> {
+    let hrp = Hrp::parse(prefix)
+        .map_err(|e| StdError::generic_err(format!("invalid bech32 prefix {}: {}", prefix, e)))?;
+
+    Ok(bech32::encode::<Bech32>(hrp, addr)
+        .map_err(|e| StdError::generic_err(format!("failed to encode address: {}", e)))?)
+}
+
 #[cfg(test)]
 mod tests {
-    use base64::Engine;
     use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
     use cosmwasm_std::Binary;
     use neutron_sdk::interchain_queries::helpers::decode_and_convert;
-
-    use crate::icq::keys::{create_distribution_validator_historical_rewards_prefix_key, extract_addresses_from_starting_info_key};
+    use neutron_sdk::interchain_queries::{v045, v047};
+
+    use crate::helpers::MSG_DELEGATE_TYPE_URL;
+    use crate::icq::keys::{
+        create_all_icq_keys_for_user, create_all_icq_keys_for_validator, create_authz_grant_query_key,
+        create_delegator_delegations_query_keys,
+        create_distribution_validator_historical_rewards_prefix_key,
+        extract_addresses_from_starting_info_key, extract_grant_from_authz_grant_key,
+        extract_validator_and_period_from_validator_historic_rewards_key, StoreLayout,
+        ValidatorHistoricalRange,
+    };
+    use crate::state::{ChainProfile, SdkVersion};
 
     const STARTING_INFO_KEY: &str = "BBQ9/0wU06NFlSKP51z/q2N6sup4VhR9ywXijNTWjJEJoZJa0nIAKXiodA==";
     const STARTING_INFO_VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
     const STARTING_INFO_DELEGATOR: &str = "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw";
-    
+
+    // Keys as found in a cosmoshub store
+    const DELEGATION_KEY: &str = "MRR9ywXijNTWjJEJoZJa0nIAKXiodBQ9/0wU06NFlSKP51z/q2N6sup4Vg==";
+    const VALIDATOR_KEY: &str = "IRQ9/0wU06NFlSKP51z/q2N6sup4Vg==";
+    const CURRENT_REWARDS_KEY: &str = "BhQ9/0wU06NFlSKP51z/q2N6sup4Vg==";
+    const HISTORICAL_REWARDS_KEY: &str = "BRQ9/0wU06NFlSKP51z/q2N6sup4VmQAAAAAAAAA";
+
+    // ICA addresses are 32 bytes long
+    const GRANTEE: &str = "cosmos1fx6893deednhgupzp5p5j7awexcync08qkn5j6ndxq66sdltfzlqlutgjs";
+    const GRANT_KEY: &str = "ARR9ywXijNTWjJEJoZJa0nIAKXiodCBJtHLFuctndHAiDQNJe67JsEnh5wWnSWptMDWoN+tIvi9jb3Ntb3Muc3Rha2luZy52MWJldGExLk1zZ0RlbGVnYXRl";
+
+    fn profile(sdk_version: SdkVersion) -> ChainProfile {
+        ChainProfile {
+            sdk_version,
+            ..ChainProfile::default()
+        }
+    }
+
+    fn assert_cosmoshub_keys(profile: &ChainProfile, staking_path: &str, distribution_path: &str) {
+        let keys = create_all_icq_keys_for_user(
+            profile,
+            STARTING_INFO_DELEGATOR.to_string(),
+            vec![STARTING_INFO_VALIDATOR.to_string()],
+            Some(vec![ValidatorHistoricalRange {
+                validator: STARTING_INFO_VALIDATOR.to_string(),
+                period: 100,
+            }]),
+            None,
+            &[],
+            false,
+        )
+        .unwrap();
+        let validator_keys =
+            create_all_icq_keys_for_validator(profile, STARTING_INFO_VALIDATOR.to_string()).unwrap();
+
+        let expected = vec![
+            (staking_path, DELEGATION_KEY),
+            (distribution_path, STARTING_INFO_KEY),
+            (distribution_path, HISTORICAL_REWARDS_KEY),
+        ];
+        assert_eq!(keys.len(), expected.len());
+        for (key, (path, base64_key)) in keys.iter().zip(expected) {
+            assert_eq!(key.path, path);
+            assert_eq!(key.key, Binary::from_base64(base64_key).unwrap());
+        }
+
+        let expected = vec![
+            (staking_path, VALIDATOR_KEY),
+            (distribution_path, CURRENT_REWARDS_KEY),
+        ];
+        assert_eq!(validator_keys.len(), expected.len());
+        for (key, (path, base64_key)) in validator_keys.iter().zip(expected) {
+            assert_eq!(key.path, path);
+            assert_eq!(key.key, Binary::from_base64(base64_key).unwrap());
+        }
+    }
+
     #[test]
     fn test_extract_validator_address_from_starting_info_key() {
-        let binary = Binary::from_base64(&STARTING_INFO_KEY).unwrap();
-        let (delegator, validator) = extract_addresses_from_starting_info_key(binary.as_slice()).unwrap();
+        let layout = StoreLayout::for_profile(&ChainProfile::default());
+        let binary = Binary::from_base64(STARTING_INFO_KEY).unwrap();
+        let (delegator, validator) =
+            extract_addresses_from_starting_info_key(&layout, binary.as_slice()).unwrap();
         assert_eq!(delegator, STARTING_INFO_DELEGATOR);
         assert_eq!(validator, STARTING_INFO_VALIDATOR);
 
         decode_and_convert(&delegator).unwrap();
         decode_and_convert(&validator).unwrap();
     }
-    
+
+    #[test]
+    fn test_extract_addresses_uses_profile_prefix() {
+        let layout = StoreLayout::for_profile(&ChainProfile {
+            bech32_prefix: "osmo".to_string(),
+            ..ChainProfile::default()
+        });
+        let binary = Binary::from_base64(STARTING_INFO_KEY).unwrap();
+        let (delegator, validator) =
+            extract_addresses_from_starting_info_key(&layout, binary.as_slice()).unwrap();
+        assert!(delegator.starts_with("osmo1"));
+        assert!(validator.starts_with("osmovaloper1"));
+        assert_eq!(
+            decode_and_convert(&delegator).unwrap(),
+            decode_and_convert(STARTING_INFO_DELEGATOR).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_extract_addresses_from_truncated_key() {
+        let layout = StoreLayout::for_profile(&ChainProfile::default());
+        let binary = Binary::from_base64(STARTING_INFO_KEY).unwrap();
+        let truncated = &binary.as_slice()[..30];
+        extract_addresses_from_starting_info_key(&layout, truncated).unwrap_err();
+    }
+
     #[test]
     fn test_create_distribution_validator_historical_rewards_prefix_key() {
+        let layout = StoreLayout::for_profile(&ChainProfile::default());
         let validator = decode_and_convert(STARTING_INFO_VALIDATOR).unwrap();
-        let key = create_distribution_validator_historical_rewards_prefix_key(&validator, 100).unwrap();
-        assert_eq!(STANDARD.encode(&key), "BRQ9/0wU06NFlSKP51z/q2N6sup4VmQAAAAAAAAA");
+        let key =
+            create_distribution_validator_historical_rewards_prefix_key(&layout, &validator, 100)
+                .unwrap();
+        assert_eq!(STANDARD.encode(key), HISTORICAL_REWARDS_KEY);
+    }
+
+    #[test]
+    fn test_extract_validator_and_period_from_historical_rewards_key() {
+        let layout = StoreLayout::for_profile(&ChainProfile::default());
+        let key = STANDARD.decode(HISTORICAL_REWARDS_KEY).unwrap();
+
+        let (validator, period) =
+            extract_validator_and_period_from_validator_historic_rewards_key(&layout, &key).unwrap();
+        assert_eq!(validator, STARTING_INFO_VALIDATOR);
+        assert_eq!(period, 100);
+
+        extract_validator_and_period_from_validator_historic_rewards_key(&layout, &key[..key.len() - 1])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_authz_grant_key() {
+        let layout = StoreLayout::for_profile(&ChainProfile::default());
+        let key = create_authz_grant_query_key(
+            &layout,
+            STARTING_INFO_DELEGATOR.to_string(),
+            GRANTEE.to_string(),
+            MSG_DELEGATE_TYPE_URL,
+        )
+        .unwrap();
+        assert_eq!(key.path, "authz");
+        assert_eq!(key.key, Binary::from_base64(GRANT_KEY).unwrap());
+
+        let (granter, grantee, msg_type_url) =
+            extract_grant_from_authz_grant_key(&layout, key.key.as_slice()).unwrap();
+        assert_eq!(granter, STARTING_INFO_DELEGATOR);
+        assert_eq!(grantee, GRANTEE);
+        assert_eq!(msg_type_url, MSG_DELEGATE_TYPE_URL);
+    }
+
+    #[test]
+    fn test_grant_key_only_with_grantee() {
+        let keys = create_all_icq_keys_for_user(
+            &ChainProfile::default(),
+            STARTING_INFO_DELEGATOR.to_string(),
+            vec![STARTING_INFO_VALIDATOR.to_string()],
+            None,
+            Some(GRANTEE.to_string()),
+            &[MSG_DELEGATE_TYPE_URL],
+            false,
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[2].path, "authz");
+        assert_eq!(keys[2].key, Binary::from_base64(GRANT_KEY).unwrap());
+    }
+
+    #[test]
+    fn test_withdraw_address_key() {
+        let keys = create_all_icq_keys_for_user(
+            &ChainProfile::default(),
+            STARTING_INFO_DELEGATOR.to_string(),
+            vec![STARTING_INFO_VALIDATOR.to_string()],
+            None,
+            Some(GRANTEE.to_string()),
+            &[MSG_DELEGATE_TYPE_URL],
+            true,
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 4);
+        assert_eq!(keys[3].path, "distribution");
+        let mut expected = vec![0x03, 20];
+        expected.extend(decode_and_convert(STARTING_INFO_DELEGATOR).unwrap());
+        assert_eq!(keys[3].key, Binary::from(expected));
+    }
+
+    // Store keys, then the validators, delegation, withdraw address, starting info, historical
+    // and current rewards prefixes
+    fn assert_layout(profile: &ChainProfile, store_keys: (&str, &str), prefixes: [u8; 6]) {
+        let layout = StoreLayout::for_profile(profile);
+        assert_eq!(
+            (layout.staking_store_key.as_str(), layout.distribution_store_key.as_str()),
+            store_keys
+        );
+        assert_eq!(
+            [
+                layout.validators_prefix,
+                layout.delegation_prefix,
+                layout.delegator_withdraw_address_prefix,
+                layout.delegator_starting_info_prefix,
+                layout.validator_historical_rewards_prefix,
+                layout.validator_current_rewards_prefix,
+            ],
+            prefixes
+        );
+    }
+
+    #[test]
+    fn test_v045_layout_keys() {
+        let profile = profile(SdkVersion::V045);
+        assert_layout(
+            &profile,
+            (v045::types::STAKING_STORE_KEY, v045::types::DISTRIBUTION_STORE_KEY),
+            [v045::types::VALIDATORS_KEY, v045::types::DELEGATION_KEY, 0x03, 0x04, 0x05, 0x06],
+        );
+        assert_cosmoshub_keys(&profile, "staking", "distribution");
+
+        // The staking keys match the ones neutron-sdk builds for 0.45
+        let delegator = decode_and_convert(STARTING_INFO_DELEGATOR).unwrap();
+        let validator = decode_and_convert(STARTING_INFO_VALIDATOR).unwrap();
+        let keys = create_all_icq_keys_for_validator(&profile, STARTING_INFO_VALIDATOR.to_string())
+            .unwrap();
+        assert_eq!(
+            keys[0].key,
+            Binary::from(v045::helpers::create_validator_key(&validator).unwrap())
+        );
+        let keys = create_delegator_delegations_query_keys(
+            &StoreLayout::for_profile(&profile),
+            STARTING_INFO_DELEGATOR.to_string(),
+            vec![STARTING_INFO_VALIDATOR.to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            keys[0].key,
+            Binary::from(v045::helpers::create_delegation_key(&delegator, &validator).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_v047_layout_keys() {
+        let profile = profile(SdkVersion::V047);
+        assert_layout(
+            &profile,
+            (v047::types::STAKING_STORE_KEY, v047::types::DISTRIBUTION_STORE_KEY),
+            [v047::types::VALIDATORS_KEY, v047::types::DELEGATION_KEY, 0x03, 0x04, 0x05, 0x06],
+        );
+        assert_cosmoshub_keys(&profile, "staking", "distribution");
+    }
+
+    #[test]
+    fn test_v050_layout_keys() {
+        let profile = profile(SdkVersion::V050);
+        // x/staking ValidatorsKey = collections.NewPrefix(33), DelegationKey = collections.NewPrefix(49),
+        // x/distribution DelegatorWithdrawAddrPrefix = collections.NewPrefix(3) through
+        // ValidatorCurrentRewardsPrefix = collections.NewPrefix(6), all with length prefixed addresses
+        assert_layout(&profile, ("staking", "distribution"), [33, 49, 3, 4, 5, 6]);
+        assert_cosmoshub_keys(&profile, "staking", "distribution");
+    }
+
+    #[test]
+    fn test_fork_layout_keys() {
+        let fork = ChainProfile {
+            sdk_version: SdkVersion::V050,
+            bech32_prefix: "cosmos".to_string(),
+            staking_store_key: Some("mstaking".to_string()),
+            distribution_store_key: Some("mdistribution".to_string()),
+        };
+        assert_cosmoshub_keys(&fork, "mstaking", "mdistribution");
     }
 }
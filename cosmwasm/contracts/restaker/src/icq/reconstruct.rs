@@ -1,14 +1,20 @@
+use std::str::FromStr;
+use cosmos_sdk_proto::cosmos::authz::v1beta1::Grant as CosmosGrant;
 use cosmos_sdk_proto::cosmos::distribution::v1beta1::{DelegatorStartingInfo, ValidatorCurrentRewards as CosmosValidatorCurrentRewards, ValidatorHistoricalRewards as CosmosValidatorHistoricalRewards};
-use cosmos_sdk_proto::cosmos::staking::v1beta1::{Delegation as CosmosDelegation, Validator as CosmosValidator};
+use cosmos_sdk_proto::cosmos::staking::v1beta1::{Delegation as CosmosDelegation, StakeAuthorization, Validator as CosmosValidator};
 use cosmos_sdk_proto::prost::Message;
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Coin, coin, StdError};
-use neutron_sdk::bindings::types::StorageValue;
+use cosmwasm_std::{Coin, coin, Decimal, Deps, StdError, Timestamp, Uint128};
+use neutron_sdk::bindings::query::NeutronQuery;
+use neutron_sdk::bindings::types::{KVKey, StorageValue};
+use neutron_sdk::interchain_queries::queries::get_raw_interchain_query_result;
 use neutron_sdk::interchain_queries::types::KVReconstruct;
 use neutron_sdk::NeutronError::Std;
 use neutron_sdk::NeutronResult;
 
-use crate::icq::keys::{extract_addresses_from_starting_info_key, extract_validator_address_from_validator_current_rewards_key, extract_validator_address_from_validator_historic_rewards_key};
+use crate::helpers::STAKE_AUTHORIZATION_TYPE_URL;
+use crate::icq::keys::{extract_addresses_from_starting_info_key, extract_grant_from_authz_grant_key, extract_validator_address_from_validator_current_rewards_key, extract_validator_and_period_from_validator_historic_rewards_key, create_all_icq_keys_for_user, create_all_icq_keys_for_validator, create_delegator_delegations_query_keys, encode_address, StoreLayout, ValidatorHistoricalRange, USER_KEYS_PER_VALIDATOR};
+use crate::state::{ChainProfile, IcqResultCoverage, UserChainRegistration, VALIDATOR_QUERIES};
 
 #[cw_serde]
 pub struct DelegatorStartingInfoWithValidator {
@@ -32,11 +38,61 @@ pub struct Validator {
     pub operator_address: String,
     pub tokens: String,
     pub all_shares: String,
+    pub jailed: bool, // Tombstoned validators stay jailed for good
+    pub status: ValidatorStatus,
+    pub commission_rate: Decimal,
+}
+
+impl Validator {
+    /// Why a delegation to this validator would earn nothing, None while it is bonded and not jailed
+    pub fn inactive_reason(&self) -> Option<String> {
+        if self.jailed {
+            Some("jailed".to_string())
+        } else if self.status != ValidatorStatus::Bonded {
+            Some(self.status.as_str().to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// staking BondStatus of a validator
+#[cw_serde]
+#[derive(Copy, Default)]
+pub enum ValidatorStatus {
+    #[default]
+    Unspecified,
+    Unbonded,
+    Unbonding,
+    Bonded,
+}
+
+impl ValidatorStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidatorStatus::Unspecified => "unspecified",
+            ValidatorStatus::Unbonded => "unbonded",
+            ValidatorStatus::Unbonding => "unbonding",
+            ValidatorStatus::Bonded => "bonded",
+        }
+    }
+}
+
+impl From<i32> for ValidatorStatus {
+    fn from(status: i32) -> Self {
+        match status {
+            1 => ValidatorStatus::Unbonded,
+            2 => ValidatorStatus::Unbonding,
+            3 => ValidatorStatus::Bonded,
+            _ => ValidatorStatus::Unspecified,
+        }
+    }
 }
 
 #[cw_serde]
 pub struct ValidatorHistoricalRewards {
     pub validator: String,
+    pub period: u64,
     pub cumulative_reward_ratio: Vec<Coin>,
     pub reference_count: u32, 
 }
@@ -49,100 +105,294 @@ pub struct ValidatorCurrentRewards {
 }
 
 #[cw_serde]
+pub struct AuthzGrant {
+    pub granter: String,
+    pub grantee: String,
+    pub msg_type_url: String,
+    pub authorization_type_url: String,
+    pub expiration: Option<Timestamp>, // No expiration means the grant lives until revoked
+    pub max_tokens: Option<Coin>, // Remaining StakeAuthorization allowance, None means no spend limit
+}
+
+#[cw_serde]
+#[derive(Default)]
 pub struct UserQueryData {
     pub delegations: Vec<Delegation>,
     pub validators: Vec<Validator>,
     pub delegator_starting_infos: Vec<DelegatorStartingInfoWithValidator>,
     pub validator_historical_rewards: Vec<ValidatorHistoricalRewards>,
     pub validator_current_rewards: Vec<ValidatorCurrentRewards>,
+    pub grants: Vec<AuthzGrant>, // Only grants that exist on the remote chain
+    pub withdraw_address: Option<String>, // None when the delegator never set one, rewards then go to the delegator itself
+}
+
+/// Sdk Dec values are stored as the integer of the value times 10^18
+fn decode_commission_rate(validator: &CosmosValidator) -> NeutronResult<Decimal> {
+    let rate = validator
+        .commission
+        .as_ref()
+        .and_then(|c| c.commission_rates.as_ref())
+        .map(|r| r.rate.as_str())
+        .unwrap_or("0");
+    let atomics = Uint128::from_str(rate)
+        .map_err(|e| Std(StdError::generic_err(format!("Invalid commission rate {}: {}", rate, e))))?;
+    Decimal::from_atomics(atomics, 18)
+        .map_err(|e| Std(StdError::generic_err(format!("Invalid commission rate {}: {}", rate, e))))
 }
 
+impl UserQueryData {
+    /// The historical rewards periods the next key set has to query, one per delegation
+    pub fn historical_ranges(&self) -> Vec<ValidatorHistoricalRange> {
+        self.delegator_starting_infos
+            .iter()
+            .map(|v| ValidatorHistoricalRange {
+                validator: v.validator.clone(),
+                period: v.previous_period,
+            })
+            .collect()
+    }
+
+    pub fn reconstruct_for_profile(profile: &ChainProfile, storage_values: &[StorageValue]) -> NeutronResult<UserQueryData> {
+        let layout = StoreLayout::for_profile(profile);
+        let mut user_query_data = UserQueryData::default();
+
+        for sv in storage_values.iter() {
+            // Keys that don't exist on the remote chain come back with an empty value
+            if sv.value.is_empty() {
+                continue;
+            }
+            let key_prefix = *sv.key.first().ok_or_else(|| Std(StdError::generic_err("Empty storage key")))?;
+
+            if sv.storage_prefix == layout.distribution_store_key {
+                if key_prefix == layout.delegator_withdraw_address_prefix {
+                    user_query_data.withdraw_address = Some(encode_address(&layout.account_prefix, sv.value.as_slice())?);
+                } else if key_prefix == layout.delegator_starting_info_prefix {
+                    let delegator_starting_info = DelegatorStartingInfo::decode(sv.value.as_slice())?;
+                    let (delegator, validator) = extract_addresses_from_starting_info_key(&layout, sv.key.as_slice())?;
+                    user_query_data.delegator_starting_infos.push(DelegatorStartingInfoWithValidator{
+                        previous_period: delegator_starting_info.previous_period,
+                        stake: delegator_starting_info.stake,
+                        height: delegator_starting_info.height,
+                        delegator,
+                        validator,
+                    });
+                } else if key_prefix == layout.validator_historical_rewards_prefix {
+                    let validator_historical_rewards = CosmosValidatorHistoricalRewards::decode(sv.value.as_slice())?;
+                    let as_coins = dec_coins_to_coins(validator_historical_rewards.cumulative_reward_ratio)?;
+                    let reference_count = validator_historical_rewards.reference_count;
+                    let (validator, period) = extract_validator_and_period_from_validator_historic_rewards_key(&layout, sv.key.as_slice())?;
+                    user_query_data.validator_historical_rewards.push(ValidatorHistoricalRewards{
+                        validator,
+                        period,
+                        cumulative_reward_ratio: as_coins,
+                        reference_count,
+                    });
+                } else if key_prefix == layout.validator_current_rewards_prefix {
+                    let validator_current_rewards = CosmosValidatorCurrentRewards::decode(sv.value.as_slice())?;
+                    let as_coins = dec_coins_to_coins(validator_current_rewards.rewards)?;
+                    let period = validator_current_rewards.period;
+                    let validator = extract_validator_address_from_validator_current_rewards_key(&layout, sv.key.as_slice())?;
+                    user_query_data.validator_current_rewards.push(ValidatorCurrentRewards{
+                        validator,
+                        rewards: as_coins,
+                        period,
+                    });
+                } else {
+                    return Err(Std(StdError::generic_err("Unknown storage key")));
+                }
+            } else if sv.storage_prefix == layout.staking_store_key {
+                if key_prefix == layout.delegation_prefix {
+                    let delegation = CosmosDelegation::decode(sv.value.as_slice())?;
+                    user_query_data.delegations.push(Delegation{
+                        delegator_address: delegation.delegator_address,
+                        validator_address: delegation.validator_address,
+                        shares: delegation.shares,
+                    });
+                } else if key_prefix == layout.validators_prefix {
+                    let validator = CosmosValidator::decode(sv.value.as_slice())?;
+                    let commission_rate = decode_commission_rate(&validator)?;
+                    user_query_data.validators.push(Validator{
+                        operator_address: validator.operator_address,
+                        tokens: validator.tokens,
+                        all_shares: validator.delegator_shares,
+                        jailed: validator.jailed,
+                        status: validator.status.into(),
+                        commission_rate,
+                    });
+                } else {
+                    return Err(Std(StdError::generic_err("Unknown storage key")));
+                }
+            } else if sv.storage_prefix == layout.authz_store_key && key_prefix == layout.authz_grant_prefix {
+                let grant = CosmosGrant::decode(sv.value.as_slice())?;
+                let (granter, grantee, msg_type_url) = extract_grant_from_authz_grant_key(&layout, sv.key.as_slice())?;
+                let authorization = grant.authorization.unwrap_or_default();
+                let max_tokens = if authorization.type_url == STAKE_AUTHORIZATION_TYPE_URL {
+                    StakeAuthorization::decode(authorization.value.as_slice())?
+                        .max_tokens
+                        .map(|c| {
+                            let amount = c.amount.parse::<u128>().map_err(|e| {
+                                Std(StdError::generic_err(format!("Invalid max tokens amount {}: {}", c.amount, e)))
+                            })?;
+                            Ok::<_, neutron_sdk::NeutronError>(coin(amount, c.denom))
+                        })
+                        .transpose()?
+                } else {
+                    None
+                };
+                user_query_data.grants.push(AuthzGrant {
+                    granter,
+                    grantee,
+                    msg_type_url,
+                    authorization_type_url: authorization.type_url,
+                    expiration: grant.expiration.map(|e| {
+                        Timestamp::from_seconds(e.seconds.max(0) as u64).plus_nanos(e.nanos.max(0) as u64)
+                    }),
+                    max_tokens,
+                });
+            } else {
+                return Err(Std(StdError::generic_err("Unknown storage prefix")));
+            }
+        }
+
+        Ok(user_query_data)
+    }
+}
+
+// Chains without a profile are read with the default (Cosmos SDK 0.47) layout
 impl KVReconstruct for UserQueryData {
     fn reconstruct(storage_values: &[StorageValue]) -> NeutronResult<UserQueryData> {
-        let mut user_query_data = UserQueryData {
-            delegations: vec![],
-            validators: vec![],
-            delegator_starting_infos: vec![],
-            validator_historical_rewards: vec![],
-            validator_current_rewards: vec![],
+        UserQueryData::reconstruct_for_profile(&ChainProfile::default(), storage_values)
+    }
+}
+
+/// Reconstructs the last results of a registration's ICQs (the primary one and its shards), joined with the
+/// shared queries of its validators, and checks which of the keys the reward math needs they hold.
+/// The historical rewards have to be the ones of the starting period found in the same result.
+/// Queries are updated every `icq_update_period` remote blocks each, but not at the same blocks, so a validator
+/// result is only joined with the delegation it pays when they are at most one update period apart.
+pub fn query_covered_user_query_data(
+    deps: Deps<NeutronQuery>,
+    profile: &ChainProfile,
+    registration: &UserChainRegistration,
+    icq_update_period: u64,
+) -> NeutronResult<(UserQueryData, IcqResultCoverage)> {
+    let layout = StoreLayout::for_profile(profile);
+    let mut user_query_data = UserQueryData::default();
+    let mut missing_keys = 0;
+    let mut remote_height: Option<u64> = None;
+    let mut undelegated_validators: Vec<String> = vec![];
+    let mut delegation_heights: Vec<(String, u64)> = vec![];
+    let mut mixed_heights = false;
+
+    for (icq_id, validators) in registration.user_queries() {
+        let result = icq_id.and_then(|icq_id| get_raw_interchain_query_result(deps, icq_id).ok());
+        let Some(result) = result else {
+            // Not registered or no result yet, every key of the shard is missing
+            missing_keys += validators.len() as u64 * USER_KEYS_PER_VALIDATOR;
+            continue;
         };
+        let shard_data = UserQueryData::reconstruct_for_profile(profile, &result.result.kv_results)?;
 
-        for sv in storage_values.iter() {
-            match sv.storage_prefix.as_str() {
-                "distribution" => {
-                    match sv.key[0] {
-                        0x04 => {
-                            let delegator_starting_info = DelegatorStartingInfo::decode(sv.value.as_slice()).unwrap();
-                            let (delegator, validator) = extract_addresses_from_starting_info_key(&sv.key.as_slice()).unwrap();
-                            user_query_data.delegator_starting_infos.push(DelegatorStartingInfoWithValidator{
-                                previous_period: delegator_starting_info.previous_period,
-                                stake: delegator_starting_info.stake,
-                                height: delegator_starting_info.height,
-                                delegator,
-                                validator,
-                            });
-                        },
-                        0x05 => {
-                            let validator_historical_rewards = CosmosValidatorHistoricalRewards::decode(sv.value.as_slice()).unwrap();
-                            let cumulative_reward_ratio = validator_historical_rewards.cumulative_reward_ratio;
-                            let as_coins = cumulative_reward_ratio
-                                .into_iter()
-                                .map(|c| coin(c.amount.parse().unwrap(), c.denom))
-                                .collect::<Vec<Coin>>();
-                            let reference_count = validator_historical_rewards.reference_count;
-                            let validator = extract_validator_address_from_validator_historic_rewards_key(&sv.key.as_slice()).unwrap();
-                            user_query_data.validator_historical_rewards.push(ValidatorHistoricalRewards{
-                                validator,
-                                cumulative_reward_ratio: as_coins,
-                                reference_count,
-                            });
-                        },
-                        0x06 => {
-                            let validator_current_rewards = CosmosValidatorCurrentRewards::decode(sv.value.as_slice()).unwrap();
-                            let rewards = validator_current_rewards.rewards;
-                            let as_coins = rewards
-                                .into_iter()
-                                .map(|c| coin(c.amount.parse().unwrap(), c.denom))
-                                .collect::<Vec<Coin>>();
-                            let period = validator_current_rewards.period;
-                            let validator = extract_validator_address_from_validator_current_rewards_key(&sv.key.as_slice()).unwrap();
-                            user_query_data.validator_current_rewards.push(ValidatorCurrentRewards{
-                                validator,
-                                rewards: as_coins,
-                                period,
-                            });
-                        }
-                        _ => return Err(Std(StdError::generic_err("Unknown storage key"))),
-                    }
-                },
-                "staking" => {
-                    match sv.key[0] {
-                        0x31 => {
-                            let delegation = CosmosDelegation::decode(sv.value.as_slice()).unwrap();
-                            user_query_data.delegations.push(Delegation{
-                                delegator_address: delegation.delegator_address,
-                                validator_address: delegation.validator_address,
-                                shares: delegation.shares,
-                            });
-                        },
-                        0x21 => {
-                            let validator = CosmosValidator::decode(sv.value.as_slice()).unwrap();
-                            user_query_data.validators.push(Validator{
-                                operator_address: validator.operator_address,
-                                tokens: validator.tokens,
-                                all_shares: validator.delegator_shares,
-                            });
-                        },
-                        _ => return Err(Std(StdError::generic_err("Unknown storage key"))),
-                    }
-                },
-                _ => return Err(Std(StdError::generic_err("Unknown storage prefix"))),
+        // A delegation that comes back empty was redelegated or unbonded, nothing else is needed for its validator
+        let mut delegated_validators = vec![];
+        for validator in validators {
+            let delegation_keys = create_delegator_delegations_query_keys(
+                &layout,
+                registration.remote_address.clone(),
+                vec![validator.clone()],
+            )?;
+            if has_empty_values(&delegation_keys, &result.result.kv_results) {
+                undelegated_validators.push(validator);
+            } else {
+                delegation_heights.push((validator.clone(), result.result.height));
+                delegated_validators.push(validator);
             }
         }
 
+        let required_keys = create_all_icq_keys_for_user(
+            profile,
+            registration.remote_address.clone(),
+            delegated_validators,
+            Some(shard_data.historical_ranges()),
+            None,
+            &[],
+            false,
+        )?;
+        missing_keys += count_missing_keys(&required_keys, &result.result.kv_results);
+        // Shards hold the whole key set of their validators, they don't have to be read at the same height
+        remote_height = Some(remote_height.map_or(result.result.height, |h| h.min(result.result.height)));
 
-        Ok(user_query_data)
+        user_query_data.delegations.extend(shard_data.delegations);
+        user_query_data.delegator_starting_infos.extend(shard_data.delegator_starting_infos);
+        user_query_data.validator_historical_rewards.extend(shard_data.validator_historical_rewards);
+        user_query_data.grants.extend(shard_data.grants);
+        user_query_data.withdraw_address = user_query_data.withdraw_address.or(shard_data.withdraw_address);
+    }
+
+    for validator in registration.validators.iter().filter(|v| !undelegated_validators.contains(v)) {
+        let validator_keys = create_all_icq_keys_for_validator(profile, validator.clone())?;
+        let validator_result = VALIDATOR_QUERIES
+            .may_load(deps.storage, (registration.chain_id.clone(), validator.clone()))?
+            .and_then(|q| q.icq_id)
+            // No result is submitted yet when the module errors
+            .and_then(|icq_id| get_raw_interchain_query_result(deps, icq_id).ok());
+
+        match validator_result {
+            Some(validator_result) => {
+                // The rewards of a validator have to be read close to the height of the delegation they are paid on
+                mixed_heights |= delegation_heights
+                    .iter()
+                    .find(|(v, _)| v == validator)
+                    .is_some_and(|(_, height)| height.abs_diff(validator_result.result.height) > icq_update_period);
+                let validator_data = UserQueryData::reconstruct_for_profile(profile, &validator_result.result.kv_results)?;
+                user_query_data.validators.extend(validator_data.validators);
+                user_query_data.validator_current_rewards.extend(validator_data.validator_current_rewards);
+                missing_keys += count_missing_keys(&validator_keys, &validator_result.result.kv_results);
+            }
+            None => missing_keys += validator_keys.len() as u64,
+        }
     }
+
+    let coverage = IcqResultCoverage {
+        remote_height: remote_height.unwrap_or_default(),
+        historical_periods: user_query_data
+            .validator_historical_rewards
+            .iter()
+            .map(|h| (h.validator.clone(), h.period))
+            .collect(),
+        missing_keys,
+        undelegated_validators,
+        mixed_heights,
+    };
+
+    Ok((user_query_data, coverage))
+}
+
+fn has_empty_values(keys: &[KVKey], storage_values: &[StorageValue]) -> bool {
+    keys.iter().all(|k| {
+        storage_values
+            .iter()
+            .any(|sv| sv.storage_prefix == k.path && sv.key == k.key && sv.value.is_empty())
+    })
+}
+
+fn count_missing_keys(keys: &[KVKey], storage_values: &[StorageValue]) -> u64 {
+    keys.iter()
+        .filter(|k| !storage_values.iter().any(|sv| sv.storage_prefix == k.path && sv.key == k.key))
+        .count() as u64
+}
+
+// DecCoin amounts are sent without the decimal point, so they parse as plain integers
+fn dec_coins_to_coins(dec_coins: Vec<cosmos_sdk_proto::cosmos::base::v1beta1::DecCoin>) -> NeutronResult<Vec<Coin>> {
+    dec_coins
+        .into_iter()
+        .map(|c| {
+            let amount = c.amount.parse::<u128>().map_err(|e| {
+                Std(StdError::generic_err(format!("Invalid dec coin amount {}: {}", c.amount, e)))
+            })?;
+            Ok(coin(amount, c.denom))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -152,6 +402,8 @@ mod tests {
     use cosmwasm_std::Binary;
     use neutron_sdk::bindings::types::StorageValue;
 
+    use crate::state::SdkVersion;
+
     use super::*;
 
     const DELEGATION_KEY: &str = "MRR9ywXijNTWjJEJoZJa0nIAKXiodBQ9/0wU06NFlSKP51z/q2N6sup4Vg==";
@@ -160,6 +412,7 @@ mod tests {
     const VALIDATOR_VALUE: &str = "CjRjb3Ntb3N2YWxvcGVyMThobDVjOXhuNWR6ZTJnNTB1YXcwbDJtcjAyZXc1N3prMGF1a3RuEkMKHS9jb3Ntb3MuY3J5cHRvLmVkMjU1MTkuUHViS2V5EiIKIBCnd3RZbB+HPld6eUcUk0aG79E/BVjf2ZDeZ/BSodm2IAMqDDMwNzAwMDAwMDAwMDIeMzA3MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwOgYKBHRlc3RKAFJKCjsKEjEwMDAwMDAwMDAwMDAwMDAwMBISMjAwMDAwMDAwMDAwMDAwMDAwGhExMDAwMDAwMDAwMDAwMDAwMBILCL7u27IGELCvkERaATByATB6ATA=";
     const DELEGATOR_STARTING_INFO_KEY: &str = "BBQ9/0wU06NFlSKP51z/q2N6sup4VhR9ywXijNTWjJEJoZJa0nIAKXiodA==";
     const DELEGATOR_STARTING_INFO_VALUE: &str = "CAUSHjMwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMBj+NQ==";
+    const GRANT_KEY: &str = "ARR9ywXijNTWjJEJoZJa0nIAKXiodCBJtHLFuctndHAiDQNJe67JsEnh5wWnSWptMDWoN+tIvi9jb3Ntb3Muc3Rha2luZy52MWJldGExLk1zZ0RlbGVnYXRl";
 
 
     #[test]
@@ -194,5 +447,321 @@ mod tests {
         assert_eq!(user_query_data.validators.len(), 1);
         assert_eq!(user_query_data.delegator_starting_infos.len(), 1);
         assert_eq!(user_query_data.validator_historical_rewards.len(), 0);
+        assert_eq!(user_query_data.validators[0].status, ValidatorStatus::Bonded);
+        assert!(!user_query_data.validators[0].jailed);
+        assert_eq!(user_query_data.validators[0].inactive_reason(), None);
+        assert_eq!(user_query_data.validators[0].commission_rate, Decimal::percent(10));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_reconstruct_jailed_validator() {
+        let mut validator = CosmosValidator::decode(Binary::from_base64(VALIDATOR_VALUE).unwrap().as_slice()).unwrap();
+        validator.jailed = true;
+        validator.status = 2; // BOND_STATUS_UNBONDING
+
+        let mut storage_values = storage_values("staking", "distribution");
+        storage_values[1].value = Binary::from(validator.encode_to_vec());
+
+        let user_query_data = UserQueryData::reconstruct(&storage_values).unwrap();
+        assert!(user_query_data.validators[0].jailed);
+        assert_eq!(user_query_data.validators[0].status, ValidatorStatus::Unbonding);
+        assert_eq!(user_query_data.validators[0].inactive_reason(), Some("jailed".to_string()));
+    }
+
+    fn storage_values(staking_prefix: &str, distribution_prefix: &str) -> Vec<StorageValue> {
+        vec![
+            StorageValue {
+                storage_prefix: staking_prefix.to_string(),
+                key: Binary::from_base64(DELEGATION_KEY).unwrap(),
+                value: Binary::from_base64(DELEGATION_VALUE).unwrap(),
+            },
+            StorageValue {
+                storage_prefix: staking_prefix.to_string(),
+                key: Binary::from_base64(VALIDATOR_KEY).unwrap(),
+                value: Binary::from_base64(VALIDATOR_VALUE).unwrap(),
+            },
+            StorageValue {
+                storage_prefix: distribution_prefix.to_string(),
+                key: Binary::from_base64(DELEGATOR_STARTING_INFO_KEY).unwrap(),
+                value: Binary::from_base64(DELEGATOR_STARTING_INFO_VALUE).unwrap(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_reconstruct_authz_grant() {
+        let grant = CosmosGrant {
+            authorization: Some(cosmos_sdk_proto::Any {
+                type_url: "/cosmos.authz.v1beta1.GenericAuthorization".to_string(),
+                value: vec![],
+            }),
+            expiration: Some(cosmos_sdk_proto::Timestamp {
+                seconds: 1_700_000_000,
+                nanos: 0,
+            }),
+        };
+
+        let mut storage_values = storage_values("staking", "distribution");
+        storage_values.push(StorageValue {
+            storage_prefix: "authz".to_string(),
+            key: Binary::from_base64(GRANT_KEY).unwrap(),
+            value: Binary::from(grant.encode_to_vec()),
+        });
+
+        let user_query_data = UserQueryData::reconstruct(&storage_values).unwrap();
+        assert_eq!(
+            user_query_data.grants,
+            vec![AuthzGrant {
+                granter: "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw".to_string(),
+                grantee: "cosmos1fx6893deednhgupzp5p5j7awexcync08qkn5j6ndxq66sdltfzlqlutgjs".to_string(),
+                msg_type_url: "/cosmos.staking.v1beta1.MsgDelegate".to_string(),
+                authorization_type_url: "/cosmos.authz.v1beta1.GenericAuthorization".to_string(),
+                expiration: Some(Timestamp::from_seconds(1_700_000_000)),
+                max_tokens: None,
+            }]
+        );
+
+        // No grant on the remote chain
+        storage_values.last_mut().unwrap().value = Binary::default();
+        let user_query_data = UserQueryData::reconstruct(&storage_values).unwrap();
+        assert!(user_query_data.grants.is_empty());
+    }
+
+    #[test]
+    fn test_reconstruct_stake_authorization_allowance() {
+        let stake_authorization = StakeAuthorization {
+            max_tokens: Some(cosmos_sdk_proto::cosmos::base::v1beta1::Coin {
+                denom: "uatom".to_string(),
+                amount: "2500000".to_string(),
+            }),
+            authorization_type: 1, // AUTHORIZATION_TYPE_DELEGATE
+            validators: None,
+        };
+        let grant = CosmosGrant {
+            authorization: Some(cosmos_sdk_proto::Any {
+                type_url: "/cosmos.staking.v1beta1.StakeAuthorization".to_string(),
+                value: stake_authorization.encode_to_vec(),
+            }),
+            expiration: None,
+        };
+
+        let mut storage_values = storage_values("staking", "distribution");
+        storage_values.push(StorageValue {
+            storage_prefix: "authz".to_string(),
+            key: Binary::from_base64(GRANT_KEY).unwrap(),
+            value: Binary::from(grant.encode_to_vec()),
+        });
+
+        let user_query_data = UserQueryData::reconstruct(&storage_values).unwrap();
+        assert_eq!(user_query_data.grants.len(), 1);
+        assert_eq!(user_query_data.grants[0].authorization_type_url, "/cosmos.staking.v1beta1.StakeAuthorization");
+        assert_eq!(user_query_data.grants[0].expiration, None);
+        assert_eq!(user_query_data.grants[0].max_tokens, Some(coin(2500000, "uatom")));
+    }
+
+    #[test]
+    fn test_reconstruct_for_each_sdk_version() {
+        for sdk_version in [SdkVersion::V045, SdkVersion::V047, SdkVersion::V050] {
+            let profile = ChainProfile {
+                sdk_version,
+                ..ChainProfile::default()
+            };
+            let user_query_data = UserQueryData::reconstruct_for_profile(
+                &profile,
+                &storage_values("staking", "distribution"),
+            )
+            .unwrap();
+            assert_eq!(user_query_data.delegations.len(), 1);
+            assert_eq!(user_query_data.validators.len(), 1);
+            assert_eq!(user_query_data.delegator_starting_infos.len(), 1);
+            assert_eq!(
+                user_query_data.delegator_starting_infos[0].validator,
+                "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_fork_store_keys() {
+        let fork = ChainProfile {
+            sdk_version: SdkVersion::V050,
+            bech32_prefix: "cosmos".to_string(),
+            staking_store_key: Some("mstaking".to_string()),
+            distribution_store_key: Some("mdistribution".to_string()),
+        };
+        let user_query_data = UserQueryData::reconstruct_for_profile(
+            &fork,
+            &storage_values("mstaking", "mdistribution"),
+        )
+        .unwrap();
+        assert_eq!(user_query_data.delegations.len(), 1);
+        assert_eq!(user_query_data.validators.len(), 1);
+        assert_eq!(user_query_data.delegator_starting_infos.len(), 1);
+
+        // The stock store keys are unknown to the fork
+        UserQueryData::reconstruct_for_profile(&fork, &storage_values("staking", "distribution"))
+            .unwrap_err();
+    }
+
+    // Answers every key with the fixture value when there is one, like the module does for missing keys
+    fn kv_results(keys: Vec<KVKey>, known: &[StorageValue]) -> Vec<StorageValue> {
+        keys.into_iter()
+            .map(|k| StorageValue {
+                value: known
+                    .iter()
+                    .find(|sv| sv.storage_prefix == k.path && sv.key == k.key)
+                    .map(|sv| sv.value.clone())
+                    .unwrap_or_default(),
+                storage_prefix: k.path,
+                key: k.key,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_query_covered_user_query_data_joins_validator_queries() {
+        use cosmwasm_std::Addr;
+        use neutron_sdk::bindings::types::InterchainQueryResult;
+
+        use crate::state::{BillingMode, IcqShard, RegistrationStatus, ValidatorQuery, DEFAULT_ICQ_UPDATE_PERIOD};
+        use crate::testing::helpers::mock_neutron_dependencies;
+
+        const DELEGATOR: &str = "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw";
+        const VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
+        let mut deps = mock_neutron_dependencies();
+        let profile = ChainProfile::default();
+        let registration = UserChainRegistration {
+            local_address: Addr::unchecked("local_user"),
+            chain_id: "chain_id".to_string(),
+            remote_address: DELEGATOR.to_string(),
+            validators: vec![VALIDATOR.to_string()],
+            delegator_delegations_reply_id: 1,
+            delegator_delegations_icq_id: Some(1),
+            next_compound_height: 0,
+            status: RegistrationStatus::Active,
+            grant_expiration: None,
+            grant_allowance: None,
+            icq_shards: vec![],
+            fallback_validator: None,
+            max_commission: None,
+            compounded_commissions: vec![],
+            withdraw_address: None,
+            billing: BillingMode::Prepaid,
+            compound_failures: 0,
+            in_flight: None,
+            failed_compound: None,
+        };
+        VALIDATOR_QUERIES
+            .save(
+                deps.as_mut().storage,
+                ("chain_id".to_string(), VALIDATOR.to_string()),
+                &ValidatorQuery {
+                    reply_id: 2,
+                    icq_id: Some(2),
+                    ref_count: 1,
+                    icq_error: None,
+                },
+            )
+            .unwrap();
+
+        let known = storage_values("staking", "distribution");
+        let user_keys = create_all_icq_keys_for_user(
+            &profile,
+            DELEGATOR.to_string(),
+            vec![VALIDATOR.to_string()],
+            Some(UserQueryData::reconstruct(&known).unwrap().historical_ranges()),
+            None,
+            &[],
+            false,
+        )
+        .unwrap();
+        let user_result = |height: u64| InterchainQueryResult {
+            kv_results: kv_results(user_keys.clone(), &known),
+            height,
+            revision: 0,
+        };
+        deps.querier.add_query_result(1, user_result(100));
+        let validator_keys = create_all_icq_keys_for_validator(&profile, VALIDATOR.to_string()).unwrap();
+        let validator_result = |height: u64| InterchainQueryResult {
+            kv_results: kv_results(validator_keys.clone(), &known),
+            height,
+            revision: 0,
+        };
+
+        // Every key answered at one height
+        deps.querier.add_query_result(2, validator_result(100));
+        let (user_query_data, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert!(coverage.is_complete());
+        assert_eq!(coverage.remote_height, 100);
+        assert_eq!(user_query_data.delegations.len(), 1);
+        assert_eq!(user_query_data.delegator_starting_infos.len(), 1);
+        // The validator comes from its shared query, not the registration's
+        assert_eq!(user_query_data.validators.len(), 1);
+        assert_eq!(user_query_data.validators[0].operator_address, VALIDATOR);
+
+        // The validator query is updated a block after the delegation, within one update period
+        deps.querier.add_query_result(2, validator_result(101));
+        let (_, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert!(coverage.is_complete());
+        assert_eq!(coverage.remote_height, 100);
+
+        // The validator query answered more than an update period after the delegation
+        deps.querier.add_query_result(2, validator_result(100 + DEFAULT_ICQ_UPDATE_PERIOD + 1));
+        let (_, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert_eq!(coverage.missing_keys, 0);
+        assert!(coverage.mixed_heights);
+        assert!(!coverage.is_complete());
+
+        // No validator result yet, its keys are missing
+        deps.querier.add_query_result(2, InterchainQueryResult {
+            kv_results: vec![],
+            height: 100,
+            revision: 0,
+        });
+        let (user_query_data, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert_eq!(coverage.missing_keys, validator_keys.len() as u64);
+        assert!(!coverage.mixed_heights);
+        assert!(user_query_data.validators.is_empty());
+
+        // The delegation moved to a shard, the primary query holds no validator anymore
+        let registration = UserChainRegistration {
+            icq_shards: vec![IcqShard {
+                validators: vec![VALIDATOR.to_string()],
+                reply_id: 3,
+                icq_id: Some(3),
+            }],
+            ..registration
+        };
+        deps.querier.add_query_result(1, InterchainQueryResult {
+            kv_results: vec![],
+            height: 100,
+            revision: 0,
+        });
+        deps.querier.add_query_result(2, validator_result(100));
+        deps.querier.add_query_result(3, user_result(100));
+        let (user_query_data, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert!(coverage.is_complete());
+        assert_eq!(user_query_data.delegations.len(), 1);
+
+        // Shards and validator queries updated a block or two apart are joined
+        deps.querier.add_query_result(1, InterchainQueryResult {
+            kv_results: vec![],
+            height: 101,
+            revision: 0,
+        });
+        deps.querier.add_query_result(2, validator_result(100));
+        deps.querier.add_query_result(3, user_result(98));
+        let (user_query_data, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert!(coverage.is_complete());
+        assert_eq!(coverage.remote_height, 98);
+        assert_eq!(user_query_data.delegations.len(), 1);
+
+        // A shard read more than an update period before the validator query can't be joined with it
+        deps.querier.add_query_result(3, user_result(90));
+        let (_, coverage) = query_covered_user_query_data(deps.as_ref(), &profile, &registration, DEFAULT_ICQ_UPDATE_PERIOD).unwrap();
+        assert_eq!(coverage.missing_keys, 0);
+        assert_eq!(coverage.remote_height, 90);
+        assert!(coverage.mixed_heights);
+        assert!(!coverage.is_complete());
+    }
+}
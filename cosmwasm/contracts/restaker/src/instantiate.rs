@@ -1,9 +1,11 @@
-use cosmwasm_std::{entry_point, DepsMut, Env, MessageInfo, Response};
+use cosmwasm_std::{entry_point, Decimal, DepsMut, Env, MessageInfo, Response};
 use neutron_sdk::bindings::query::NeutronQuery;
 
 use crate::error::ContractError;
 use crate::msg::InstantiateMsg;
-use crate::state::{Config, CONFIG, NEXT_REPLY_ID};
+use crate::state::{
+    Config, CONFIG, DEFAULT_MAX_KV_QUERY_KEYS, DEFAULT_MAX_VALIDATORS_PER_REGISTRATION, NEXT_REPLY_ID,
+};
 
 #[entry_point]
 pub fn instantiate(
@@ -13,18 +15,25 @@ pub fn instantiate(
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     let admin = deps.api.addr_validate(&msg.admin)?;
-    CONFIG
-        .save(
-            deps.storage,
-            &Config {
-                admin,
-                neutron_register_ica_fee: msg.neutron_register_ica_fee,
-                autocompound_threshold: msg.autocompound_threshold,
-            },
-        )
-        .unwrap();
+    let treasury_fee = msg.treasury_fee.unwrap_or_default();
+    if treasury_fee > Decimal::one() {
+        return Err(ContractError::InvalidTreasuryFee { treasury_fee });
+    }
+    CONFIG.save(
+        deps.storage,
+        &Config {
+            admin,
+            neutron_register_ica_fee: msg.neutron_register_ica_fee,
+            autocompound_threshold: msg.autocompound_threshold,
+            max_validators_per_registration: msg
+                .max_validators_per_registration
+                .unwrap_or(DEFAULT_MAX_VALIDATORS_PER_REGISTRATION),
+            max_kv_query_keys: msg.max_kv_query_keys.unwrap_or(DEFAULT_MAX_KV_QUERY_KEYS),
+            treasury_fee,
+        },
+    )?;
 
-    NEXT_REPLY_ID.save(deps.storage, &1).unwrap();
+    NEXT_REPLY_ID.save(deps.storage, &1)?;
 
     Ok(Response::new())
 }
@@ -49,6 +58,9 @@ mod tests {
             admin: "admin".to_string(),
             neutron_register_ica_fee: 1000000,
             autocompound_threshold: 100,
+            max_validators_per_registration: None,
+            max_kv_query_keys: None,
+            treasury_fee: None,
         };
 
         let res = instantiate(deps.as_mut(), mock_env(), info, msg.clone()).unwrap();
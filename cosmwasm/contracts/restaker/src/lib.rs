@@ -4,6 +4,7 @@ pub mod error;
 pub mod execute;
 pub mod helpers;
 pub mod instantiate;
+pub mod migrate;
 pub mod msg;
 pub mod query;
 pub mod reply;
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::{entry_point, Addr, DepsMut, Env, Order, Response, StdResult};
+use cw_storage_plus::{Index, Map, PrimaryKey};
+use neutron_sdk::bindings::msg::NeutronMsg;
+use neutron_sdk::bindings::query::NeutronQuery;
+
+use crate::helpers::{remove_registration_queries, tally_ledger};
+use crate::msg::MigrateMsg;
+use crate::state::{user_chain_registrations, UserChainRegistration, LEDGER};
+
+#[entry_point]
+pub fn migrate(
+    deps: DepsMut<NeutronQuery>,
+    _env: Env,
+    _msg: MigrateMsg,
+) -> StdResult<Response<NeutronMsg>> {
+    // Contracts from before the ledger hold balances it has never seen, it starts from the records
+    if LEDGER.may_load(deps.storage)?.is_none() {
+        let ledger = tally_ledger(deps.storage)?;
+        LEDGER.save(deps.storage, &ledger)?;
+    }
+
+    // Registrations from before the remote address index have no entry in it, saving them again adds it.
+    // A remote address registered by several users stays with the earliest registration, the others are
+    // removed. Reply ids only grow, the lowest primary query reply id is the earliest registration.
+    let registrations = user_chain_registrations()
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut earliest: HashMap<(String, String), u64> = HashMap::new();
+    for (_, registration) in &registrations {
+        let reply_id = earliest
+            .entry((registration.chain_id.clone(), registration.remote_address.clone()))
+            .or_insert(registration.delegator_delegations_reply_id);
+        *reply_id = (*reply_id).min(registration.delegator_delegations_reply_id);
+    }
+
+    let mut msgs = vec![];
+    let mut response = Response::new().add_attribute("action", "migrate");
+    for (key, registration) in registrations {
+        let index_key = (registration.chain_id.clone(), registration.remote_address.clone());
+        if earliest[&index_key] == registration.delegator_delegations_reply_id {
+            user_chain_registrations().save(deps.storage, key, &registration)?;
+            continue;
+        }
+
+        msgs.extend(remove_registration_queries(deps.storage, &registration)?);
+        // Removing through the IndexedMap would drop the unique entry of the registration kept
+        let pk = key.joined_key();
+        let indexes = user_chain_registrations().idx;
+        indexes.local_address.remove(deps.storage, &pk, &registration)?;
+        indexes.next_compound_height.remove(deps.storage, &pk, &registration)?;
+        let primary: Map<(Addr, String, String), UserChainRegistration> = Map::new("user_chain_registrations");
+        primary.remove(deps.storage, key.clone());
+        response = response.add_attribute("removed_duplicate", format!("{}/{}/{}", key.0, key.1, key.2));
+    }
+
+    Ok(response.add_submessages(msgs))
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::mock_env;
+    use cosmwasm_std::{coins, from_json, Addr, Storage, SubMsg, Uint128};
+    use cw_storage_plus::Map;
+    use neutron_sdk::bindings::msg::NeutronMsg;
+
+    use crate::migrate::migrate;
+    use crate::msg::{InvariantsResponse, MigrateMsg, QueryMsg};
+    use crate::query::query;
+    use crate::state::{
+        user_chain_registrations, QueryDeposit, UserChainRegistration, LEDGER, QUERY_DEPOSITS, TREASURY,
+        USER_BALANCES,
+    };
+    use crate::testing::helpers::mock_neutron_dependencies;
+
+    fn registration(local_address: &str, remote_address: &str, icq_id: u64) -> UserChainRegistration {
+        UserChainRegistration {
+            local_address: Addr::unchecked(local_address),
+            chain_id: "chain_id".to_string(),
+            remote_address: remote_address.to_string(),
+            validators: vec![],
+            delegator_delegations_reply_id: icq_id,
+            delegator_delegations_icq_id: Some(icq_id),
+            next_compound_height: 0,
+            status: Default::default(),
+            grant_expiration: None,
+            grant_allowance: None,
+            icq_shards: vec![],
+            fallback_validator: None,
+            max_commission: None,
+            compounded_commissions: vec![],
+            withdraw_address: None,
+            billing: Default::default(),
+            compound_failures: 0,
+            in_flight: None,
+            failed_compound: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_initializes_the_ledger() {
+        let mut deps = mock_neutron_dependencies();
+        USER_BALANCES.save(deps.as_mut().storage, Addr::unchecked("user"), &Uint128::new(1000)).unwrap();
+        TREASURY.save(deps.as_mut().storage, "untrn".to_string(), &Uint128::new(300)).unwrap();
+        deps.querier.set_balance(&mock_env().contract.address, coins(1300, "untrn"));
+
+        // The balances stored before the upgrade are missing from an empty ledger
+        let invariants: InvariantsResponse =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::Invariants {}).unwrap()).unwrap();
+        assert_eq!(
+            invariants.violations,
+            vec![
+                "user_balances ledger 0 does not match the records 1000".to_string(),
+                "treasury ledger 0 does not match the records 300".to_string(),
+            ]
+        );
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+        let ledger = LEDGER.load(deps.as_ref().storage).unwrap();
+        assert_eq!(ledger.user_balances, Uint128::new(1000));
+        assert_eq!(ledger.treasury, Uint128::new(300));
+        let invariants: InvariantsResponse =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::Invariants {}).unwrap()).unwrap();
+        assert!(invariants.violations.is_empty());
+        assert_eq!(invariants.reserve, Uint128::zero());
+
+        // An existing ledger is left alone
+        USER_BALANCES.save(deps.as_mut().storage, Addr::unchecked("user"), &Uint128::new(2000)).unwrap();
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+        assert_eq!(LEDGER.load(deps.as_ref().storage).unwrap().user_balances, Uint128::new(1000));
+    }
+
+    #[test]
+    fn test_migrate_indexes_remote_addresses() {
+        let mut deps = mock_neutron_dependencies();
+        // Saved before the remote address index, without its entries
+        let primary: Map<(Addr, String, String), UserChainRegistration> = Map::new("user_chain_registrations");
+        for (local_address, remote_address, icq_id) in
+            [("alice", "remote_1", 1), ("bob", "remote_1", 2), ("carol", "remote_2", 3)]
+        {
+            let key = (Addr::unchecked(local_address), "chain_id".to_string(), remote_address.to_string());
+            primary
+                .save(deps.as_mut().storage, key, &registration(local_address, remote_address, icq_id))
+                .unwrap();
+        }
+        // Registered after the index was added, while the earlier registration of remote_3 had no entry in it
+        let key = (Addr::unchecked("dave"), "chain_id".to_string(), "remote_3".to_string());
+        user_chain_registrations()
+            .save(deps.as_mut().storage, key, &registration("dave", "remote_3", 5))
+            .unwrap();
+        let key = (Addr::unchecked("erin"), "chain_id".to_string(), "remote_3".to_string());
+        primary.save(deps.as_mut().storage, key, &registration("erin", "remote_3", 4)).unwrap();
+        QUERY_DEPOSITS
+            .save(
+                deps.as_mut().storage,
+                2,
+                &QueryDeposit {
+                    payer: Addr::unchecked("bob"),
+                    amount: Uint128::new(100),
+                },
+            )
+            .unwrap();
+
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        // The earliest registration of a remote address keeps it, the others are removed and their deposits refunded
+        let removed: Vec<_> =
+            res.attributes.iter().filter(|a| a.key == "removed_duplicate").map(|a| a.value.as_str()).collect();
+        assert_eq!(removed, vec!["bob/chain_id/remote_1", "dave/chain_id/remote_3"]);
+        assert_eq!(
+            res.messages,
+            vec![
+                SubMsg::new(NeutronMsg::remove_interchain_query(2)),
+                SubMsg::new(NeutronMsg::remove_interchain_query(5)),
+            ]
+        );
+        assert!(primary
+            .may_load(
+                deps.as_ref().storage,
+                (Addr::unchecked("bob"), "chain_id".to_string(), "remote_1".to_string())
+            )
+            .unwrap()
+            .is_none());
+        assert_eq!(USER_BALANCES.load(deps.as_ref().storage, Addr::unchecked("bob")).unwrap(), Uint128::new(100));
+
+        let indexed = |storage: &dyn Storage, remote_address: &str| {
+            user_chain_registrations()
+                .idx
+                .remote_address
+                .item(storage, ("chain_id".to_string(), remote_address.to_string()))
+                .unwrap()
+                .map(|(_, registration)| registration.local_address)
+        };
+        assert_eq!(indexed(deps.as_ref().storage, "remote_1"), Some(Addr::unchecked("alice")));
+        assert_eq!(indexed(deps.as_ref().storage, "remote_2"), Some(Addr::unchecked("carol")));
+        assert_eq!(indexed(deps.as_ref().storage, "remote_3"), Some(Addr::unchecked("erin")));
+
+        // Running it again changes nothing
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+        assert!(res.messages.is_empty());
+        assert_eq!(indexed(deps.as_ref().storage, "remote_1"), Some(Addr::unchecked("alice")));
+    }
+}
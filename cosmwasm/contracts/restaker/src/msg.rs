@@ -1,16 +1,24 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::Coin;
+use cosmwasm_std::{Binary, Coin, Decimal, Timestamp, Uint128};
 use crate::icq::reconstruct::UserQueryData;
 
-use crate::state::{Config, UserChainRegistration};
+use crate::state::{
+    BillingMode, ChainProfile, Config, HostFeeConfig, IcaTxConfig, Ledger, IcqShard, RegistrationStatus, RegistrationStatusKind, UserChainRegistration,
+};
 
 #[cw_serde]
 pub struct InstantiateMsg {
     pub admin: String,
     pub neutron_register_ica_fee: u128,
     pub autocompound_threshold: u64,
+    pub max_validators_per_registration: Option<u64>, // Defaults to DEFAULT_MAX_VALIDATORS_PER_REGISTRATION
+    pub max_kv_query_keys: Option<u64>, // Keep in line with the interchainqueries module, defaults to DEFAULT_MAX_KV_QUERY_KEYS
+    pub treasury_fee: Option<Decimal>, // Share of each autocompound cost for the treasury, defaults to none of it
 }
 
+#[cw_serde]
+pub struct MigrateMsg {}
+
 #[cw_serde]
 pub enum ExecuteMsg {
     UpdateConfig {
@@ -21,22 +29,61 @@ pub enum ExecuteMsg {
         connection_id: String,
         denom: String,           // The native staking token of a dst chain
         autocompound_cost: u128, // Always in untrn, this is the fee paid to the keepers for autocompounding
+        profile: Option<ChainProfile>, // Defaults to a Cosmos SDK 0.47 chain with the "cosmos" prefix
+        max_icq_staleness: Option<u64>, // Local blocks, defaults to DEFAULT_MAX_ICQ_STALENESS
+        icq_update_period: Option<u64>, // Remote blocks between ICQ results, defaults to DEFAULT_ICQ_UPDATE_PERIOD
+        ica_tx: Option<IcaTxConfig>, // Defaults to the MinIbcFee, a 2 weeks timeout and the default memo
+        host_fee: Option<HostFeeConfig>, // Only prepaid registrations when not set
     },
     UpdateSupportedChain {
         chain_id: String,
         connection_id: String,
         denom: String,           // The native staking token of a dst chain
         autocompound_cost: u128, // Always in untrn, this is the fee paid to the keepers for autocompounding
+        profile: Option<ChainProfile>, // Keeps the current profile when not set
+        max_icq_staleness: Option<u64>, // Keeps the current limit when not set
+        icq_update_period: Option<u64>, // Keeps the current period when not set
+        ica_tx: Option<IcaTxConfig>, // Keeps the current settings when not set
+        host_fee: Option<HostFeeConfig>, // Keeps the current host fee when not set
+        clear_host_fee: Option<bool>, // Removes the host fee, host-billed registrations compound without one
     },
     RegisterUser {
         registrations: Vec<UserChainRegistrationInput>,
     },
+    UpdateRegistration {
+        chain_id: String,
+        remote_address: String,
+        validators: Option<Vec<String>>, // Keeps the current validators when not set
+        fallback_validator: Option<String>, // Keeps the current fallback when not set
+        max_commission: Option<Decimal>, // Keeps the current ceiling when not set
+        clear_fallback_validator: Option<bool>, // Removes the fallback, rewards of skipped validators are held back again
+        clear_max_commission: Option<bool>, // Removes the ceiling
+        billing: Option<BillingMode>, // Keeps the current billing mode when not set
+    },
+    RemoveRegistration {
+        chain_id: String,
+        remote_address: String,
+        local_address: Option<String>, // Admin only, to remove the registration of another user. The sender's when not set
+    },
     TopupUserBalance {
         // recipient: String, // TODO: nice to have thing
     },
+    SetFeeDenom {
+        denom: String,
+        untrn_rate: Option<Decimal>, // untrn one unit of the denom is worth, removes the denom when not set
+    },
     Autocompound {
         delegators_amount: u64,
     },
+    WithdrawTreasury {
+        denom: String,
+        amount: Option<Uint128>, // Defaults to the whole treasury balance of the denom
+        recipient: Option<String>, // Defaults to the admin
+    },
+    SweepHostFees {
+        chain_id: String,
+        amount: Option<Uint128>, // Defaults to every fee the ICA collected since the last sweep
+    },
 }
 
 #[cw_serde]
@@ -44,6 +91,9 @@ pub struct UserChainRegistrationInput {
     pub chain_id: String,
     pub address: String,
     pub validators: Vec<String>,
+    pub fallback_validator: Option<String>, // Rewards of a jailed or unbonded validator are held back without one
+    pub max_commission: Option<Decimal>, // Validators above it are treated like jailed ones, no ceiling when not set
+    pub billing: Option<BillingMode>, // Defaults to Prepaid
 }
 
 #[cw_serde]
@@ -61,6 +111,7 @@ pub enum QueryMsg {
         address: String,
         limit: Option<u64>,
         start_after: Option<String>,
+        status: Option<Vec<RegistrationStatusKind>>, // Only registrations in one of these states, all of them when not set
     },
     #[returns(GetCalculatedRewardResponse)]
     CalculateReward {
@@ -76,8 +127,26 @@ pub enum QueryMsg {
     },
     #[returns(UserBalanceResponse)]
     UserBalance { address: String },
+    #[returns(FeeDenomsResponse)]
+    FeeDenoms {},
+    #[returns(TreasuryResponse)]
+    Treasury {},
+    #[returns(InvariantsResponse)]
+    Invariants {},
     #[returns(DueUserChainRegistrationsResponse)]
     DueUserChainRegistrations { delegators_amount: u64 },
+    #[returns(RemoteAddressOwnerResponse)]
+    RemoteAddressOwner {
+        chain_id: String,
+        remote_address: String,
+    },
+    #[returns(RequiredGrantsResponse)]
+    RequiredGrants {
+        chain_id: String,
+        remote_address: String,
+    },
+    #[returns(CommissionChangesResponse)]
+    CommissionChanges { address: String },
 }
 
 #[cw_serde]
@@ -91,6 +160,12 @@ pub struct ChainResponse {
     pub connection_id: String,
     pub ica_address: Option<String>, // When this is set, the chain is ready to be used, until then dont use it
     pub autocompound_cost: u128,
+    pub profile: ChainProfile,
+    pub max_icq_staleness: u64,
+    pub icq_update_period: u64,
+    pub ica_tx: IcaTxConfig,
+    pub host_fee: Option<HostFeeConfig>,
+    pub unswept_host_fees: Uint128, // Host fees held by the ICA, see SweepHostFees
 }
 
 #[cw_serde]
@@ -103,10 +178,18 @@ pub struct UserChainResponse {
     pub chain_id: String,
     pub remote_address: String,
     pub validators: Vec<String>,
+    pub status: RegistrationStatus,
+    pub grant_expiration: Option<Timestamp>,
+    pub grant_allowance: Option<Coin>,
+    pub fallback_validator: Option<String>,
+    pub max_commission: Option<Decimal>,
+    pub withdraw_address: Option<String>, // Set when rewards go to another account, the registration is paused then
+    pub billing: BillingMode,
 
     // Mostly for debugging, honestly
     pub delegator_delegations_reply_id: u64,
     pub delegator_delegations_icq_id: Option<u64>,
+    pub icq_shards: Vec<IcqShard>,
 }
 
 #[cw_serde]
@@ -118,19 +201,100 @@ pub struct GetUserRegistrationsResponse {
 pub struct RewardResponse {
     pub validator: String,
     pub reward: Vec<Coin>,
+    pub inactive_reason: Option<String>, // Set when the validator is jailed or out of the bonded set, see autocompound
+    pub commission_rate: Decimal,
 }
 
 #[cw_serde]
 pub struct GetCalculatedRewardResponse {
     pub rewards: Vec<RewardResponse>,
+    pub data_age: IcqDataAge,
+    pub remote_height: u64, // The oldest delegation the rewards were computed from was read at this height, validators within an ICQ update period of it
+}
+
+/// How old the ICQ result the rewards were computed from is
+#[cw_serde]
+pub struct IcqDataAge {
+    pub last_submitted_local_height: u64,
+    pub last_submitted_remote_height: u64,
+    pub age: u64,    // Local blocks since the last result was submitted
+    pub stale: bool, // Older than the chain's max_icq_staleness, autocompound skips it
 }
 
 #[cw_serde]
 pub struct UserBalanceResponse {
-    pub balance: u128,
+    pub balance: u128, // untrn
+    pub fee_balances: Vec<Coin>, // Other whitelisted fee denoms
+}
+
+#[cw_serde]
+pub struct FeeDenom {
+    pub denom: String,
+    pub untrn_rate: Decimal,
+}
+
+#[cw_serde]
+pub struct FeeDenomsResponse {
+    pub fee_denoms: Vec<FeeDenom>, // untrn is always accepted and not listed
+}
+
+#[cw_serde]
+pub struct TreasuryResponse {
+    pub balances: Vec<Coin>,
+}
+
+/// The ledger against the untrn balance of the contract and the records each bucket adds up
+#[cw_serde]
+pub struct InvariantsResponse {
+    pub ledger: Ledger,
+    pub bank_balance: Uint128, // untrn held by the contract
+    pub liabilities: Uint128,  // user_balances, escrowed_fees and treasury, the bank balance has to cover them
+    pub reserve: Uint128,      // Bank balance on top of the liabilities, relayer fees are paid from it
+    pub violations: Vec<String>, // Empty when every invariant holds
 }
 
 #[cw_serde]
 pub struct DueUserChainRegistrationsResponse {
     pub due_user_chain_registrations: Vec<UserChainRegistration>,
 }
+
+#[cw_serde]
+pub struct RemoteAddressOwnerResponse {
+    pub owner: Option<String>, // The local address that registered the remote address, if any
+}
+
+#[cw_serde]
+pub struct RequiredGrant {
+    pub msg_type_url: String,           // The message the ICA will execute through MsgExec
+    pub authorization_type_url: String, // Suggested authorization, StakeAuthorization when the validators are known
+    pub msg_grant_type_url: String,
+    pub msg_grant: Binary, // Protobuf encoded MsgGrant, ready to be signed by the remote address
+}
+
+#[cw_serde]
+pub struct CommissionChange {
+    pub validator: String,
+    pub last_compound_rate: Decimal,
+    pub current_rate: Decimal,
+}
+
+#[cw_serde]
+pub struct RegistrationCommissionChanges {
+    pub chain_id: String,
+    pub remote_address: String,
+    pub changes: Vec<CommissionChange>,
+}
+
+/// Registrations with a validator whose commission is not what it was at their last compound
+#[cw_serde]
+pub struct CommissionChangesResponse {
+    pub registrations: Vec<RegistrationCommissionChanges>,
+}
+
+#[cw_serde]
+pub struct RequiredGrantsResponse {
+    pub granter: String,
+    pub grantee: String, // The chain ICA
+    pub expiration: Timestamp,
+    pub grants: Vec<RequiredGrant>,
+}
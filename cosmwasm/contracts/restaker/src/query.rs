@@ -1,17 +1,20 @@
 use std::str::FromStr;
-use cosmwasm_std::{Binary, Decimal256, Deps, entry_point, Env, Order, StdError, StdResult, to_json_binary, Uint128, Uint256};
+use cosmwasm_std::{coin, Binary, Coin, Decimal256, Deps, entry_point, Env, Order, StdError, StdResult, to_json_binary, Uint128, Uint256};
 use cw_storage_plus::Bound;
 use neutron_sdk::bindings::query::NeutronQuery;
-use neutron_sdk::interchain_queries::{check_query_type, get_registered_query, query_kv_result};
+use neutron_sdk::interchain_queries::{check_query_type, get_registered_query};
 use neutron_sdk::interchain_queries::types::QueryType;
 use restaker_utils::rewards::calculate_delegation_rewards;
 use restaker_utils::types::DelegatorStartingInfo as UtilsDelegatorStartingInfo;
 use restaker_utils::types::ValidatorHistoricalRewards as UtilsValidatorHistoricalRewards;
 
-use crate::icq::reconstruct::UserQueryData;
-use crate::helpers::get_due_user_chain_registrations;
-use crate::msg::{ChainResponse, ConfigResponse, DueUserChainRegistrationsResponse, GetCalculatedRewardResponse, GetUserRegistrationsResponse, QueryMsg, RewardResponse, SupportedChainsResponse, UserBalanceResponse, UserChainResponse};
-use crate::state::{user_chain_registrations, Chain, CONFIG, SUPPORTED_CHAINS, USER_BALANCES};
+use crate::icq::reconstruct::{query_covered_user_query_data, UserQueryData};
+use crate::helpers::{
+    build_msg_grant, get_due_user_chain_registrations, icq_data_age, neutron_err, required_msg_type_urls,
+    suggested_grant_expiration, tally_ledger, validate_remote_address, MSG_GRANT_TYPE_URL,
+};
+use crate::msg::{ChainResponse, CommissionChange, CommissionChangesResponse, ConfigResponse, DueUserChainRegistrationsResponse, FeeDenom, FeeDenomsResponse, GetCalculatedRewardResponse, GetUserRegistrationsResponse, InvariantsResponse, QueryMsg, RegistrationCommissionChanges, RemoteAddressOwnerResponse, RequiredGrant, RequiredGrantsResponse, RewardResponse, SupportedChainsResponse, TreasuryResponse, UserBalanceResponse, UserChainResponse};
+use crate::state::{user_chain_registrations, Chain, RegistrationStatusKind, CONFIG, LEDGER, FEE_DENOMS, HOST_FEES, SUPPORTED_CHAINS, TREASURY, USER_BALANCES, USER_FEE_BALANCES, VALIDATOR_QUERIES};
 
 pub const DEFAULT_LIMIT: u64 = 30;
 
@@ -26,11 +29,13 @@ pub fn query(deps: Deps<NeutronQuery>, env: Env, msg: QueryMsg) -> StdResult<Bin
             address,
             limit,
             start_after,
+            status,
         } => to_json_binary(&query_user_registrations(
             deps,
             address,
             limit,
             start_after,
+            status,
         )?),
         QueryMsg::CalculateReward {
             address,
@@ -45,9 +50,21 @@ pub fn query(deps: Deps<NeutronQuery>, env: Env, msg: QueryMsg) -> StdResult<Bin
         )?),
         QueryMsg::UserQuery { address, chain_id, remote_address } => to_json_binary(&query_user_query(deps, address, chain_id, remote_address)?),
         QueryMsg::UserBalance { address } => to_json_binary(&query_user_balance(deps, address)?),
+        QueryMsg::FeeDenoms {} => to_json_binary(&query_fee_denoms(deps)?),
+        QueryMsg::Treasury {} => to_json_binary(&query_treasury(deps)?),
+        QueryMsg::Invariants {} => to_json_binary(&query_invariants(deps, env)?),
         QueryMsg::DueUserChainRegistrations { delegators_amount } => to_json_binary(
             &query_due_user_chain_registrations(deps, env, delegators_amount)?,
         ),
+        QueryMsg::RemoteAddressOwner {
+            chain_id,
+            remote_address,
+        } => to_json_binary(&query_remote_address_owner(deps, chain_id, remote_address)?),
+        QueryMsg::RequiredGrants {
+            chain_id,
+            remote_address,
+        } => to_json_binary(&query_required_grants(deps, env, chain_id, remote_address)?),
+        QueryMsg::CommissionChanges { address } => to_json_binary(&query_commission_changes(deps, address)?),
     }
 }
 
@@ -69,13 +86,21 @@ pub fn query_supported_chains(
         .take(limit as usize)
         .collect::<Result<Vec<(String, Chain)>, _>>()?
         .into_iter()
-        .map(|(id, chain)| ChainResponse {
-            chain_id: id,
-            connection_id: chain.connection_id,
-            ica_address: chain.ica_address.map(|addr| addr.to_string()),
-            autocompound_cost: chain.autocompound_cost,
+        .map(|(id, chain)| {
+            Ok(ChainResponse {
+                unswept_host_fees: HOST_FEES.may_load(deps.storage, id.clone())?.unwrap_or_default(),
+                chain_id: id,
+                connection_id: chain.connection_id,
+                ica_address: chain.ica_address.map(|addr| addr.to_string()),
+                autocompound_cost: chain.autocompound_cost,
+                profile: chain.profile,
+                max_icq_staleness: chain.max_icq_staleness,
+                icq_update_period: chain.icq_update_period,
+                ica_tx: chain.ica_tx,
+                host_fee: chain.host_fee,
+            })
         })
-        .collect();
+        .collect::<StdResult<Vec<ChainResponse>>>()?;
 
     Ok(SupportedChainsResponse {
         chains: supported_chains,
@@ -87,6 +112,7 @@ pub fn query_user_registrations(
     address: String,
     limit: Option<u64>,
     _start_after: Option<String>, // TODO: Implement
+    status: Option<Vec<RegistrationStatusKind>>,
 ) -> StdResult<GetUserRegistrationsResponse> {
     let limit = limit.unwrap_or(DEFAULT_LIMIT);
     //let start = start_after.map(Bound::exclusive); // TODO: Implement From Str for the PK
@@ -98,19 +124,31 @@ pub fn query_user_registrations(
         .local_address
         .prefix(local_address)
         .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| match (item, &status) {
+            (Ok((_, reg)), Some(status)) => status.contains(&reg.status.kind()),
+            _ => true,
+        })
         .take(limit as usize)
-        .filter_map(|item| {
-            let user_chain_registration = item.unwrap().1;
-            Some(UserChainResponse {
+        .map(|item| {
+            let user_chain_registration = item?.1;
+            Ok(UserChainResponse {
                 chain_id: user_chain_registration.chain_id,
                 remote_address: user_chain_registration.remote_address,
                 validators: user_chain_registration.validators.clone(),
+                status: user_chain_registration.status,
+                grant_expiration: user_chain_registration.grant_expiration,
+                grant_allowance: user_chain_registration.grant_allowance,
+                fallback_validator: user_chain_registration.fallback_validator,
+                max_commission: user_chain_registration.max_commission,
+                withdraw_address: user_chain_registration.withdraw_address,
+                billing: user_chain_registration.billing,
                 delegator_delegations_reply_id: user_chain_registration
                     .delegator_delegations_reply_id,
                 delegator_delegations_icq_id: user_chain_registration.delegator_delegations_icq_id,
+                icq_shards: user_chain_registration.icq_shards,
             })
         })
-        .collect::<Vec<UserChainResponse>>();
+        .collect::<StdResult<Vec<UserChainResponse>>>()?;
 
     Ok(GetUserRegistrationsResponse {
         user_chain_registrations,
@@ -126,13 +164,32 @@ pub fn query_calculate_reward(
 ) -> StdResult<GetCalculatedRewardResponse> {
     let local_address = deps.api.addr_validate(&local_address)?;
     let user_reg =
-        user_chain_registrations().load(deps.storage, (local_address, chain_id, remote_address))?;
-    let icq_id = user_reg.delegator_delegations_icq_id.unwrap();
+        user_chain_registrations().load(deps.storage, (local_address, chain_id.clone(), remote_address))?;
+    let icq_id = user_reg
+        .delegator_delegations_icq_id
+        .ok_or_else(|| StdError::generic_err("interchain query is not registered yet"))?;
+    let chain = SUPPORTED_CHAINS.load(deps.storage, chain_id.clone())?;
 
-    let resp = get_registered_query(deps, icq_id).unwrap();
-    check_query_type(resp.registered_query.query_type, QueryType::KV).unwrap();
+    let resp = get_registered_query(deps, icq_id).map_err(neutron_err)?;
+    check_query_type(resp.registered_query.query_type, QueryType::KV).map_err(neutron_err)?;
+    let mut data_age = icq_data_age(&env, &chain, &resp.registered_query);
+    // A registration is as old as its oldest shard or validator query
+    let validator_icq_ids = user_reg
+        .validators
+        .iter()
+        .map(|validator| VALIDATOR_QUERIES.may_load(deps.storage, (chain_id.clone(), validator.clone())))
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .filter_map(|validator_query| validator_query.and_then(|q| q.icq_id));
+    for other_icq_id in user_reg.icq_shards.iter().filter_map(|s| s.icq_id).chain(validator_icq_ids) {
+        let other_resp = get_registered_query(deps, other_icq_id).map_err(neutron_err)?;
+        let other_age = icq_data_age(&env, &chain, &other_resp.registered_query);
+        if other_age.age > data_age.age {
+            data_age = other_age;
+        }
+    }
 
-    let user_query_data: UserQueryData = query_kv_result(deps, icq_id).unwrap();
+    let (user_query_data, coverage) = query_covered_user_query_data(deps, &chain.profile, &user_reg, chain.icq_update_period).map_err(neutron_err)?;
     deps.api
         .debug(format!("WASMDEBUG: user_query_data, delegation len: {}, val len {}, starting_infos len {} historical_rewards len {}",
                        user_query_data.delegations.len(),
@@ -141,29 +198,46 @@ pub fn query_calculate_reward(
                        user_query_data.validator_historical_rewards.len()
         ).as_str());
 
+    // Mixing values of different key sets or heights would give wrong rewards
+    if coverage.missing_keys > 0 {
+        return Err(StdError::generic_err(format!(
+            "ICQ result at remote height {} is missing {} keys, waiting for the next one",
+            coverage.remote_height, coverage.missing_keys
+        )));
+    }
+    if coverage.mixed_heights {
+        return Err(StdError::generic_err(format!(
+            "ICQ results are more than {} remote blocks apart from their delegations, waiting for the next ones",
+            chain.icq_update_period
+        )));
+    }
+
     let rewards = calculate_rewards(env, deps, user_query_data)?;
 
     Ok(GetCalculatedRewardResponse {
         rewards,
+        data_age,
+        remote_height: coverage.remote_height,
     })
 }
 
 fn calculate_rewards(env: Env, deps: Deps<NeutronQuery>, user_query_data: UserQueryData) -> Result<Vec<RewardResponse>, StdError> {
     let mut rewards: Vec<RewardResponse> = vec![];
     for delegation in user_query_data.delegations.iter() {
-        let delegator_starting_info = user_query_data.delegator_starting_infos.iter().find(|dsi| dsi.validator == delegation.validator_address).unwrap();
+        let missing = |what: &str| StdError::not_found(format!("{} for validator {}", what, delegation.validator_address));
+        let delegator_starting_info = user_query_data.delegator_starting_infos.iter().find(|dsi| dsi.validator == delegation.validator_address).ok_or_else(|| missing("delegator starting info"))?;
         let shares_as_dec = Decimal256::from_atomics(
             Uint256::from_str(&delegation.shares)?,
             0, //DECIMAL_PLACES,
-        ).unwrap();
-        let validator = user_query_data.validators.iter().find(|v| v.operator_address == delegation.validator_address).unwrap();
+        ).map_err(|e| StdError::generic_err(e.to_string()))?;
+        let validator = user_query_data.validators.iter().find(|v| v.operator_address == delegation.validator_address).ok_or_else(|| missing("validator"))?;
         let validator_shares_as_dec = Decimal256::from_atomics(
             Uint256::from_str(&validator.all_shares)?,
             0, //DECIMAL_PLACES,
-        ).unwrap();
-        let validator_tokens_as_u128 = validator.tokens.parse::<u128>().unwrap();
-        let historic_rewards = user_query_data.validator_historical_rewards.iter().find(|vhr| vhr.validator == delegation.validator_address).unwrap();
-        let validator_current_rewards = user_query_data.validator_current_rewards.iter().find(|vcr| vcr.validator == delegation.validator_address).unwrap();
+        ).map_err(|e| StdError::generic_err(e.to_string()))?;
+        let validator_tokens = Uint128::from_str(&validator.tokens)?;
+        let historic_rewards = user_query_data.validator_historical_rewards.iter().find(|vhr| vhr.validator == delegation.validator_address && vhr.period == delegator_starting_info.previous_period).ok_or_else(|| missing("validator historical rewards"))?;
+        let validator_current_rewards = user_query_data.validator_current_rewards.iter().find(|vcr| vcr.validator == delegation.validator_address).ok_or_else(|| missing("validator current rewards"))?;
         let calculated_rewards = calculate_delegation_rewards(
             env.clone(),
             deps.into_empty(),
@@ -174,7 +248,7 @@ fn calculate_rewards(env: Env, deps: Deps<NeutronQuery>, user_query_data: UserQu
             },
             shares_as_dec,
             validator_shares_as_dec,
-            Uint128::from(validator_tokens_as_u128),
+            validator_tokens,
             UtilsValidatorHistoricalRewards {
                 cumulative_reward_ratio: historic_rewards.cumulative_reward_ratio.clone(),
                 reference_count: historic_rewards.reference_count,
@@ -183,11 +257,13 @@ fn calculate_rewards(env: Env, deps: Deps<NeutronQuery>, user_query_data: UserQu
                 cumulative_reward_ratio: validator_current_rewards.rewards.clone(),
                 reference_count: 0,
             }
-        ).unwrap();
+        )?;
 
         rewards.push(RewardResponse {
             validator: delegation.validator_address.clone(),
             reward: calculated_rewards,
+            inactive_reason: validator.inactive_reason(),
+            commission_rate: validator.commission_rate,
         });
     }
     Ok(rewards)
@@ -201,10 +277,14 @@ pub fn query_user_query(
 ) -> StdResult<UserQueryData> {
     let local_address = deps.api.addr_validate(&local_address)?;
     let user_reg =
-        user_chain_registrations().load(deps.storage, (local_address, chain_id, remote_address))?;
-    let icq_id = user_reg.delegator_delegations_icq_id.unwrap();
+        user_chain_registrations().load(deps.storage, (local_address, chain_id.clone(), remote_address))?;
+    if user_reg.delegator_delegations_icq_id.is_none() {
+        return Err(StdError::generic_err("interchain query is not registered yet"));
+    }
+    let chain = SUPPORTED_CHAINS.load(deps.storage, chain_id)?;
 
-    let user_query_data: UserQueryData = query_kv_result(deps, icq_id).unwrap();
+    // Merged with the shards and joined with the shared validator queries, as the reward calculation sees it
+    let (user_query_data, _) = query_covered_user_query_data(deps, &chain.profile, &user_reg, chain.icq_update_period).map_err(neutron_err)?;
     deps.api
         .debug(format!("WASMDEBUG: user_query_data, delegation len: {}, val len {}, starting_infos len {} historical_rewards len {}",
                        user_query_data.delegations.len(),
@@ -223,34 +303,213 @@ pub fn query_user_balance(
     let local_address = deps.api.addr_validate(&address)?;
 
     let balance = USER_BALANCES
-        .may_load(deps.storage, local_address)
+        .may_load(deps.storage, local_address.clone())
         .map(|balance| balance.unwrap_or_default())?;
+    let fee_balances = USER_FEE_BALANCES
+        .prefix(local_address)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
 
     Ok(UserBalanceResponse {
         balance: balance.into(),
+        fee_balances,
     })
 }
 
+pub fn query_treasury(deps: Deps<NeutronQuery>) -> StdResult<TreasuryResponse> {
+    let balances = TREASURY
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(TreasuryResponse { balances })
+}
+
+pub fn query_invariants(deps: Deps<NeutronQuery>, env: Env) -> StdResult<InvariantsResponse> {
+    let ledger = LEDGER.may_load(deps.storage)?.unwrap_or_default();
+    let bank_balance = deps.querier.query_balance(env.contract.address, "untrn")?.amount;
+    let liabilities = ledger.liabilities();
+
+    let mut violations = vec![];
+    if bank_balance < liabilities {
+        violations.push(format!("bank balance {} is below the liabilities {}", bank_balance, liabilities));
+    }
+
+    // Every bucket has to add up to the records it summarizes
+    let records = tally_ledger(deps.storage)?;
+    for (bucket, ledger_amount, records) in [
+        ("user_balances", ledger.user_balances, records.user_balances),
+        ("escrowed_fees", ledger.escrowed_fees, records.escrowed_fees),
+        ("icq_deposits", ledger.icq_deposits, records.icq_deposits),
+        ("treasury", ledger.treasury, records.treasury),
+        ("relayer_escrow", ledger.relayer_escrow, records.relayer_escrow),
+    ] {
+        if ledger_amount != records {
+            violations.push(format!("{} ledger {} does not match the records {}", bucket, ledger_amount, records));
+        }
+    }
+
+    Ok(InvariantsResponse {
+        reserve: bank_balance.saturating_sub(liabilities),
+        ledger,
+        bank_balance,
+        liabilities,
+        violations,
+    })
+}
+
+pub fn query_fee_denoms(deps: Deps<NeutronQuery>) -> StdResult<FeeDenomsResponse> {
+    let fee_denoms = FEE_DENOMS
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, untrn_rate)| FeeDenom { denom, untrn_rate }))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(FeeDenomsResponse { fee_denoms })
+}
+
 pub fn query_due_user_chain_registrations(
     deps: Deps<NeutronQuery>, // Change to DepsMut<NeutronQuery>
     env: Env,
     delegators_amount: u64,
 ) -> StdResult<DueUserChainRegistrationsResponse> {
     let due_user_chain_registrations =
-        get_due_user_chain_registrations(&deps, &env, delegators_amount).unwrap();
+        get_due_user_chain_registrations(&deps, &env, delegators_amount)
+            .map_err(|e| StdError::generic_err(e.to_string()))?;
 
     Ok(DueUserChainRegistrationsResponse {
         due_user_chain_registrations,
     })
 }
 
+pub fn query_remote_address_owner(
+    deps: Deps<NeutronQuery>,
+    chain_id: String,
+    remote_address: String,
+) -> StdResult<RemoteAddressOwnerResponse> {
+    let owner = user_chain_registrations()
+        .idx
+        .remote_address
+        .item(deps.storage, (chain_id, remote_address))?
+        .map(|(_, reg)| reg.local_address.to_string());
+
+    Ok(RemoteAddressOwnerResponse { owner })
+}
+
+pub fn query_required_grants(
+    deps: Deps<NeutronQuery>,
+    env: Env,
+    chain_id: String,
+    remote_address: String,
+) -> StdResult<RequiredGrantsResponse> {
+    let chain = SUPPORTED_CHAINS.load(deps.storage, chain_id.clone())?;
+    validate_remote_address(&chain, &remote_address)
+        .map_err(|e| StdError::generic_err(e.to_string()))?;
+    let grantee = chain
+        .ica_address
+        .ok_or_else(|| StdError::generic_err(format!("ICA for chain {} is not ready yet", chain_id)))?
+        .to_string();
+
+    // Once registered, delegations can be restricted to the chosen validators and the fallback
+    let (mut validators, billing, fallback_validator) = user_chain_registrations()
+        .idx
+        .remote_address
+        .item(deps.storage, (chain_id, remote_address.clone()))?
+        .map(|(_, reg)| (reg.validators, reg.billing, reg.fallback_validator))
+        .unwrap_or_default();
+    if let Some(fallback_validator) = fallback_validator.as_ref().filter(|f| !validators.contains(f)) {
+        validators.push(fallback_validator.clone());
+    }
+    // Host fees can only be sent to the collector, the ICA itself when there is none
+    let host_fee_collector = chain.host_fee.as_ref().map(|host_fee| host_fee.collector.clone().unwrap_or(grantee.clone()));
+    let host_fee_spend_limit = chain
+        .host_fee
+        .as_ref()
+        .and_then(|host_fee| host_fee.grant_spend_limit)
+        .map(|limit| coin(limit.u128(), chain.denom.clone()));
+    let host_fee_send = host_fee_collector.as_deref().zip(host_fee_spend_limit.as_ref());
+    let expiration = suggested_grant_expiration(&env);
+
+    let grants = required_msg_type_urls(&billing, fallback_validator.as_ref())
+        .into_iter()
+        .map(|msg_type_url| {
+            let (authorization_type_url, msg_grant) =
+                build_msg_grant(
+                    &remote_address,
+                    &grantee,
+                    msg_type_url,
+                    &validators,
+                    host_fee_send,
+                    chain.profile.sdk_version,
+                    expiration,
+                );
+            RequiredGrant {
+                msg_type_url: msg_type_url.to_string(),
+                authorization_type_url,
+                msg_grant_type_url: MSG_GRANT_TYPE_URL.to_string(),
+                msg_grant,
+            }
+        })
+        .collect();
+
+    Ok(RequiredGrantsResponse {
+        granter: remote_address,
+        grantee,
+        expiration,
+        grants,
+    })
+}
+
+pub fn query_commission_changes(deps: Deps<NeutronQuery>, address: String) -> StdResult<CommissionChangesResponse> {
+    let local_address = deps.api.addr_validate(&address)?;
+
+    let mut registrations = vec![];
+    for item in user_chain_registrations()
+        .idx
+        .local_address
+        .prefix(local_address)
+        .range(deps.storage, None, None, Order::Ascending)
+    {
+        let (_, registration) = item?;
+        let chain = SUPPORTED_CHAINS.load(deps.storage, registration.chain_id.clone())?;
+        // Nothing to compare against until the first result and compound
+        let Ok((user_query_data, _)) = query_covered_user_query_data(deps, &chain.profile, &registration, chain.icq_update_period) else {
+            continue;
+        };
+        let changes: Vec<CommissionChange> = registration
+            .compounded_commissions
+            .iter()
+            .filter_map(|compounded| {
+                let validator = user_query_data
+                    .validators
+                    .iter()
+                    .find(|v| v.operator_address == compounded.validator)?;
+                (validator.commission_rate != compounded.rate).then(|| CommissionChange {
+                    validator: compounded.validator.clone(),
+                    last_compound_rate: compounded.rate,
+                    current_rate: validator.commission_rate,
+                })
+            })
+            .collect();
+        if !changes.is_empty() {
+            registrations.push(RegistrationCommissionChanges {
+                chain_id: registration.chain_id,
+                remote_address: registration.remote_address,
+                changes,
+            });
+        }
+    }
+
+    Ok(CommissionChangesResponse { registrations })
+}
+
 #[cfg(test)]
 mod tests {
     mod test_query_supported_chains {
         use cosmwasm_std::{coins, from_json};
         use cosmwasm_std::testing::{mock_env, mock_info};
 
-        use crate::execute::execute;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, SupportedChainsResponse};
         use crate::query::query;
@@ -269,6 +528,9 @@ mod tests {
                     admin: info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
             .unwrap();
@@ -277,8 +539,13 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(deps.as_mut(), mock_env(), info.clone(), add_chain_msg).unwrap();
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg).unwrap();
 
             let query_msg = QueryMsg::SupportedChains {
                 limit: None,
@@ -297,14 +564,16 @@ mod tests {
         use cosmwasm_std::{coins, from_json};
         use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
 
-        use crate::execute::execute;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{
             ExecuteMsg, GetUserRegistrationsResponse, InstantiateMsg, QueryMsg,
             UserChainRegistrationInput,
         };
         use crate::query::query;
-        use crate::testing::helpers::mock_neutron_dependencies;
+        use crate::reply::reply;
+        use crate::state::{RegistrationStatus, RegistrationStatusKind};
+        use crate::testing::helpers::{mock_neutron_dependencies, mock_register_query_reply};
 
         #[test]
         fn test_query_user_registrations() {
@@ -319,6 +588,9 @@ mod tests {
                     admin: info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
             .unwrap();
@@ -327,22 +599,33 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(deps.as_mut(), mock_env(), info.clone(), add_chain_msg1).unwrap();
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg1).unwrap();
             let add_chain_msg2 = ExecuteMsg::AddSupportedChain {
                 chain_id: "osmosis".to_string(),
                 connection_id: "osmosis_connection_id".to_string(),
                 denom: "uosmo".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(deps.as_mut(), mock_env(), info.clone(), add_chain_msg2).unwrap();
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg2).unwrap();
             let info = mock_info("local_user", &coins(1000000, "untrn"));
             let cosmos_mock_api = MockApi::default().with_prefix("cosmos");
+            let cosmos_valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
             let cosmos_remote_user_addr = cosmos_mock_api.addr_make("remote_user");
-            let cosmos_validator1 = cosmos_mock_api.addr_make("validator1");
-            let cosmos_validator2 = cosmos_mock_api.addr_make("validator2");
+            let cosmos_validator1 = cosmos_valoper_mock_api.addr_make("validator1");
+            let cosmos_validator2 = cosmos_valoper_mock_api.addr_make("validator2");
             let osmosis_remote_user_addr = cosmos_mock_api.addr_make("osmo");
-            let osmosis_validator1 = cosmos_mock_api.addr_make("osmo_validator1");
+            let osmosis_validator1 = cosmos_valoper_mock_api.addr_make("osmo_validator1");
 
             let register_user_msg = ExecuteMsg::RegisterUser {
                 registrations: vec![
@@ -353,31 +636,86 @@ mod tests {
                             cosmos_validator1.to_string(),
                             cosmos_validator2.to_string(),
                         ],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
                     },
                     UserChainRegistrationInput {
                         chain_id: "osmosis".to_string(),
                         address: osmosis_remote_user_addr.to_string(),
                         validators: vec![osmosis_validator1.to_string()],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
                     },
                 ],
             };
-            execute(deps.as_mut(), mock_env(), info.clone(), register_user_msg).unwrap();
+            execute_checked(&mut deps, mock_env(), info.clone(), register_user_msg).unwrap();
 
             let query_msg = QueryMsg::UserRegistrations {
                 address: "local_user".to_string(),
                 limit: None,
                 start_after: None,
+                status: None,
             };
             let response = query(deps.as_ref(), mock_env(), query_msg).unwrap();
             let res: GetUserRegistrationsResponse = from_json(&response).unwrap();
             assert_eq!(res.user_chain_registrations.len(), 2);
+            assert!(res
+                .user_chain_registrations
+                .iter()
+                .all(|r| r.status == RegistrationStatus::PendingQuery));
+
+            // The cosmos query registers, the osmosis one fails
+            let registration_on = |chain_id: &str| {
+                res.user_chain_registrations.iter().find(|r| r.chain_id == chain_id).unwrap()
+            };
+            let cosmos_reg = registration_on("chain_id");
+            let osmosis_reg = registration_on("osmosis");
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(cosmos_reg.delegator_delegations_reply_id, Ok(1)),
+            )
+            .unwrap();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(osmosis_reg.delegator_delegations_reply_id, Err("out of gas".to_string())),
+            )
+            .unwrap();
+
+            let registrations_with_status = |status: Vec<RegistrationStatusKind>| {
+                let query_msg = QueryMsg::UserRegistrations {
+                    address: "local_user".to_string(),
+                    limit: None,
+                    start_after: None,
+                    status: Some(status),
+                };
+                let response = query(deps.as_ref(), mock_env(), query_msg).unwrap();
+                from_json::<GetUserRegistrationsResponse>(&response)
+                    .unwrap()
+                    .user_chain_registrations
+            };
+            let waiting_for_grant = registrations_with_status(vec![RegistrationStatusKind::GrantMissing]);
+            assert_eq!(waiting_for_grant.len(), 1);
+            assert_eq!(waiting_for_grant[0].delegator_delegations_icq_id, Some(1));
+            let failed = registrations_with_status(vec![RegistrationStatusKind::Failed]);
+            assert_eq!(failed.len(), 1);
+            assert_eq!(
+                failed[0].status,
+                RegistrationStatus::Failed {
+                    reason: "ICQ registration failed: out of gas".to_string()
+                }
+            );
+            assert!(registrations_with_status(vec![RegistrationStatusKind::Active, RegistrationStatusKind::PendingQuery]).is_empty());
         }
     }
 
     mod test_calculate_rewards {
-        use cosmwasm_std::{Coin, Uint128};
+        use cosmwasm_std::{Coin, Decimal, Uint128};
         use cosmwasm_std::testing::mock_env;
-        use crate::icq::reconstruct::{Delegation, DelegatorStartingInfoWithValidator, UserQueryData, Validator, ValidatorCurrentRewards, ValidatorHistoricalRewards};
+        use crate::icq::reconstruct::{Delegation, DelegatorStartingInfoWithValidator, UserQueryData, Validator, ValidatorCurrentRewards, ValidatorHistoricalRewards, ValidatorStatus};
         use crate::query::calculate_rewards;
         use crate::testing::helpers::mock_neutron_dependencies;
 
@@ -413,7 +751,7 @@ mod tests {
     tokens: "1007000000000"
 
              */
-           let user_query_data = UserQueryData {
+           let mut user_query_data = UserQueryData {
                delegations: vec![
                    Delegation {
                        delegator_address: "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw".to_string(),
@@ -425,7 +763,10 @@ mod tests {
                      Validator {
                           operator_address: "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn".to_string(),
                           tokens: "1007000000000".to_string(),
-                          all_shares: "1007000000000000000000000000000".to_string()
+                          all_shares: "1007000000000000000000000000000".to_string(),
+                          jailed: false,
+                          status: ValidatorStatus::Bonded,
+                          commission_rate: Decimal::percent(10),
                      }
                 ],
                 delegator_starting_infos: vec![
@@ -440,6 +781,7 @@ mod tests {
                 validator_historical_rewards: vec![
                     ValidatorHistoricalRewards {
                         validator: "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn".to_string(),
+                        period: 11,
                         cumulative_reward_ratio: vec![
                             Coin {
                                 denom: "uatom".to_string(),
@@ -461,16 +803,29 @@ mod tests {
                         ]
                     }
                 ],
+                grants: vec![],
+                withdraw_address: None,
             };
 
             let deps = mock_neutron_dependencies();
 
-            let rewards = calculate_rewards(mock_env(), deps.as_ref(), user_query_data).unwrap();
+            let rewards = calculate_rewards(mock_env(), deps.as_ref(), user_query_data.clone()).unwrap();
             assert_eq!(rewards.len(), 1);
             assert_eq!(rewards[0].validator, "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn");
             assert_eq!(rewards[0].reward.len(), 1);
             assert_eq!(rewards[0].reward[0].denom, "uatom");
             assert_eq!(rewards[0].reward[0].amount, Uint128::new(2444866473));
+            assert_eq!(rewards[0].inactive_reason, None);
+
+            // Rewards are still there to claim, but the validator would not be compounded into
+            user_query_data.validators[0].status = ValidatorStatus::Unbonding;
+            let rewards = calculate_rewards(mock_env(), deps.as_ref(), user_query_data.clone()).unwrap();
+            assert_eq!(rewards[0].reward[0].amount, Uint128::new(2444866473));
+            assert_eq!(rewards[0].inactive_reason, Some("unbonding".to_string()));
+
+            user_query_data.validators[0].jailed = true;
+            let rewards = calculate_rewards(mock_env(), deps.as_ref(), user_query_data).unwrap();
+            assert_eq!(rewards[0].inactive_reason, Some("jailed".to_string()));
         }
     }
 
@@ -480,10 +835,11 @@ mod tests {
         use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
         use cosmwasm_std::{coins, from_json};
 
-        use crate::execute::execute;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{DueUserChainRegistrationsResponse, ExecuteMsg, InstantiateMsg, QueryMsg};
         use crate::query::query;
+        use crate::state::{user_chain_registrations, RegistrationStatus};
         use crate::testing::helpers::mock_neutron_dependencies;
 
         #[test]
@@ -499,6 +855,9 @@ mod tests {
                     admin: creator_info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
                 .unwrap();
@@ -508,9 +867,14 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(
-                deps.as_mut(),
+            execute_checked(
+                &mut deps,
                 mock_env(),
                 creator_info.clone(),
                 add_supported_chain_msg,
@@ -518,11 +882,12 @@ mod tests {
                 .unwrap();
 
             let mock_api = MockApi::default().with_prefix("cosmos");
+            let valoper_mock_api = MockApi::default().with_prefix("cosmosvaloper");
             let remote_user_addr = mock_api.addr_make("remote_user");
 
             let info = mock_info("local_user", &coins(1000000, "untrn"));
-            let validator1 = mock_api.addr_make("validator1");
-            let validator2 = mock_api.addr_make("validator2");
+            let validator1 = valoper_mock_api.addr_make("validator1");
+            let validator2 = valoper_mock_api.addr_make("validator2");
             let register_user_msg = ExecuteMsg::RegisterUser {
                 registrations: vec![crate::msg::UserChainRegistrationInput {
                     chain_id: "chain_id".to_string(),
@@ -531,20 +896,23 @@ mod tests {
                         validator1.clone().to_string(),
                         validator2.clone().to_string(),
                     ],
+                    fallback_validator: None,
+                    max_commission: None,
+                    billing: None,
                 }],
             };
 
             // Register a user at height 1000
             let mut mock_env = mock_env();
             mock_env.block.height = 1000;
-            let res = execute(
-                deps.as_mut(),
+            let res = execute_checked(
+                &mut deps,
                 mock_env.clone(),
                 info.clone(),
                 register_user_msg,
             )
                 .unwrap();
-            assert_eq!(1, res.messages.len());
+            assert_eq!(3, res.messages.len());
 
             // Increase 99 blocks, we still should not be able to compound
             mock_env.block.height = 1099;
@@ -555,14 +923,720 @@ mod tests {
             let res = from_json::<DueUserChainRegistrationsResponse>(&response).unwrap();
             assert_eq!(res.due_user_chain_registrations.len(), 0);
 
-            // Compound time!
+            // Compound time, but the grant has not been seen over ICQ yet
             mock_env.block.height = 1100; // init + autocompound_threshold set as 100
             let query_msg = QueryMsg::DueUserChainRegistrations {
                 delegators_amount: 1,
             };
+            let response = query(deps.as_ref(), mock_env.clone(), query_msg.clone()).unwrap();
+            let res = from_json::<DueUserChainRegistrationsResponse>(&response).unwrap();
+            assert_eq!(res.due_user_chain_registrations.len(), 0);
+
+            // Once the grant shows up the registration is due
+            let key = (info.sender.clone(), "chain_id".to_string(), remote_user_addr.to_string());
+            let mut registration = user_chain_registrations().load(deps.as_ref().storage, key.clone()).unwrap();
+            registration.status = RegistrationStatus::Active;
+            user_chain_registrations().save(deps.as_mut().storage, key, &registration).unwrap();
+
             let response = query(deps.as_ref(), mock_env, query_msg).unwrap();
             let res = from_json::<DueUserChainRegistrationsResponse>(&response).unwrap();
             assert_eq!(res.due_user_chain_registrations.len(), 1);
         }
     }
+
+    mod test_query_remote_address_owner {
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, from_json};
+
+        use crate::error::ContractError;
+        use crate::testing::helpers::execute_checked;
+        use crate::instantiate::instantiate;
+        use crate::msg::{
+            ExecuteMsg, InstantiateMsg, QueryMsg, RemoteAddressOwnerResponse,
+            UserChainRegistrationInput,
+        };
+        use crate::query::query;
+        use crate::testing::helpers::mock_neutron_dependencies;
+
+        #[test]
+        fn test_query_remote_address_owner() {
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            let add_chain_msg = ExecuteMsg::AddSupportedChain {
+                chain_id: "chain_id".to_string(),
+                connection_id: "connection_id".to_string(),
+                denom: "denom".to_string(),
+                autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
+            };
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg).unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user");
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1");
+            let register_user_msg = ExecuteMsg::RegisterUser {
+                registrations: vec![UserChainRegistrationInput {
+                    chain_id: "chain_id".to_string(),
+                    address: remote_user_addr.to_string(),
+                    validators: vec![validator.to_string()],
+                    fallback_validator: None,
+                    max_commission: None,
+                    billing: None,
+                }],
+            };
+
+            let owner_query = QueryMsg::RemoteAddressOwner {
+                chain_id: "chain_id".to_string(),
+                remote_address: remote_user_addr.to_string(),
+            };
+            let response = query(deps.as_ref(), mock_env(), owner_query.clone()).unwrap();
+            let res: RemoteAddressOwnerResponse = from_json(&response).unwrap();
+            assert_eq!(res.owner, None);
+
+            let first_user = mock_info("local_user", &[]);
+            execute_checked(&mut deps, mock_env(), first_user.clone(), register_user_msg.clone()).unwrap();
+
+            let response = query(deps.as_ref(), mock_env(), owner_query).unwrap();
+            let res: RemoteAddressOwnerResponse = from_json(&response).unwrap();
+            assert_eq!(res.owner, Some("local_user".to_string()));
+
+            // A second local user can not register the same remote delegator
+            let second_user = mock_info("other_local_user", &[]);
+            let err = execute_checked(&mut deps, mock_env(), second_user, register_user_msg).unwrap_err();
+            assert_eq!(
+                err,
+                ContractError::RemoteAddressAlreadyRegistered {
+                    chain_id: "chain_id".to_string(),
+                    remote_address: remote_user_addr.to_string(),
+                    owner: "local_user".to_string(),
+                }
+            );
+        }
+    }
+
+    mod test_query_required_grants {
+        use cosmos_sdk_proto::cosmos::authz::v1beta1::MsgGrant;
+        use cosmos_sdk_proto::cosmos::bank::v1beta1::SendAuthorization;
+        use cosmos_sdk_proto::cosmos::base::v1beta1::Coin as ProtoCoin;
+        use cosmos_sdk_proto::cosmos::staking::v1beta1::{stake_authorization, StakeAuthorization};
+        use cosmos_sdk_proto::traits::Message;
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, from_json, Addr, Decimal, Uint128};
+
+        use crate::testing::helpers::execute_checked;
+        use crate::instantiate::instantiate;
+        use crate::msg::{
+            ExecuteMsg, InstantiateMsg, QueryMsg, RequiredGrantsResponse, UserChainRegistrationInput,
+        };
+        use crate::query::query;
+        use crate::state::{BillingMode, HostFeeConfig, SUPPORTED_CHAINS};
+        use crate::testing::helpers::{
+            mock_compound_ready, mock_neutron_dependencies, MockValidator, MOCK_DELEGATOR, MOCK_ICA_ADDRESS,
+        };
+
+        #[test]
+        fn test_query_required_grants() {
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            let add_chain_msg = ExecuteMsg::AddSupportedChain {
+                chain_id: "chain_id".to_string(),
+                connection_id: "connection_id".to_string(),
+                denom: "denom".to_string(),
+                autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
+            };
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg).unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user");
+            let ica_address = MockApi::default().with_prefix("cosmos").addr_make("ica");
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1");
+            let fallback = MockApi::default().with_prefix("cosmosvaloper").addr_make("fallback");
+            let grants_query = QueryMsg::RequiredGrants {
+                chain_id: "chain_id".to_string(),
+                remote_address: remote_user_addr.to_string(),
+            };
+
+            // The ICA has to exist before anything can be granted to it
+            query(deps.as_ref(), mock_env(), grants_query.clone()).unwrap_err();
+            SUPPORTED_CHAINS
+                .update(deps.as_mut().storage, "chain_id".to_string(), |chain| -> cosmwasm_std::StdResult<_> {
+                    let mut chain = chain.unwrap();
+                    chain.ica_address = Some(Addr::unchecked(ica_address.to_string()));
+                    Ok(chain)
+                })
+                .unwrap();
+
+            // Addresses of another chain are refused
+            let wrong_prefix_query = QueryMsg::RequiredGrants {
+                chain_id: "chain_id".to_string(),
+                remote_address: MockApi::default().with_prefix("osmo").addr_make("remote_user").to_string(),
+            };
+            query(deps.as_ref(), mock_env(), wrong_prefix_query).unwrap_err();
+
+            // Before registering the validators are unknown
+            let response = query(deps.as_ref(), mock_env(), grants_query.clone()).unwrap();
+            let res: RequiredGrantsResponse = from_json(&response).unwrap();
+            assert_eq!(res.granter, remote_user_addr.to_string());
+            assert_eq!(res.grantee, ica_address.to_string());
+            assert_eq!(res.expiration, mock_env().block.time.plus_seconds(60 * 60 * 24 * 365));
+            assert_eq!(res.grants.len(), 1);
+            assert_eq!(res.grants[0].msg_type_url, "/cosmos.staking.v1beta1.MsgDelegate");
+            assert_eq!(res.grants[0].authorization_type_url, "/cosmos.authz.v1beta1.GenericAuthorization");
+            assert_eq!(res.grants[0].msg_grant_type_url, "/cosmos.authz.v1beta1.MsgGrant");
+
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                mock_info("local_user", &[]),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_user_addr.to_string(),
+                        validators: vec![validator.to_string()],
+                        fallback_validator: Some(fallback.to_string()),
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+            .unwrap();
+
+            // Registered, delegations are restricted to the chosen validators and the fallback
+            let response = query(deps.as_ref(), mock_env(), grants_query).unwrap();
+            let res: RequiredGrantsResponse = from_json(&response).unwrap();
+            assert_eq!(res.grants[0].authorization_type_url, "/cosmos.staking.v1beta1.StakeAuthorization");
+
+            let msg_grant = MsgGrant::decode(res.grants[0].msg_grant.as_slice()).unwrap();
+            assert_eq!(msg_grant.granter, remote_user_addr.to_string());
+            assert_eq!(msg_grant.grantee, ica_address.to_string());
+            let grant = msg_grant.grant.unwrap();
+            assert_eq!(grant.expiration.unwrap().seconds as u64, res.expiration.seconds());
+            let stake_authorization = StakeAuthorization::decode(grant.authorization.unwrap().value.as_slice()).unwrap();
+            assert_eq!(stake_authorization.authorization_type, 1); // AUTHORIZATION_TYPE_DELEGATE
+            assert_eq!(stake_authorization.max_tokens, None);
+            assert_eq!(
+                stake_authorization.validators,
+                Some(stake_authorization::Policy::AllowList(stake_authorization::Validators {
+                    address: vec![validator.to_string(), fallback.to_string()],
+                }))
+            );
+
+            // Compounds redirected to the fallback withdraw the rewards of the skipped validator first
+            assert_eq!(res.grants.len(), 2);
+            assert_eq!(res.grants[1].msg_type_url, "/cosmos.distribution.v1beta1.MsgWithdrawDelegatorReward");
+            assert_eq!(res.grants[1].authorization_type_url, "/cosmos.authz.v1beta1.GenericAuthorization");
+        }
+
+        #[test]
+        fn test_query_required_grants_host_fee() {
+            let validator = MockValidator {
+                address: MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string(),
+                icq_id: 2,
+                jailed: false,
+            };
+            let host_fee = HostFeeConfig {
+                percentage: Decimal::percent(5),
+                flat: Uint128::zero(),
+                collector: None,
+                sweep_channel: None,
+                sweep_denom: None,
+                grant_spend_limit: Some(Uint128::new(1000000)),
+            };
+            let (deps, _) = mock_compound_ready(&[validator], None, BillingMode::HostChain, Some(host_fee));
+
+            let grants_query = QueryMsg::RequiredGrants {
+                chain_id: "chain_id".to_string(),
+                remote_address: MOCK_DELEGATOR.to_string(),
+            };
+            let res: RequiredGrantsResponse = from_json(query(deps.as_ref(), mock_env(), grants_query).unwrap()).unwrap();
+            assert_eq!(res.grants.len(), 2);
+            assert_eq!(res.grants[1].msg_type_url, "/cosmos.bank.v1beta1.MsgSend");
+            assert_eq!(res.grants[1].authorization_type_url, "/cosmos.bank.v1beta1.SendAuthorization");
+
+            // Only up to the spend limit, and only to the ICA collecting the fees
+            let msg_grant = MsgGrant::decode(res.grants[1].msg_grant.as_slice()).unwrap();
+            let authorization = msg_grant.grant.unwrap().authorization.unwrap();
+            let mut expected = SendAuthorization {
+                spend_limit: vec![ProtoCoin {
+                    denom: "uatom".to_string(),
+                    amount: "1000000".to_string(),
+                }],
+            }
+            .encode_to_vec();
+            expected.extend([0x12, MOCK_ICA_ADDRESS.len() as u8]);
+            expected.extend(MOCK_ICA_ADDRESS.as_bytes());
+            assert_eq!(authorization.value, expected);
+        }
+    }
+
+    mod test_calculate_reward_data_age {
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, from_json};
+        use cosmwasm_std::Binary;
+        use neutron_sdk::bindings::types::{Height, InterchainQueryResult, RegisteredQuery, StorageValue};
+        use neutron_sdk::interchain_queries::types::QueryType;
+
+        use crate::icq::keys::{create_all_icq_keys_for_user, create_all_icq_keys_for_validator};
+        use crate::state::{ChainProfile, VALIDATOR_QUERIES};
+
+        use crate::testing::helpers::execute_checked;
+        use crate::instantiate::instantiate;
+        use crate::msg::{
+            ExecuteMsg, GetCalculatedRewardResponse, IcqDataAge, InstantiateMsg, QueryMsg,
+            UserChainRegistrationInput,
+        };
+        use crate::query::query;
+        use crate::state::user_chain_registrations;
+        use crate::testing::helpers::mock_neutron_dependencies;
+
+        #[test]
+        fn test_calculate_reward_data_age() {
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            let add_chain_msg = ExecuteMsg::AddSupportedChain {
+                chain_id: "chain_id".to_string(),
+                connection_id: "connection_id".to_string(),
+                denom: "denom".to_string(),
+                autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: Some(50),
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
+            };
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg).unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user");
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1");
+            let local_user = mock_info("local_user", &[]);
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                local_user.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_user_addr.to_string(),
+                        validators: vec![validator.to_string()],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+            .unwrap();
+
+            // Normally done in the register query reply
+            let reg_key = (local_user.sender.clone(), "chain_id".to_string(), remote_user_addr.to_string());
+            let mut registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            registration.delegator_delegations_icq_id = Some(1);
+            user_chain_registrations().save(deps.as_mut().storage, reg_key, &registration).unwrap();
+
+            let mut env = mock_env();
+            deps.querier.add_registered_query(RegisteredQuery {
+                id: 1,
+                owner: "contract".to_string(),
+                keys: vec![],
+                query_type: QueryType::KV,
+                transactions_filter: "".to_string(),
+                connection_id: "connection_id".to_string(),
+                update_period: 5,
+                last_submitted_result_local_height: env.block.height - 10,
+                last_submitted_result_remote_height: Height {
+                    revision_number: 0,
+                    revision_height: 4242,
+                },
+                deposit: vec![],
+                submit_timeout: 0,
+                registered_at_height: 0,
+            });
+            // Nothing delegated yet, every key comes back empty
+            let kv_results = create_all_icq_keys_for_user(
+                &ChainProfile::default(),
+                remote_user_addr.to_string(),
+                vec![validator.to_string()],
+                None,
+                None,
+                &[],
+                false,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|k| StorageValue {
+                storage_prefix: k.path,
+                key: k.key,
+                value: Binary::default(),
+            })
+            .collect::<Vec<_>>();
+            deps.querier.add_query_result(1, InterchainQueryResult {
+                kv_results: kv_results.clone(),
+                height: 4242,
+                revision: 0,
+            });
+
+            // The validator is unknown on the remote chain too, its shared query comes back empty
+            let validator_key = ("chain_id".to_string(), validator.to_string());
+            let mut validator_query = VALIDATOR_QUERIES.load(deps.as_ref().storage, validator_key.clone()).unwrap();
+            validator_query.icq_id = Some(2);
+            VALIDATOR_QUERIES.save(deps.as_mut().storage, validator_key, &validator_query).unwrap();
+            let validator_kv_results = create_all_icq_keys_for_validator(&ChainProfile::default(), validator.to_string())
+                .unwrap()
+                .into_iter()
+                .map(|k| StorageValue {
+                    storage_prefix: k.path,
+                    key: k.key,
+                    value: Binary::default(),
+                })
+                .collect::<Vec<_>>();
+            deps.querier.add_query_result(2, InterchainQueryResult {
+                kv_results: validator_kv_results,
+                height: 4240,
+                revision: 0,
+            });
+            let validator_registered_query = |local_height: u64| RegisteredQuery {
+                id: 2,
+                owner: "contract".to_string(),
+                keys: vec![],
+                query_type: QueryType::KV,
+                transactions_filter: "".to_string(),
+                connection_id: "connection_id".to_string(),
+                update_period: 5,
+                last_submitted_result_local_height: local_height,
+                last_submitted_result_remote_height: Height {
+                    revision_number: 0,
+                    revision_height: 4240,
+                },
+                deposit: vec![],
+                submit_timeout: 0,
+                registered_at_height: 0,
+            };
+            deps.querier.add_registered_query(validator_registered_query(env.block.height - 5));
+
+            let reward_query = QueryMsg::CalculateReward {
+                address: local_user.sender.to_string(),
+                chain_id: "chain_id".to_string(),
+                remote_address: remote_user_addr.to_string(),
+            };
+            let response = query(deps.as_ref(), env.clone(), reward_query.clone()).unwrap();
+            let res: GetCalculatedRewardResponse = from_json(&response).unwrap();
+            assert_eq!(
+                res.data_age,
+                IcqDataAge {
+                    last_submitted_local_height: env.block.height - 10,
+                    last_submitted_remote_height: 4242,
+                    age: 10,
+                    stale: false,
+                }
+            );
+
+            // The shared validator query ages the registration like its own queries
+            deps.querier.add_registered_query(validator_registered_query(env.block.height - 60));
+            let res: GetCalculatedRewardResponse = from_json(query(deps.as_ref(), env.clone(), reward_query.clone()).unwrap()).unwrap();
+            assert_eq!(res.data_age.age, 60);
+            assert!(res.data_age.stale);
+            deps.querier.add_registered_query(validator_registered_query(env.block.height - 5));
+
+            // The relayer stopped submitting results
+            env.block.height += 41;
+            let response = query(deps.as_ref(), env.clone(), reward_query.clone()).unwrap();
+            let res: GetCalculatedRewardResponse = from_json(&response).unwrap();
+            assert_eq!(res.data_age.age, 51);
+            assert!(res.data_age.stale);
+            assert_eq!(res.remote_height, 4242);
+            assert!(res.rewards.is_empty());
+
+            // A result taken before the key set held the validator's delegation is not used
+            deps.querier.add_query_result(1, InterchainQueryResult {
+                kv_results: kv_results[1..].to_vec(),
+                height: 4243,
+                revision: 0,
+            });
+            let err = query(deps.as_ref(), env, reward_query).unwrap_err();
+            assert!(err.to_string().contains("ICQ result at remote height 4243 is missing"));
+        }
+    }
+
+    mod test_query_commission_changes {
+        use cosmos_sdk_proto::cosmos::staking::v1beta1::{Commission, CommissionRates, Delegation, Validator};
+        use cosmos_sdk_proto::prost::Message;
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, Binary, Decimal};
+        use neutron_sdk::bindings::types::{InterchainQueryResult, StorageValue};
+
+        use crate::testing::helpers::execute_checked;
+        use crate::icq::keys::{create_all_icq_keys_for_user, create_all_icq_keys_for_validator};
+        use crate::instantiate::instantiate;
+        use crate::msg::{CommissionChange, ExecuteMsg, InstantiateMsg, RegistrationCommissionChanges, UserChainRegistrationInput};
+        use crate::query::query_commission_changes;
+        use crate::state::{user_chain_registrations, ChainProfile, ValidatorCommission, VALIDATOR_QUERIES};
+        use crate::testing::helpers::mock_neutron_dependencies;
+
+        #[test]
+        fn test_query_commission_changes() {
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &coins(1000000, "untrn"));
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "denom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+            .unwrap();
+
+            let remote_user_addr = MockApi::default().with_prefix("cosmos").addr_make("remote_user").to_string();
+            let validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("validator1").to_string();
+            let local_user = mock_info("local_user", &[]);
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                local_user.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: remote_user_addr.clone(),
+                        validators: vec![validator.clone()],
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+            .unwrap();
+
+            // Normally done in the register query replies
+            let reg_key = (local_user.sender.clone(), "chain_id".to_string(), remote_user_addr.clone());
+            let mut registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            registration.delegator_delegations_icq_id = Some(1);
+            user_chain_registrations().save(deps.as_mut().storage, reg_key.clone(), &registration).unwrap();
+            let validator_key = ("chain_id".to_string(), validator.clone());
+            let mut validator_query = VALIDATOR_QUERIES.load(deps.as_ref().storage, validator_key.clone()).unwrap();
+            validator_query.icq_id = Some(2);
+            VALIDATOR_QUERIES.save(deps.as_mut().storage, validator_key, &validator_query).unwrap();
+
+            let mut kv_results = create_all_icq_keys_for_user(&ChainProfile::default(), remote_user_addr.clone(), vec![validator.clone()], None, None, &[], false)
+                .unwrap()
+                .into_iter()
+                .map(|k| StorageValue {
+                    storage_prefix: k.path,
+                    key: k.key,
+                    value: Binary::default(),
+                })
+                .collect::<Vec<_>>();
+            kv_results[0].value = Binary::from(
+                Delegation {
+                    delegator_address: remote_user_addr.clone(),
+                    validator_address: validator.clone(),
+                    shares: "1000000".to_string(),
+                }
+                .encode_to_vec(),
+            );
+            deps.querier.add_query_result(1, InterchainQueryResult {
+                kv_results,
+                height: 100,
+                revision: 0,
+            });
+
+            // The validator record says 10%
+            let mut validator_kv_results = create_all_icq_keys_for_validator(&ChainProfile::default(), validator.clone())
+                .unwrap()
+                .into_iter()
+                .map(|k| StorageValue {
+                    storage_prefix: k.path,
+                    key: k.key,
+                    value: Binary::default(),
+                })
+                .collect::<Vec<_>>();
+            validator_kv_results[0].value = Binary::from(
+                Validator {
+                    operator_address: validator.clone(),
+                    status: 3,
+                    tokens: "1000000".to_string(),
+                    delegator_shares: "1000000".to_string(),
+                    commission: Some(Commission {
+                        commission_rates: Some(CommissionRates {
+                            rate: "100000000000000000".to_string(),
+                            max_rate: "200000000000000000".to_string(),
+                            max_change_rate: "10000000000000000".to_string(),
+                        }),
+                        update_time: None,
+                    }),
+                    ..Validator::default()
+                }
+                .encode_to_vec(),
+            );
+            deps.querier.add_query_result(2, InterchainQueryResult {
+                kv_results: validator_kv_results,
+                height: 100,
+                revision: 0,
+            });
+
+            // Never compounded, nothing to compare against
+            let res = query_commission_changes(deps.as_ref(), local_user.sender.to_string()).unwrap();
+            assert!(res.registrations.is_empty());
+
+            // Compounded at 5%
+            registration.compounded_commissions = vec![ValidatorCommission {
+                validator: validator.clone(),
+                rate: Decimal::percent(5),
+            }];
+            user_chain_registrations().save(deps.as_mut().storage, reg_key.clone(), &registration).unwrap();
+            let res = query_commission_changes(deps.as_ref(), local_user.sender.to_string()).unwrap();
+            assert_eq!(
+                res.registrations,
+                vec![RegistrationCommissionChanges {
+                    chain_id: "chain_id".to_string(),
+                    remote_address: remote_user_addr.clone(),
+                    changes: vec![CommissionChange {
+                        validator: validator.clone(),
+                        last_compound_rate: Decimal::percent(5),
+                        current_rate: Decimal::percent(10),
+                    }],
+                }]
+            );
+
+            // Compounded again since, at the current rate
+            registration.compounded_commissions[0].rate = Decimal::percent(10);
+            user_chain_registrations().save(deps.as_mut().storage, reg_key, &registration).unwrap();
+            let res = query_commission_changes(deps.as_ref(), local_user.sender.to_string()).unwrap();
+            assert!(res.registrations.is_empty());
+        }
+    }
+
+    mod test_query_invariants {
+        use cosmwasm_std::testing::{mock_env, mock_info};
+        use cosmwasm_std::{coins, from_json, Addr, Uint128};
+
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, InvariantsResponse, QueryMsg};
+        use crate::query::query;
+        use crate::state::USER_BALANCES;
+        use crate::testing::helpers::{execute_checked, mock_neutron_dependencies, MockDeps};
+
+        fn invariants(deps: &MockDeps) -> InvariantsResponse {
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::Invariants {}).unwrap()).unwrap()
+        }
+
+        #[test]
+        fn test_query_invariants_violations() {
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &[]);
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                mock_info("local_user", &coins(1000, "untrn")),
+                ExecuteMsg::TopupUserBalance {},
+            )
+            .unwrap();
+            let res = invariants(&deps);
+            assert!(res.violations.is_empty());
+            assert_eq!(res.liabilities, Uint128::new(1000));
+
+            // untrn that left the contract behind the ledger's back
+            deps.querier.set_balance(&mock_env().contract.address, coins(600, "untrn"));
+            let res = invariants(&deps);
+            assert_eq!(res.violations, vec!["bank balance 600 is below the liabilities 1000".to_string()]);
+            assert_eq!(res.reserve, Uint128::zero());
+
+            // A balance moved without its ledger bucket
+            USER_BALANCES.save(deps.as_mut().storage, Addr::unchecked("local_user"), &Uint128::new(400)).unwrap();
+            let res = invariants(&deps);
+            assert_eq!(
+                res.violations,
+                vec![
+                    "bank balance 600 is below the liabilities 1000".to_string(),
+                    "user_balances ledger 1000 does not match the records 400".to_string(),
+                ]
+            );
+        }
+    }
 }
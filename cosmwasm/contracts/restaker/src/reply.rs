@@ -1,40 +1,115 @@
+use crate::helpers::refund_query_deposit;
 use crate::state::{
-    user_chain_registrations, UserChainRegistration, REPLY_ID_TO_USER_CHAIN_REGISTRATION,
+    user_chain_registrations, RegistrationStatus, UserChainRegistration, ICA_TXS, REPLY_ID_TO_ICA_TX,
+    QUERY_ID_TO_USER_CHAIN_REGISTRATION, QUERY_ID_TO_VALIDATOR_QUERY,
+    REPLY_ID_TO_USER_CHAIN_REGISTRATION, REPLY_ID_TO_VALIDATOR_QUERY, VALIDATOR_QUERIES,
 };
-use cosmwasm_std::{entry_point, DepsMut, Env, Reply, Response, StdError, StdResult};
-use neutron_sdk::bindings::msg::MsgRegisterInterchainQueryResponse;
+use cosmwasm_std::{entry_point, DepsMut, Env, Reply, Response, StdError, StdResult, SubMsgResult};
+use neutron_sdk::bindings::msg::{MsgRegisterInterchainQueryResponse, MsgSubmitTxResponse};
+use neutron_sdk::bindings::query::NeutronQuery;
 
 #[entry_point]
-pub fn reply(deps: DepsMut, _: Env, msg: Reply) -> StdResult<Response> {
+pub fn reply(deps: DepsMut<NeutronQuery>, _: Env, msg: Reply) -> StdResult<Response> {
     deps.api
         .debug(format!("WASMDEBUG: reply msg: {:?}", msg).as_str());
 
-    let resp: MsgRegisterInterchainQueryResponse = serde_json_wasm::from_slice(
-        msg.result
-            .into_result()
-            .map_err(StdError::generic_err)?
-            .data
-            .ok_or_else(|| StdError::generic_err("no result"))?
-            .as_slice(),
-    )
-    .map_err(|e| StdError::generic_err(format!("failed to parse response: {:?}", e)))?;
+    // Only successful submissions reply, the packet is found again by its channel and sequence in sudo
+    if let Some(ica_tx) = REPLY_ID_TO_ICA_TX.may_load(deps.storage, msg.id)? {
+        let resp: MsgSubmitTxResponse = serde_json_wasm::from_slice(
+            msg.result
+                .into_result()
+                .map_err(StdError::generic_err)?
+                .data
+                .ok_or_else(|| StdError::generic_err("no result"))?
+                .as_slice(),
+        )
+        .map_err(|e| StdError::generic_err(format!("failed to parse response: {:?}", e)))?;
+        REPLY_ID_TO_ICA_TX.remove(deps.storage, msg.id);
+        ICA_TXS.save(deps.storage, (resp.channel, resp.sequence_id), &ica_tx)?;
+        return Ok(Response::default());
+    }
+
+    // A failed registration is kept with its error, the module took no deposit for it
+    let query_id = match msg.result {
+        SubMsgResult::Ok(result) => {
+            let resp: MsgRegisterInterchainQueryResponse = serde_json_wasm::from_slice(
+                result
+                    .data
+                    .ok_or_else(|| StdError::generic_err("no result"))?
+                    .as_slice(),
+            )
+            .map_err(|e| StdError::generic_err(format!("failed to parse response: {:?}", e)))?;
+            Ok(resp.id)
+        }
+        SubMsgResult::Err(err) => {
+            refund_query_deposit(deps.storage, msg.id)?;
+            Err(err)
+        }
+    };
 
-    let reply_id_to_reg = REPLY_ID_TO_USER_CHAIN_REGISTRATION
-        .may_load(deps.storage, msg.id)
-        .unwrap();
-    if reply_id_to_reg.is_some() {
+    let reply_id_to_reg = REPLY_ID_TO_USER_CHAIN_REGISTRATION.may_load(deps.storage, msg.id)?;
+    if let Some(reg_key) = reply_id_to_reg {
         user_chain_registrations().update(
             deps.storage,
-            reply_id_to_reg.unwrap(),
+            reg_key.clone(),
             |reg_opt| -> Result<UserChainRegistration, StdError> {
-                let mut reg = reg_opt.unwrap();
-                reg.delegator_delegations_icq_id = Some(resp.id);
+                let mut reg = reg_opt.ok_or_else(|| {
+                    StdError::not_found("user chain registration")
+                })?;
+                let query_id = match &query_id {
+                    Ok(query_id) => *query_id,
+                    Err(err) => {
+                        reg.status = RegistrationStatus::Failed {
+                            reason: format!("ICQ registration failed: {}", err),
+                        };
+                        return Ok(reg);
+                    }
+                };
+
+                if reg.delegator_delegations_reply_id == msg.id {
+                    reg.delegator_delegations_icq_id = Some(query_id);
+                } else {
+                    let shard = reg
+                        .icq_shards
+                        .iter_mut()
+                        .find(|s| s.reply_id == msg.id)
+                        .ok_or_else(|| StdError::not_found("icq shard"))?;
+                    shard.icq_id = Some(query_id);
+                }
+                // Every query is registered, the grant is the next thing to wait for
+                if reg.status == RegistrationStatus::PendingQuery
+                    && reg.user_queries().iter().all(|(icq_id, _)| icq_id.is_some())
+                {
+                    reg.status = RegistrationStatus::GrantMissing;
+                }
                 Ok(reg)
             },
         )?;
+        if let Ok(query_id) = query_id {
+            QUERY_ID_TO_USER_CHAIN_REGISTRATION.save(deps.storage, query_id, &reg_key)?;
+        }
         return Ok(Response::default());
     }
 
+    if let Some(validator_key) = REPLY_ID_TO_VALIDATOR_QUERY.may_load(deps.storage, msg.id)? {
+        VALIDATOR_QUERIES.update(
+            deps.storage,
+            validator_key.clone(),
+            |query_opt| -> StdResult<_> {
+                let mut validator_query =
+                    query_opt.ok_or_else(|| StdError::not_found("validator query"))?;
+                match &query_id {
+                    Ok(query_id) => validator_query.icq_id = Some(*query_id),
+                    Err(err) => validator_query.icq_error = Some(err.clone()),
+                }
+                Ok(validator_query)
+            },
+        )?;
+        if let Ok(query_id) = query_id {
+            QUERY_ID_TO_VALIDATOR_QUERY.save(deps.storage, query_id, &validator_key)?;
+        }
+        return Ok(Response::default());
+    }
 
     // If not found by now, we error out
     Err(StdError::generic_err(format!(
@@ -1,13 +1,36 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Coin, Decimal, Timestamp, Uint128};
 use cw_storage_macro::index_list;
-use cw_storage_plus::{IndexedMap, Item, Map, MultiIndex};
+use cw_storage_plus::{IndexedMap, Item, Map, MultiIndex, UniqueIndex};
+
+pub const DEFAULT_MAX_VALIDATORS_PER_REGISTRATION: u64 = 10;
+pub const DEFAULT_MAX_ICQ_STALENESS: u64 = 600; // Local blocks
+pub const DEFAULT_ICQ_UPDATE_PERIOD: u64 = 6; // Remote blocks
+pub const DEFAULT_MAX_KV_QUERY_KEYS: u64 = 32; // MaxKvQueryKeysCount of Neutron's interchainqueries module
+pub const DEFAULT_ICA_TIMEOUT_SECONDS: u64 = 60 * 60 * 24 * 7 * 2; // 2 weeks
+pub const DEFAULT_ICA_MEMO: &str = "InterChadz ruleZ";
+pub const COMPOUND_RETRY_DELAY: u64 = 50; // Local blocks before a failed compound is retried, doubled after each failure in a row
+pub const MAX_COMPOUND_FAILURES: u32 = 5; // Failed compounds in a row before the registration is paused
 
 #[cw_serde]
 pub struct Config {
     pub admin: Addr,
     pub neutron_register_ica_fee: u128, // Always in untrn
     pub autocompound_threshold: u64,    // Always in blocks unit, local chain ones.
+    #[serde(default = "default_max_validators_per_registration")]
+    pub max_validators_per_registration: u64,
+    #[serde(default = "default_max_kv_query_keys")]
+    pub max_kv_query_keys: u64, // Registrations needing more keys are split over several queries
+    #[serde(default)]
+    pub treasury_fee: Decimal, // Share of each autocompound cost kept by the protocol, the keeper gets the rest
+}
+
+fn default_max_validators_per_registration() -> u64 {
+    DEFAULT_MAX_VALIDATORS_PER_REGISTRATION
+}
+
+fn default_max_kv_query_keys() -> u64 {
+    DEFAULT_MAX_KV_QUERY_KEYS
 }
 
 #[cw_serde]
@@ -15,10 +38,101 @@ pub struct Chain {
     pub connection_id: String,
     pub ica_id: String,
     pub ica_port_id: String,
-    pub autocompound_cost: u128,   // Always in untrn
+    pub autocompound_cost: u128,   // Always in untrn, paid to the keeper for each compound. Relayers are paid from ica_tx on top
     pub denom: String,             // The native stake token of the dst chain
     pub ica_address: Option<Addr>, // When this is set, the chain is ready to be used
     pub ica_error: Option<String>, // When this is set, the ica setup has failed
+    #[serde(default)]
+    pub profile: ChainProfile, // Chains stored before profiles existed are Cosmos SDK 0.47 hubs
+    #[serde(default = "default_max_icq_staleness")]
+    pub max_icq_staleness: u64, // Local blocks an ICQ result can be old before we stop compounding on it
+    #[serde(default = "default_icq_update_period")]
+    pub icq_update_period: u64,
+    #[serde(default)]
+    pub ica_tx: IcaTxConfig,
+    #[serde(default)]
+    pub host_fee: Option<HostFeeConfig>, // Registrations can only be billed on the host chain when this is set
+}
+
+fn default_max_icq_staleness() -> u64 {
+    DEFAULT_MAX_ICQ_STALENESS
+}
+
+fn default_icq_update_period() -> u64 {
+    DEFAULT_ICQ_UPDATE_PERIOD
+}
+
+/// Fees, timeout and memo of the ICA transactions sent to a chain
+#[cw_serde]
+pub struct IcaTxConfig {
+    pub ack_fee: u128,     // untrn paid to the relayer of the ack, raised to Neutron's MinIbcFee when lower
+    pub timeout_fee: u128, // untrn paid to the relayer of a timeout, raised to Neutron's MinIbcFee when lower
+    pub timeout_seconds: u64,
+    pub memo: String,
+}
+
+impl Default for IcaTxConfig {
+    fn default() -> Self {
+        IcaTxConfig {
+            ack_fee: 0,
+            timeout_fee: 0,
+            timeout_seconds: DEFAULT_ICA_TIMEOUT_SECONDS,
+            memo: DEFAULT_ICA_MEMO.to_string(),
+        }
+    }
+}
+
+/// Service fee taken from the compounded rewards on the host chain, in the chain's staking denom
+#[cw_serde]
+pub struct HostFeeConfig {
+    pub percentage: Decimal, // Of the claimed rewards
+    pub flat: Uint128,       // Added on top of the percentage
+    pub collector: Option<String>, // Host chain address the fees are sent to, the chain ICA when not set
+    pub sweep_channel: Option<String>, // Transfer channel from the host chain to Neutron, fees held by the ICA are swept over it
+    #[serde(default)]
+    pub sweep_denom: Option<String>, // Denom the swept fees arrive in on Neutron, credited to the treasury on the sweep ack
+    #[serde(default)]
+    pub grant_spend_limit: Option<Uint128>, // Suggested for the SendAuthorization of the host fee, a GenericAuthorization without it
+}
+
+/// How the autocompound cost of a registration is paid
+#[cw_serde]
+#[derive(Default)]
+pub enum BillingMode {
+    #[default]
+    Prepaid, // From the untrn or fee denom balance topped up on Neutron
+    HostChain, // The ICA takes the chain's host_fee out of each compound, nothing is charged on Neutron
+}
+
+/// The Cosmos SDK release family a chain runs. It decides how ICQ keys are built and parsed.
+#[cw_serde]
+#[derive(Copy, Default)]
+pub enum SdkVersion {
+    V045,
+    #[default]
+    V047,
+    V050,
+}
+
+/// Describes the store layout of a remote chain.
+/// Forks that renamed their module store keys set the overrides, everything else follows `sdk_version`.
+#[cw_serde]
+pub struct ChainProfile {
+    pub sdk_version: SdkVersion,
+    pub bech32_prefix: String, // Account prefix, validators use "{bech32_prefix}valoper"
+    pub staking_store_key: Option<String>,
+    pub distribution_store_key: Option<String>,
+}
+
+impl Default for ChainProfile {
+    fn default() -> Self {
+        ChainProfile {
+            sdk_version: SdkVersion::default(),
+            bech32_prefix: "cosmos".to_string(),
+            staking_store_key: None,
+            distribution_store_key: None,
+        }
+    }
 }
 
 #[cw_serde]
@@ -30,6 +144,224 @@ pub struct UserChainRegistration {
     pub delegator_delegations_reply_id: u64, // This is used to set up the ICQ query id (see reply.rs)
     pub delegator_delegations_icq_id: Option<u64>, // This is they ID we use to query the ICQ, if this is set the registration is in progress
     pub next_compound_height: u64, // this is the block when this registration can autocompounded again. height is local, not remote.
+    #[serde(default)]
+    pub status: RegistrationStatus,
+    #[serde(default)]
+    pub grant_expiration: Option<Timestamp>, // Expiration of the MsgDelegate grant to the ICA, as last seen over ICQ
+    #[serde(default)]
+    pub grant_allowance: Option<Coin>, // What is left of a StakeAuthorization max_tokens, None means no spend limit
+    #[serde(default)]
+    pub icq_shards: Vec<IcqShard>, // Validators whose keys did not fit in the primary query
+    #[serde(default)]
+    pub fallback_validator: Option<String>, // Takes the rewards of validators that are jailed or out of the bonded set
+    #[serde(default)]
+    pub max_commission: Option<Decimal>, // Validators above it are skipped like jailed ones
+    #[serde(default)]
+    pub compounded_commissions: Vec<ValidatorCommission>, // Commission of each validator at the last compound
+    #[serde(default)]
+    pub withdraw_address: Option<String>, // Withdraw address of the delegator when it is another account, as last seen over ICQ
+    #[serde(default)]
+    pub billing: BillingMode,
+    #[serde(default)]
+    pub compound_failures: u32, // Compounds with a failed or timed out tx in a row, reset by one whose txs were all acked
+    #[serde(default)]
+    pub in_flight: Option<InFlight>, // Set while compound txs are waiting for their ack, error or timeout
+    #[serde(default)]
+    pub failed_compound: Option<Timestamp>, // In-flight expiry of the last compound counted in compound_failures
+}
+
+/// Compound ICA txs of a registration that are not settled yet, the registration isn't due again meanwhile
+#[cw_serde]
+pub struct InFlight {
+    pub txs: u32,
+    pub expires: Timestamp, // The ICA tx timeout, none of the txs can land on the host chain after it
+}
+
+#[cw_serde]
+pub struct ValidatorCommission {
+    pub validator: String,
+    pub rate: Decimal,
+}
+
+impl UserChainRegistration {
+    /// Validators whose keys are in the primary (delegator_delegations) query
+    pub fn primary_validators(&self) -> Vec<String> {
+        self.validators
+            .iter()
+            .filter(|v| !self.icq_shards.iter().any(|s| s.validators.contains(v)))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether compound txs sent before `now` may still land on the host chain
+    pub fn is_in_flight(&self, now: Timestamp) -> bool {
+        self.in_flight.as_ref().is_some_and(|in_flight| in_flight.expires > now)
+    }
+
+    /// Settles one of the compound txs sent under the lock expiring at `expires`. Returns false while other txs of
+    /// the same compound still hold the lock
+    pub fn release_in_flight(&mut self, expires: Option<Timestamp>) -> bool {
+        // An expired lock may have been taken again since, it is only released by the txs of the new compound
+        let Some(in_flight) = self.in_flight.as_mut().filter(|in_flight| Some(in_flight.expires) == expires) else {
+            return true;
+        };
+        in_flight.txs = in_flight.txs.saturating_sub(1);
+        if in_flight.txs == 0 {
+            self.in_flight = None;
+        }
+        self.in_flight.is_none()
+    }
+
+    /// Icq id and validators of every query holding keys of this registration, the primary one first
+    pub fn user_queries(&self) -> Vec<(Option<u64>, Vec<String>)> {
+        std::iter::once((self.delegator_delegations_icq_id, self.primary_validators()))
+            .chain(self.icq_shards.iter().map(|s| (s.icq_id, s.validators.clone())))
+            .collect()
+    }
+}
+
+/// An extra query of a registration, holding the delegator keys of some of its validators
+#[cw_serde]
+pub struct IcqShard {
+    pub validators: Vec<String>,
+    pub reply_id: u64,
+    pub icq_id: Option<u64>, // Set in the register query reply
+}
+
+/// Validator record and current rewards of one validator, registered once and shared by every registration using it
+#[cw_serde]
+pub struct ValidatorQuery {
+    pub reply_id: u64,
+    pub icq_id: Option<u64>, // Set in the register query reply
+    pub ref_count: u64,      // Registrations delegating to this validator, the query is removed at 0
+    #[serde(default)]
+    pub icq_error: Option<String>, // Set when registering the query failed, the next registration retries it
+}
+
+/// The interchainqueries module deposit paid for a query, credited back to the payer when the query is removed
+#[cw_serde]
+pub struct QueryDeposit {
+    pub payer: Addr,
+    pub amount: Uint128, // Always in untrn
+}
+
+/// The remote height and key set an ICQ result was taken at, recorded in sudo_kv_query_result.
+/// The key set changes after each result, rewards are only computed from a result that holds every required key.
+#[cw_serde]
+pub struct IcqResultCoverage {
+    pub remote_height: u64, // The lowest height of the registration's own query results
+    pub historical_periods: Vec<(String, u64)>, // (validator, period) of the historical rewards in the result
+    pub missing_keys: u64,
+    #[serde(default)]
+    pub undelegated_validators: Vec<String>, // Listed validators whose delegation came back empty
+    #[serde(default)]
+    pub mixed_heights: bool, // A validator query result is more than an ICQ update period away from its delegation
+}
+
+impl IcqResultCoverage {
+    pub fn is_complete(&self) -> bool {
+        self.missing_keys == 0 && !self.mixed_heights
+    }
+}
+
+/// Untrn the contract holds or is owed, in buckets kept up to date by every movement. See the Invariants query
+#[cw_serde]
+#[derive(Default)]
+pub struct Ledger {
+    pub user_balances: Uint128, // Topped up by the users and not spent yet
+    pub escrowed_fees: Uint128, // Charged for compounds and not paid out yet
+    pub icq_deposits: Uint128,  // Held by the interchainqueries module, credited back to the payers
+    pub treasury: Uint128,
+    pub relayer_escrow: Uint128, // Held by the feerefunder module until the ICA tx is acknowledged or times out
+}
+
+impl Ledger {
+    /// What the contract owes from its own untrn balance
+    pub fn liabilities(&self) -> Uint128 {
+        self.user_balances + self.escrowed_fees + self.treasury
+    }
+}
+
+/// What an ICA tx sent by the contract does
+#[cw_serde]
+pub enum IcaTxKind {
+    Compound {
+        local_address: Addr,
+        chain_id: String,
+        remote_address: String,
+        #[serde(default)]
+        escrowed_fee: Option<EscrowedFee>, // None for host-billed compounds
+        #[serde(default)]
+        in_flight_expires: Option<Timestamp>, // Expiry of the in-flight lock the compound took
+        #[serde(default)]
+        host_fee: Option<Uint128>, // Sent to the ICA, added to HOST_FEES on the ack
+    },
+    Sweep {
+        chain_id: String,
+        amount: Uint128,
+        #[serde(default)]
+        treasury_denom: Option<String>, // The sweep_denom of the chain when it was sent
+    },
+}
+
+/// An autocompound cost charged from the user, held until the compound is acknowledged and refunded if it fails
+#[cw_serde]
+pub struct EscrowedFee {
+    pub fee: Coin,
+    pub keeper: Addr,          // Gets the fee minus the treasury share on the ack
+    pub treasury_fee: Uint128, // In the fee denom
+}
+
+/// An ICA tx waiting for its ack or timeout
+#[cw_serde]
+pub struct IcaTx {
+    pub kind: IcaTxKind,
+    pub ack_fee: Uint128,     // untrn escrowed for the relayer of the ack, refunded on a timeout
+    pub timeout_fee: Uint128, // untrn escrowed for the relayer of a timeout, refunded on an ack
+}
+
+/// Where a registration is in its lifecycle, only Active ones are autocompounded.
+#[cw_serde]
+#[derive(Default)]
+pub enum RegistrationStatus {
+    PendingQuery, // Waiting for the replies of its ICQ registrations
+    #[default]
+    Active,
+    Paused { reason: PauseReason },
+    GrantMissing, // No MsgDelegate grant from the remote address to the chain ICA
+    Failed { reason: String }, // One of its ICQs could not be registered
+}
+
+impl RegistrationStatus {
+    pub fn kind(&self) -> RegistrationStatusKind {
+        match self {
+            RegistrationStatus::PendingQuery => RegistrationStatusKind::PendingQuery,
+            RegistrationStatus::Active => RegistrationStatusKind::Active,
+            RegistrationStatus::Paused { .. } => RegistrationStatusKind::Paused,
+            RegistrationStatus::GrantMissing => RegistrationStatusKind::GrantMissing,
+            RegistrationStatus::Failed { .. } => RegistrationStatusKind::Failed,
+        }
+    }
+}
+
+#[cw_serde]
+pub enum PauseReason {
+    NoDelegations, // The delegator left every listed validator, resumes once a delegation shows up again
+    GrantExpired,
+    GrantExhausted, // The StakeAuthorization allowance can't cover the pending rewards
+    ForeignWithdrawAddress, // Rewards are withdrawn to another account, a compound would delegate the delegator's own funds
+    CompoundFailures, // MAX_COMPOUND_FAILURES compounds in a row failed on the host chain, resumed by UpdateRegistration
+}
+
+/// A RegistrationStatus without its reason, to filter registrations on
+#[cw_serde]
+#[derive(Copy)]
+pub enum RegistrationStatusKind {
+    PendingQuery,
+    Active,
+    Paused,
+    GrantMissing,
+    Failed,
 }
 
 pub const CONFIG: Item<Config> = Item::new("config");
@@ -42,6 +374,18 @@ pub const ICA_PORT_ID_TO_CHAIN_ID: Map<String, String> = Map::new("ica_port_id_t
 pub const NEXT_REPLY_ID: Item<u64> = Item::new("next_reply_id");
 pub const REPLY_ID_TO_USER_CHAIN_REGISTRATION: Map<u64, (Addr, String, String)> =
     Map::new("reply_id_to_user_chain_registration");
+pub const QUERY_ID_TO_USER_CHAIN_REGISTRATION: Map<u64, (Addr, String, String)> =
+    Map::new("query_id_to_user_chain_registration");
+// (chain_id, validator) -> the query shared by every registration delegating to it
+pub const VALIDATOR_QUERIES: Map<(String, String), ValidatorQuery> = Map::new("validator_queries");
+pub const REPLY_ID_TO_VALIDATOR_QUERY: Map<u64, (String, String)> =
+    Map::new("reply_id_to_validator_query");
+pub const QUERY_ID_TO_VALIDATOR_QUERY: Map<u64, (String, String)> =
+    Map::new("query_id_to_validator_query");
+// register query reply id -> deposit paid for it
+pub const QUERY_DEPOSITS: Map<u64, QueryDeposit> = Map::new("query_deposits");
+// query id -> what its last result covered
+pub const ICQ_RESULT_COVERAGE: Map<u64, IcqResultCoverage> = Map::new("icq_result_coverage");
 
 // Autocompound and delegate msgs state
 pub const REPLY_ID_STORAGE: Item<Vec<u8>> = Item::new("reply_queue_id");
@@ -51,12 +395,28 @@ pub const REPLY_ID_TO_USER_DELEGATE: Map<u64, (Addr, String, String)> =
 
 // user_address -> balance
 pub const USER_BALANCES: Map<Addr, Uint128> = Map::new("user_balances"); // Always in untrn
+// (user_address, denom) -> balance, only whitelisted fee denoms other than untrn
+pub const USER_FEE_BALANCES: Map<(Addr, String), Uint128> = Map::new("user_fee_balances");
+// denom -> untrn one unit of it is worth, set by the admin
+pub const FEE_DENOMS: Map<String, Decimal> = Map::new("fee_denoms");
+pub const LEDGER: Item<Ledger> = Item::new("ledger");
+// submit tx reply id -> the ICA tx, until the reply tells its channel and sequence
+pub const REPLY_ID_TO_ICA_TX: Map<u64, IcaTx> = Map::new("reply_id_to_ica_tx");
+// (channel, sequence) -> the ICA tx the packet belongs to
+pub const ICA_TXS: Map<(String, u64), IcaTx> = Map::new("ica_txs");
+
+// denom -> protocol fees kept from autocompound costs, only withdrawn by the admin
+pub const TREASURY: Map<String, Uint128> = Map::new("treasury");
+// chain_id -> host fees of acknowledged compounds held by the chain ICA and not swept to Neutron yet, in the chain's staking denom
+pub const HOST_FEES: Map<String, Uint128> = Map::new("host_fees");
 
 // (Addr, String, String) is local_address, chain_id, remote_address
 #[index_list(UserChainRegistration)]
 pub struct UserChainRegistrationIndexes<'a> {
     pub local_address: MultiIndex<'a, Addr, UserChainRegistration, (Addr, String, String)>,
     pub next_compound_height: MultiIndex<'a, u64, UserChainRegistration, (Addr, String, String)>,
+    // (chain_id, remote_address), a remote delegator can only be registered by one local user
+    pub remote_address: UniqueIndex<'a, (String, String), UserChainRegistration, (Addr, String, String)>,
 }
 
 pub fn user_chain_registrations<'a>(
@@ -69,10 +429,14 @@ pub fn user_chain_registrations<'a>(
             "user_chain_registrations__local_address",
         ),
         next_compound_height: MultiIndex::new(
-            |_pk: &[u8], u: &UserChainRegistration| u.next_compound_height.clone(),
+            |_pk: &[u8], u: &UserChainRegistration| u.next_compound_height,
             "user_chain_registrations",
             "user_chain_registrations__next_compound_height",
         ),
+        remote_address: UniqueIndex::new(
+            |u: &UserChainRegistration| (u.chain_id.clone(), u.remote_address.clone()),
+            "user_chain_registrations__remote_address",
+        ),
     };
 
     IndexedMap::new("user_chain_registrations", indexes)
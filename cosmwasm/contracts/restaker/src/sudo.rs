@@ -1,14 +1,18 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, DepsMut, entry_point, Env, Response, StdError, StdResult};
+use cosmwasm_std::{Addr, DepsMut, entry_point, Env, Response, StdError, StdResult, Storage, Uint128};
 use neutron_sdk::bindings::msg::NeutronMsg;
 use neutron_sdk::bindings::query::NeutronQuery;
-use neutron_sdk::interchain_queries::{check_query_type, get_registered_query, query_kv_result};
+use neutron_sdk::interchain_queries::{check_query_type, get_registered_query};
 use neutron_sdk::interchain_queries::types::QueryType;
 use neutron_sdk::sudo::msg::{RequestPacket, SudoMsg};
 
-use crate::icq::keys::{create_all_icq_keys_for_user, ValidatorHistoricalRange};
-use crate::icq::reconstruct::UserQueryData;
-use crate::state::{ICA_PORT_ID_TO_CHAIN_ID, SUPPORTED_CHAINS};
+use crate::helpers::{
+    charge_query_deposits, contract_err, evaluate_delegate_grant, neutron_err, query_icq_deposit, refund_escrowed_fee,
+    release_ica_tx, required_msg_type_urls, settle_escrowed_fee, update_ledger, update_registration_queries,
+};
+use crate::icq::keys::create_all_icq_keys_for_user;
+use crate::icq::reconstruct::query_covered_user_query_data;
+use crate::state::{user_chain_registrations, IcaTx, IcaTxKind, IcqResultCoverage, PauseReason, RegistrationStatus, UserChainRegistration, CONFIG, HOST_FEES, ICA_PORT_ID_TO_CHAIN_ID, ICQ_RESULT_COVERAGE, COMPOUND_RETRY_DELAY, MAX_COMPOUND_FAILURES, NEXT_REPLY_ID, QUERY_ID_TO_USER_CHAIN_REGISTRATION, QUERY_ID_TO_VALIDATOR_QUERY, SUPPORTED_CHAINS, TREASURY, USER_BALANCES, VALIDATOR_QUERIES};
 
 /// SudoPayload is a type that stores information about a transaction that we try to execute
 /// on the host chain. This is a type introduced for our convenience.
@@ -28,7 +32,7 @@ struct OpenAckVersion {
     tx_type: String,
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
+#[entry_point]
 pub fn sudo(deps: DepsMut<NeutronQuery>, env: Env, msg: SudoMsg) -> StdResult<Response<NeutronMsg>> {
     match msg {
         SudoMsg::OpenAck {
@@ -44,8 +48,10 @@ pub fn sudo(deps: DepsMut<NeutronQuery>, env: Env, msg: SudoMsg) -> StdResult<Re
             counterparty_channel_id,
             counterparty_version,
         ),
-        SudoMsg::KVQueryResult { query_id } => sudo_kv_query_result(deps, query_id),
-        SudoMsg::Error { request, details } => sudo_error(deps, request, details),
+        SudoMsg::KVQueryResult { query_id } => sudo_kv_query_result(deps, env, query_id),
+        SudoMsg::Response { request, .. } => sudo_response(deps, env, request),
+        SudoMsg::Error { request, details } => sudo_error(deps, env, request, details),
+        SudoMsg::Timeout { request } => sudo_timeout(deps, env, request),
         _ => Ok(Response::default()),
     }
 }
@@ -76,7 +82,7 @@ fn sudo_open_ack(
             deps.storage,
             chain_id.clone(),
             |existing_chain| -> StdResult<_> {
-                let mut chain = existing_chain.unwrap();
+                let mut chain = existing_chain.ok_or_else(|| StdError::not_found("chain"))?;
                 let address = Addr::unchecked(parsed_version.clone().address);
                 chain.ica_address = Option::from(address);
                 Ok(chain)
@@ -92,14 +98,37 @@ fn sudo_open_ack(
     Err(StdError::generic_err("Can't parse counterparty_version"))
 }
 
-fn sudo_kv_query_result(deps: DepsMut<NeutronQuery>, query_id: u64) -> StdResult<Response<NeutronMsg>> {
+fn sudo_kv_query_result(deps: DepsMut<NeutronQuery>, env: Env, query_id: u64) -> StdResult<Response<NeutronMsg>> {
     deps.api
         .debug(format!("WASMDEBUG: sudo_kv_query_result, query_id: {:?}", query_id).as_str());
 
-    let resp = get_registered_query(deps.as_ref(), query_id).unwrap();
-    check_query_type(resp.registered_query.query_type, QueryType::KV).unwrap();
+    let resp = get_registered_query(deps.as_ref(), query_id).map_err(neutron_err)?;
+    check_query_type(resp.registered_query.query_type, QueryType::KV).map_err(neutron_err)?;
 
-    let user_query_data: UserQueryData = query_kv_result(deps.as_ref(), query_id).unwrap();
+    // Shared validator queries have a fixed key set, the registrations read their results when computing rewards
+    if QUERY_ID_TO_VALIDATOR_QUERY.has(deps.storage, query_id) {
+        return Ok(Response::new()
+            .add_attribute("action", "sudo_kv_query_result")
+            .add_attribute("validator_query", query_id.to_string()));
+    }
+
+    let reg_key = QUERY_ID_TO_USER_CHAIN_REGISTRATION.load(deps.storage, query_id)?;
+    let mut registration = user_chain_registrations().load(deps.storage, reg_key.clone())?;
+    let chain = SUPPORTED_CHAINS.load(deps.storage, registration.chain_id.clone())?;
+
+    // The query is either the primary one of the registration or one of its shards
+    let (shard_validators, primary_query) = if registration.delegator_delegations_icq_id == Some(query_id) {
+        (registration.primary_validators(), true)
+    } else {
+        let shard = registration
+            .icq_shards
+            .iter()
+            .find(|s| s.icq_id == Some(query_id))
+            .ok_or_else(|| StdError::not_found("icq shard"))?;
+        (shard.validators.clone(), false)
+    };
+
+    let (user_query_data, coverage) = query_covered_user_query_data(deps.as_ref(), &chain.profile, &registration, chain.icq_update_period).map_err(neutron_err)?;
     deps.api
         .debug(format!("WASMDEBUG: user_query_data, delegation len: {}, val len {}, starting_infos len {} historical_rewards len {}",
                        user_query_data.delegations.len(),
@@ -108,23 +137,300 @@ fn sudo_kv_query_result(deps: DepsMut<NeutronQuery>, query_id: u64) -> StdResult
                        user_query_data.validator_historical_rewards.len()
         ).as_str());
 
-    let delegation = user_query_data.delegations.get(0).unwrap();
-    let validators = user_query_data.validators.into_iter().map(|v| v.operator_address).collect::<Vec<_>>();
-    let validator_historical_range = user_query_data.delegator_starting_infos.into_iter()
-        .map(|v| {
-            ValidatorHistoricalRange {
-                validator: v.validator,
-                period: v.previous_period,
+    let ica_address = chain.ica_address.as_ref().map(|a| a.to_string());
+    let grant_msg_type_urls = required_msg_type_urls(&registration.billing, registration.fallback_validator.as_ref());
+    let (status, grant) = evaluate_delegate_grant(
+        &user_query_data.grants,
+        &registration.remote_address,
+        ica_address.as_deref(),
+        &grant_msg_type_urls,
+        env.block.time,
+    );
+    // With no delegation left there is nothing to compound, the validators are kept for when the user comes back
+    let no_delegations_left = !coverage.undelegated_validators.is_empty()
+        && registration.validators.iter().all(|v| coverage.undelegated_validators.contains(v));
+    // Delegating changes withdraws the pending rewards to the withdraw address, the compound would take the delegator's own funds
+    let foreign_withdraw_address = user_query_data
+        .withdraw_address
+        .clone()
+        .filter(|address| address != &registration.remote_address);
+    let status = if no_delegations_left && status == RegistrationStatus::Active {
+        RegistrationStatus::Paused { reason: PauseReason::NoDelegations }
+    } else if foreign_withdraw_address.is_some() && status == RegistrationStatus::Active {
+        RegistrationStatus::Paused { reason: PauseReason::ForeignWithdrawAddress }
+    } else {
+        status
+    };
+    let grant_allowance = grant.and_then(|g| g.max_tokens.clone());
+    // An exhausted allowance stays paused until the user grants a new one
+    let still_exhausted = registration.status == RegistrationStatus::Paused { reason: PauseReason::GrantExhausted }
+        && status == RegistrationStatus::Active
+        && grant_allowance.is_some()
+        && grant_allowance == registration.grant_allowance;
+    // Repeated compound failures are only resumed by the user, see update_registration
+    let failing = registration.status == RegistrationStatus::Paused { reason: PauseReason::CompoundFailures };
+    // A shared validator query that failed to register leaves the rewards of this registration incomplete
+    let failed_validator_query = registration.validators.iter().find_map(|validator| {
+        VALIDATOR_QUERIES
+            .may_load(deps.storage, (registration.chain_id.clone(), validator.clone()))
+            .ok()
+            .flatten()
+            .and_then(|q| q.icq_error)
+            .map(|error| format!("validator query for {} failed: {}", validator, error))
+    });
+    if let Some(reason) = failed_validator_query {
+        registration.status = RegistrationStatus::Failed { reason };
+    } else if !still_exhausted && !failing && !matches!(registration.status, RegistrationStatus::Failed { .. }) {
+        registration.status = status;
+    }
+    registration.grant_expiration = grant.and_then(|g| g.expiration);
+    registration.grant_allowance = grant_allowance;
+    registration.withdraw_address = foreign_withdraw_address;
+
+    // Validators the user redelegated away from or unbonded are dropped from the registration and its queries
+    if !coverage.undelegated_validators.is_empty() && !no_delegations_left {
+        let delegated_validators = registration
+            .validators
+            .iter()
+            .filter(|v| !coverage.undelegated_validators.contains(v))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // The queries of a failed registration that could not be registered are registered again, on the
+        // owner's deposits. Without the balance for them it stays failed and the validators are dropped later.
+        let mut unregistered_queries = registration.user_queries().iter().filter(|(icq_id, _)| icq_id.is_none()).count();
+        if matches!(registration.status, RegistrationStatus::Failed { .. }) {
+            for validator in &delegated_validators {
+                let key = (registration.chain_id.clone(), validator.clone());
+                if VALIDATOR_QUERIES.may_load(deps.storage, key)?.is_some_and(|q| q.icq_error.is_some()) {
+                    unregistered_queries += 1;
+                }
             }
-        }).collect::<Vec<_>>();
-    let icq_keys = create_all_icq_keys_for_user(delegation.clone().delegator_address, validators, Some(validator_historical_range)).unwrap();
-    let icq_msg = NeutronMsg::update_interchain_query(query_id, Some(icq_keys), Some(6), None).unwrap();
+        }
+        let deposit = query_icq_deposit(deps.as_ref()).map_err(contract_err)?;
+        let required = deposit * Uint128::from(unregistered_queries as u64);
+        let balance = USER_BALANCES.may_load(deps.storage, registration.local_address.clone())?.unwrap_or_default();
+        if balance < required {
+            registration.status = RegistrationStatus::Failed {
+                reason: format!(
+                    "{} untrn balance can't cover the {} untrn deposits of its queries to register again",
+                    balance, required
+                ),
+            };
+            user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+            save_coverage(deps.storage, &registration, &coverage)?;
+
+            return Ok(Response::new()
+                .add_attribute("action", "sudo_kv_query_result")
+                .add_attribute("status", format!("{:?}", registration.status))
+                .add_attribute("remote_height", coverage.remote_height.to_string())
+                .add_attribute("missing_keys", coverage.missing_keys.to_string()));
+        }
+
+        let config = CONFIG.load(deps.storage)?;
+        let mut next_reply_id = NEXT_REPLY_ID.load(deps.storage)?;
+        let (msgs, new_query_reply_ids) = update_registration_queries(
+            deps.storage,
+            &chain,
+            &mut registration,
+            delegated_validators,
+            &user_query_data.historical_ranges(),
+            config.max_kv_query_keys,
+            &mut next_reply_id,
+        )
+        .map_err(contract_err)?;
+        NEXT_REPLY_ID.save(deps.storage, &next_reply_id)?;
+        if !new_query_reply_ids.is_empty() {
+            charge_query_deposits(deps.storage, &registration.local_address, &new_query_reply_ids, deposit)
+                .map_err(contract_err)?;
+        }
+        user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+        // The result still holds every key the remaining validators need
+        save_coverage(deps.storage, &registration, &coverage)?;
+
+        return Ok(Response::new()
+            .add_submessages(msgs)
+            .add_attribute("action", "sudo_kv_query_result")
+            .add_attribute("dropped_validators", coverage.undelegated_validators.join(","))
+            .add_attribute("status", format!("{:?}", registration.status))
+            .add_attribute("remote_height", coverage.remote_height.to_string())
+            .add_attribute("missing_keys", coverage.missing_keys.to_string()));
+    }
+    user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+    save_coverage(deps.storage, &registration, &coverage)?;
+
+    // Next result has to cover the starting periods seen in this one
+    let historical_ranges = user_query_data
+        .historical_ranges()
+        .into_iter()
+        .filter(|r| shard_validators.contains(&r.validator))
+        .collect();
+    let grantee = if primary_query { ica_address } else { None };
+    let icq_keys = create_all_icq_keys_for_user(&chain.profile, registration.remote_address, shard_validators, Some(historical_ranges), grantee, &grant_msg_type_urls, primary_query).map_err(neutron_err)?;
+
+    // Updating costs gas on every relayer submission, only do it when the periods (or the grantee) moved
+    let mut response = Response::new();
+    let keys_changed = icq_keys != resp.registered_query.keys;
+    if keys_changed || resp.registered_query.update_period != chain.icq_update_period {
+        let icq_msg = NeutronMsg::update_interchain_query(query_id, Some(icq_keys), Some(chain.icq_update_period), None).map_err(neutron_err)?;
+        response = response.add_message(icq_msg);
+    }
+
+    Ok(response
+        .add_attribute("action", "sudo_kv_query_result")
+        .add_attribute("keys_updated", keys_changed.to_string())
+        .add_attribute("status", format!("{:?}", registration.status))
+        .add_attribute("remote_height", coverage.remote_height.to_string())
+        .add_attribute("missing_keys", coverage.missing_keys.to_string()))
+}
+
+// Coverage is of the whole registration, autocompound looks it up by the primary query
+fn save_coverage(
+    storage: &mut dyn Storage,
+    registration: &UserChainRegistration,
+    coverage: &IcqResultCoverage,
+) -> StdResult<()> {
+    if let Some(primary_icq_id) = registration.delegator_delegations_icq_id {
+        ICQ_RESULT_COVERAGE.save(storage, primary_icq_id, coverage)?;
+    }
+    Ok(())
+}
+
+fn sudo_response(deps: DepsMut<NeutronQuery>, env: Env, request: RequestPacket) -> StdResult<Response<NeutronMsg>> {
+    match release_ica_tx(deps.storage, &request)? {
+        Some(ica_tx) => settle_ica_tx(deps, &env, ica_tx, None),
+        None => Ok(Response::default()),
+    }
+}
 
-    Ok(Response::new().add_message(icq_msg).add_attribute("action", "sudo_kv_query_result"))
+fn sudo_timeout(deps: DepsMut<NeutronQuery>, env: Env, request: RequestPacket) -> StdResult<Response<NeutronMsg>> {
+    match release_ica_tx(deps.storage, &request)? {
+        Some(ica_tx) => settle_ica_tx(deps, &env, ica_tx, Some("timeout")),
+        None => Ok(Response::default()),
+    }
+}
+
+/// Pays out the fee of an acknowledged compound. A compound that failed or timed out with `failure` gets its fee
+/// credited back and is retried later, until MAX_COMPOUND_FAILURES in a row pause the registration
+fn settle_ica_tx(
+    deps: DepsMut<NeutronQuery>,
+    env: &Env,
+    ica_tx: IcaTx,
+    failure: Option<&str>,
+) -> StdResult<Response<NeutronMsg>> {
+    let (local_address, chain_id, remote_address, escrowed_fee, in_flight_expires, host_fee) = match ica_tx.kind {
+        IcaTxKind::Compound { local_address, chain_id, remote_address, escrowed_fee, in_flight_expires, host_fee } => {
+            (local_address, chain_id, remote_address, escrowed_fee, in_flight_expires, host_fee)
+        }
+        IcaTxKind::Sweep { chain_id, amount, treasury_denom } => {
+            return settle_sweep(deps.storage, chain_id, amount, treasury_denom, failure)
+        }
+    };
+    let mut response = Response::new()
+        .add_attribute("action", "settle_compound")
+        .add_attribute("local_address", local_address.to_string())
+        .add_attribute("chain_id", chain_id.clone())
+        .add_attribute("remote_address", remote_address.clone());
+    let reg_key = (local_address.clone(), chain_id, remote_address);
+    // The registration may be gone by now, the fee is settled all the same
+    let loaded = user_chain_registrations().may_load(deps.storage, reg_key.clone())?;
+    let mut registration = loaded.clone();
+    let compound_settled = registration
+        .as_mut()
+        .map(|registration| registration.release_in_flight(in_flight_expires))
+        .unwrap_or(true);
+    // Compounds count as one failure however many of their txs fail. Txs sent before the locks have no compound
+    // to tell them apart and count one by one
+    let failure_counted = |registration: &UserChainRegistration| {
+        in_flight_expires.is_some() && registration.failed_compound == in_flight_expires
+    };
+
+    let Some(failure) = failure else {
+        if let Some(host_fee) = host_fee {
+            HOST_FEES.update(deps.storage, reg_key.1.clone(), |fees| -> StdResult<_> {
+                Ok(fees.unwrap_or_default() + host_fee)
+            })?;
+            response = response.add_attribute("host_fee", host_fee.to_string());
+        }
+        if let Some(escrowed_fee) = escrowed_fee {
+            if let Some(msg) = settle_escrowed_fee(deps.storage, &escrowed_fee)? {
+                response = response.add_message(msg);
+            }
+            response = response.add_attribute(
+                "treasury_fee",
+                format!("{}{}", escrowed_fee.treasury_fee, escrowed_fee.fee.denom),
+            );
+        }
+        // The run of failures only ends once every tx of the compound landed
+        if let Some(registration) = registration.as_mut().filter(|r| compound_settled && !failure_counted(r)) {
+            registration.compound_failures = 0;
+        }
+        if let Some(registration) = registration.filter(|r| Some(r) != loaded.as_ref()) {
+            user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+        }
+        return Ok(response);
+    };
+
+    if let Some(escrowed_fee) = escrowed_fee {
+        refund_escrowed_fee(deps.storage, &local_address, &escrowed_fee)?;
+        response = response.add_attribute("refunded_fee", escrowed_fee.fee.to_string());
+    }
+    if let Some(mut registration) = registration {
+        if !failure_counted(&registration) {
+            registration.compound_failures += 1;
+            registration.failed_compound = in_flight_expires;
+            if registration.compound_failures >= MAX_COMPOUND_FAILURES {
+                registration.status = RegistrationStatus::Paused { reason: PauseReason::CompoundFailures };
+            } else {
+                // Back on the due queue, a bit later after each failure in a row
+                registration.next_compound_height =
+                    env.block.height + COMPOUND_RETRY_DELAY * 2u64.pow(registration.compound_failures - 1);
+            }
+        }
+        response = response.add_attribute("compound_failures", registration.compound_failures.to_string());
+        user_chain_registrations().save(deps.storage, reg_key, &registration)?;
+    }
+
+    Ok(response.add_attribute("failure", failure))
+}
+
+/// Credits the swept host fees to the treasury once they reached Neutron, a failed or timed out sweep leaves them
+/// on the ICA to be swept again
+fn settle_sweep(
+    storage: &mut dyn Storage,
+    chain_id: String,
+    amount: Uint128,
+    treasury_denom: Option<String>,
+    failure: Option<&str>,
+) -> StdResult<Response<NeutronMsg>> {
+    let response = Response::new()
+        .add_attribute("action", "settle_sweep")
+        .add_attribute("chain_id", chain_id.clone())
+        .add_attribute("amount", amount.to_string());
+    if let Some(failure) = failure {
+        HOST_FEES.update(storage, chain_id, |fees| -> StdResult<_> { Ok(fees.unwrap_or_default() + amount) })?;
+        return Ok(response.add_attribute("failure", failure));
+    }
+
+    // Sweeps sent before the denom was recorded can't be told apart from the rest of the contract balance
+    let Some(denom) = treasury_denom else {
+        return Ok(response);
+    };
+    if denom == "untrn" {
+        update_ledger(storage, |ledger| {
+            ledger.treasury = ledger.treasury.checked_add(amount)?;
+            Ok(())
+        })?;
+    }
+    TREASURY.update(storage, denom.clone(), |balance| -> StdResult<_> {
+        Ok(balance.unwrap_or_default() + amount)
+    })?;
+    Ok(response.add_attribute("treasury_denom", denom))
 }
 
 fn sudo_error(
     deps: DepsMut<NeutronQuery>,
+    env: Env,
     request: RequestPacket,
     details: String,
 ) -> StdResult<Response<NeutronMsg>> {
@@ -133,13 +439,21 @@ fn sudo_error(
     deps.api
         .debug(format!("WASMDEBUG: request packet: {:?}", request).as_str());
 
-    let chain_id = ICA_PORT_ID_TO_CHAIN_ID.load(deps.storage, request.source_port.unwrap())?;
+    // A compound or sweep failed on the host chain, the ICA itself is fine
+    if let Some(ica_tx) = release_ica_tx(deps.storage, &request)? {
+        return settle_ica_tx(deps, &env, ica_tx, Some(&details));
+    }
+
+    let source_port = request
+        .source_port
+        .ok_or_else(|| StdError::generic_err("request packet without source port"))?;
+    let chain_id = ICA_PORT_ID_TO_CHAIN_ID.load(deps.storage, source_port)?;
 
     SUPPORTED_CHAINS.update(
         deps.storage,
         chain_id.clone(),
         |existing_chain| -> StdResult<_> {
-            let mut chain = existing_chain.unwrap();
+            let mut chain = existing_chain.ok_or_else(|| StdError::not_found("chain"))?;
             chain.ica_error = Option::from(details);
             Ok(chain)
         },
@@ -155,7 +469,7 @@ mod tests {
         use cosmwasm_std::testing::{mock_env, mock_info};
         use neutron_sdk::sudo::msg::SudoMsg;
 
-        use crate::execute::execute;
+        use crate::testing::helpers::execute_checked;
         use crate::instantiate::instantiate;
         use crate::msg::{ExecuteMsg, InstantiateMsg};
         use crate::state::SUPPORTED_CHAINS;
@@ -175,6 +489,9 @@ mod tests {
                     admin: info.sender.to_string(),
                     neutron_register_ica_fee: 1000000,
                     autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
                 },
             )
                 .unwrap();
@@ -183,8 +500,13 @@ mod tests {
                 connection_id: "connection_id".to_string(),
                 denom: "denom".to_string(),
                 autocompound_cost: 100000,
+                profile: None,
+                max_icq_staleness: None,
+                icq_update_period: None,
+                ica_tx: None,
+                host_fee: None,
             };
-            execute(deps.as_mut(), mock_env(), info.clone(), add_chain_msg).unwrap();
+            execute_checked(&mut deps, mock_env(), info.clone(), add_chain_msg).unwrap();
             let chain = SUPPORTED_CHAINS
                 .load(deps.as_ref().storage, "chain_id".to_string())
                 .unwrap();
@@ -204,4 +526,796 @@ mod tests {
             assert_eq!(chain.ica_address.unwrap(), Addr::unchecked("icaaddress"));
         }
     }
+
+    mod test_sudo_kv_query_result {
+        use cosmos_sdk_proto::cosmos::authz::v1beta1::Grant;
+        use cosmos_sdk_proto::traits::Message;
+        use cosmos_sdk_proto::cosmos::staking::v1beta1::StakeAuthorization;
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+        use cosmwasm_std::{coin, coins, Addr, Binary, OwnedDeps, Timestamp, Uint128};
+        use neutron_sdk::bindings::query::NeutronQuery;
+        use neutron_sdk::bindings::types::{InterchainQueryResult, RegisteredQuery, StorageValue};
+        use neutron_sdk::interchain_queries::helpers::decode_and_convert;
+        use neutron_sdk::interchain_queries::types::QueryType;
+        use neutron_sdk::sudo::msg::SudoMsg;
+
+        use crate::testing::helpers::execute_checked;
+        use crate::helpers::MSG_DELEGATE_TYPE_URL;
+        use crate::icq::keys::{create_all_icq_keys_for_user, create_delegator_withdraw_address_query_key, StoreLayout};
+        use crate::instantiate::instantiate;
+        use crate::msg::{ExecuteMsg, InstantiateMsg, UserChainRegistrationInput};
+        use crate::state::{
+            user_chain_registrations, ChainProfile, DEFAULT_ICQ_UPDATE_PERIOD, PauseReason, RegistrationStatus, ICQ_RESULT_COVERAGE,
+            QUERY_ID_TO_USER_CHAIN_REGISTRATION, SUPPORTED_CHAINS, USER_BALANCES, VALIDATOR_QUERIES,
+        };
+        use crate::sudo::sudo;
+        use crate::reply::reply;
+        use crate::testing::helpers::{mock_neutron_dependencies, mock_register_query_reply, NeutronMockQuerier};
+
+        const GRANTER: &str = "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw";
+        const ICA_ADDRESS: &str = "cosmos1fx6893deednhgupzp5p5j7awexcync08qkn5j6ndxq66sdltfzlqlutgjs";
+        const VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
+        // MsgDelegate grant from GRANTER to ICA_ADDRESS
+        const GRANT_KEY: &str = "ARR9ywXijNTWjJEJoZJa0nIAKXiodCBJtHLFuctndHAiDQNJe67JsEnh5wWnSWptMDWoN+tIvi9jb3Ntb3Muc3Rha2luZy52MWJldGExLk1zZ0RlbGVnYXRl";
+
+        fn grant_value(expiration_seconds: i64) -> Binary {
+            Binary::from(
+                Grant {
+                    authorization: Some(cosmos_sdk_proto::Any {
+                        type_url: "/cosmos.authz.v1beta1.GenericAuthorization".to_string(),
+                        value: vec![],
+                    }),
+                    expiration: Some(cosmos_sdk_proto::Timestamp {
+                        seconds: expiration_seconds,
+                        nanos: 0,
+                    }),
+                }
+                .encode_to_vec(),
+            )
+        }
+
+        type MockDeps = OwnedDeps<MockStorage, MockApi, NeutronMockQuerier, NeutronQuery>;
+
+        // Registers GRANTER on a chain whose ICA is ICA_ADDRESS, with ICQ id 1
+        fn setup_registration() -> (MockDeps, (Addr, String, String)) {
+            setup_registration_with(vec![VALIDATOR.to_string()])
+        }
+
+        fn setup_registration_with(validators: Vec<String>) -> (MockDeps, (Addr, String, String)) {
+            let validator_count = validators.len();
+            let mut deps = mock_neutron_dependencies();
+            let info = mock_info("creator", &coins(1000000, "untrn"));
+
+            instantiate(
+                deps.as_mut(),
+                mock_env(),
+                info.clone(),
+                InstantiateMsg {
+                    admin: info.sender.to_string(),
+                    neutron_register_ica_fee: 1000000,
+                    autocompound_threshold: 100,
+                    max_validators_per_registration: None,
+                    max_kv_query_keys: None,
+                    treasury_fee: None,
+                },
+            )
+            .unwrap();
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                info.clone(),
+                ExecuteMsg::AddSupportedChain {
+                    chain_id: "chain_id".to_string(),
+                    connection_id: "connection_id".to_string(),
+                    denom: "uatom".to_string(),
+                    autocompound_cost: 100000,
+                    profile: None,
+                    max_icq_staleness: None,
+                    icq_update_period: None,
+                    ica_tx: None,
+                    host_fee: None,
+                },
+            )
+            .unwrap();
+            SUPPORTED_CHAINS
+                .update(deps.as_mut().storage, "chain_id".to_string(), |chain| -> cosmwasm_std::StdResult<_> {
+                    let mut chain = chain.unwrap();
+                    chain.ica_address = Some(Addr::unchecked(ICA_ADDRESS));
+                    Ok(chain)
+                })
+                .unwrap();
+
+            let user_info = mock_info("local_user", &[]);
+            let res = execute_checked(
+                &mut deps,
+                mock_env(),
+                user_info.clone(),
+                ExecuteMsg::RegisterUser {
+                    registrations: vec![UserChainRegistrationInput {
+                        chain_id: "chain_id".to_string(),
+                        address: GRANTER.to_string(),
+                        validators,
+                        fallback_validator: None,
+                        max_commission: None,
+                        billing: None,
+                    }],
+                },
+            )
+            .unwrap();
+            assert_eq!(1 + validator_count, res.messages.len());
+
+            let reg_key = (user_info.sender.clone(), "chain_id".to_string(), GRANTER.to_string());
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(registration.delegator_delegations_reply_id, Ok(1)),
+            )
+            .unwrap();
+            assert_eq!(QUERY_ID_TO_USER_CHAIN_REGISTRATION.load(deps.as_ref().storage, 1).unwrap(), reg_key);
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::GrantMissing);
+
+            deps.querier.add_registered_query(registered_query());
+            (deps, reg_key)
+        }
+
+        fn registered_query() -> RegisteredQuery {
+            RegisteredQuery {
+                id: 1,
+                owner: "contract".to_string(),
+                keys: vec![],
+                query_type: QueryType::KV,
+                transactions_filter: "".to_string(),
+                connection_id: "connection_id".to_string(),
+                update_period: 5,
+                last_submitted_result_local_height: 0,
+                last_submitted_result_remote_height: Default::default(),
+                deposit: vec![],
+                submit_timeout: 0,
+                registered_at_height: 0,
+            }
+        }
+
+        fn result_with_grant(value: Binary) -> InterchainQueryResult {
+            InterchainQueryResult {
+                kv_results: vec![StorageValue {
+                    storage_prefix: "authz".to_string(),
+                    key: Binary::from_base64(GRANT_KEY).unwrap(),
+                    value,
+                }],
+                height: 100,
+                revision: 0,
+            }
+        }
+
+        #[test]
+        fn test_grant_status_follows_query_result() {
+            let (mut deps, reg_key) = setup_registration();
+
+            // Grant is there and still valid
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            deps.querier.add_query_result(1, result_with_grant(grant_value(expiration)));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert_eq!(registration.grant_expiration, Some(Timestamp::from_seconds(expiration as u64)));
+
+            // Only the grant is in the result, the staking and distribution keys are still missing
+            let coverage = ICQ_RESULT_COVERAGE.load(deps.as_ref().storage, 1).unwrap();
+            assert_eq!(coverage.remote_height, 100);
+            assert_eq!(coverage.missing_keys, 4);
+            assert!(!coverage.is_complete());
+
+            // Same grant once its expiration has passed
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(1000);
+            sudo(deps.as_mut(), env, SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::GrantExpired });
+
+            // Grant revoked on the remote chain
+            deps.querier.add_query_result(1, result_with_grant(Binary::default()));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key)
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::GrantMissing);
+            assert_eq!(registration.grant_expiration, None);
+        }
+
+        #[test]
+        fn test_foreign_withdraw_address_pauses_registration() {
+            let (mut deps, reg_key) = setup_registration();
+            let withdraw_address_key = create_delegator_withdraw_address_query_key(
+                &StoreLayout::for_profile(&ChainProfile::default()),
+                GRANTER.to_string(),
+            )
+            .unwrap();
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            let result_with_withdraw_address = |address: &str| {
+                let mut result = result_with_grant(grant_value(expiration));
+                result.kv_results.push(StorageValue {
+                    storage_prefix: withdraw_address_key.path.clone(),
+                    key: withdraw_address_key.key.clone(),
+                    value: Binary::from(decode_and_convert(address).unwrap()),
+                });
+                result
+            };
+
+            let other = MockApi::default().with_prefix("cosmos").addr_make("other").to_string();
+            deps.querier.add_query_result(1, result_with_withdraw_address(&other));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::ForeignWithdrawAddress });
+            assert_eq!(registration.withdraw_address, Some(other));
+
+            // Set back to the delegator itself
+            deps.querier.add_query_result(1, result_with_withdraw_address(GRANTER));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key)
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert_eq!(registration.withdraw_address, None);
+        }
+
+        #[test]
+        fn test_failed_validator_query_fails_registration() {
+            let (mut deps, reg_key) = setup_registration();
+            let validator_query = VALIDATOR_QUERIES
+                .load(deps.as_ref().storage, ("chain_id".to_string(), VALIDATOR.to_string()))
+                .unwrap();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(validator_query.reply_id, Err("invalid connection".to_string())),
+            )
+            .unwrap();
+
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            deps.querier.add_query_result(1, result_with_grant(grant_value(expiration)));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key)
+                .unwrap();
+            assert_eq!(
+                registration.status,
+                RegistrationStatus::Failed {
+                    reason: format!("validator query for {} failed: invalid connection", VALIDATOR)
+                }
+            );
+        }
+
+        fn empty_delegation(validator: &str) -> StorageValue {
+            let key = create_all_icq_keys_for_user(&ChainProfile::default(), GRANTER.to_string(), vec![validator.to_string()], None, None, &[], false)
+                .unwrap()
+                .remove(0);
+            StorageValue {
+                storage_prefix: key.path,
+                key: key.key,
+                value: Binary::default(),
+            }
+        }
+
+        #[test]
+        fn test_undelegated_validators_are_dropped() {
+            let other_validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("other").to_string();
+            let (mut deps, reg_key) = setup_registration_with(vec![VALIDATOR.to_string(), other_validator.clone()]);
+
+            // The user moved away from the other validator
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            let mut result = result_with_grant(grant_value(expiration));
+            result.kv_results.push(empty_delegation(&other_validator));
+            deps.querier.add_query_result(1, result);
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert!(res.attributes.iter().any(|a| a.key == "dropped_validators" && a.value == other_validator));
+
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.validators, vec![VALIDATOR.to_string()]);
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert!(!VALIDATOR_QUERIES.has(deps.as_ref().storage, ("chain_id".to_string(), other_validator)));
+            let coverage = ICQ_RESULT_COVERAGE.load(deps.as_ref().storage, 1).unwrap();
+            assert_eq!(coverage.undelegated_validators.len(), 1);
+
+            // And then from the last one too
+            let mut result = result_with_grant(grant_value(expiration));
+            result.kv_results.push(empty_delegation(VALIDATOR));
+            deps.querier.add_query_result(1, result);
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key)
+                .unwrap();
+            assert_eq!(registration.validators, vec![VALIDATOR.to_string()]);
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::NoDelegations });
+        }
+
+        #[test]
+        fn test_dropped_validators_wait_for_deposit_balance() {
+            let other_validator = MockApi::default().with_prefix("cosmosvaloper").addr_make("other").to_string();
+            let (mut deps, reg_key) = setup_registration_with(vec![VALIDATOR.to_string(), other_validator.clone()]);
+            // The shared query of the kept validator failed, dropping the other one registers it again
+            let validator_query = VALIDATOR_QUERIES
+                .load(deps.as_ref().storage, ("chain_id".to_string(), VALIDATOR.to_string()))
+                .unwrap();
+            reply(
+                deps.as_mut(),
+                mock_env(),
+                mock_register_query_reply(validator_query.reply_id, Err("invalid connection".to_string())),
+            )
+            .unwrap();
+            deps.querier.set_query_deposit(coins(1000, "untrn"));
+
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            let mut result = result_with_grant(grant_value(expiration));
+            result.kv_results.push(empty_delegation(&other_validator));
+            deps.querier.add_query_result(1, result);
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert!(res.messages.is_empty());
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.validators, vec![VALIDATOR.to_string(), other_validator.clone()]);
+            assert_eq!(
+                registration.status,
+                RegistrationStatus::Failed {
+                    reason: "0 untrn balance can't cover the 1000 untrn deposits of its queries to register again"
+                        .to_string()
+                }
+            );
+
+            // Once the owner tops up, the validator is dropped and the failed query registered again
+            execute_checked(
+                &mut deps,
+                mock_env(),
+                mock_info("local_user", &coins(1000, "untrn")),
+                ExecuteMsg::TopupUserBalance {},
+            )
+            .unwrap();
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert!(res.attributes.iter().any(|a| a.key == "dropped_validators" && a.value == other_validator));
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.validators, vec![VALIDATOR.to_string()]);
+            assert_eq!(registration.status, RegistrationStatus::PendingQuery);
+            assert_eq!(USER_BALANCES.load(deps.as_ref().storage, reg_key.0).unwrap(), Uint128::zero());
+        }
+
+        #[test]
+        fn test_keys_only_updated_when_changed() {
+            let (mut deps, _) = setup_registration();
+            let expiration = mock_env().block.time.seconds() as i64 + 1000;
+            deps.querier.add_query_result(1, result_with_grant(grant_value(expiration)));
+
+            // The registered key set has no grant key yet, so it is updated
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert_eq!(res.messages.len(), 1);
+
+            // Same starting periods and update period, nothing to send
+            let keys = create_all_icq_keys_for_user(
+                &ChainProfile::default(),
+                GRANTER.to_string(),
+                vec![VALIDATOR.to_string()],
+                Some(vec![]),
+                Some(ICA_ADDRESS.to_string()),
+                &[MSG_DELEGATE_TYPE_URL],
+                true,
+            )
+            .unwrap();
+            let mut registered_query = registered_query();
+            registered_query.keys = keys;
+            registered_query.update_period = DEFAULT_ICQ_UPDATE_PERIOD;
+            deps.querier.add_registered_query(registered_query.clone());
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert_eq!(res.messages.len(), 0);
+
+            // The chain update period changed
+            registered_query.update_period = 10;
+            deps.querier.add_registered_query(registered_query);
+            let res = sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            assert_eq!(res.messages.len(), 1);
+        }
+
+        #[test]
+        fn test_stake_authorization_allowance() {
+            let (mut deps, reg_key) = setup_registration();
+            let stake_authorization_grant = |max_tokens: u128| {
+                Binary::from(
+                    Grant {
+                        authorization: Some(cosmos_sdk_proto::Any {
+                            type_url: "/cosmos.staking.v1beta1.StakeAuthorization".to_string(),
+                            value: StakeAuthorization {
+                                max_tokens: Some(cosmos_sdk_proto::cosmos::base::v1beta1::Coin {
+                                    denom: "uatom".to_string(),
+                                    amount: max_tokens.to_string(),
+                                }),
+                                authorization_type: 1, // AUTHORIZATION_TYPE_DELEGATE
+                                validators: None,
+                            }
+                            .encode_to_vec(),
+                        }),
+                        expiration: None,
+                    }
+                    .encode_to_vec(),
+                )
+            };
+
+            // Allowance left, the registration can compound
+            deps.querier.add_query_result(1, result_with_grant(stake_authorization_grant(5000)));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert_eq!(registration.grant_allowance, Some(coin(5000, "uatom")));
+
+            // Autocompound paused it because the rewards were above the allowance,
+            // the same allowance coming back over ICQ keeps it paused
+            let mut registration = registration;
+            registration.status = RegistrationStatus::Paused { reason: PauseReason::GrantExhausted };
+            user_chain_registrations()
+                .save(deps.as_mut().storage, reg_key.clone(), &registration)
+                .unwrap();
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::GrantExhausted });
+
+            // A new grant resumes it
+            deps.querier.add_query_result(1, result_with_grant(stake_authorization_grant(1_000_000)));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key.clone())
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert_eq!(registration.grant_allowance, Some(coin(1_000_000, "uatom")));
+
+            // Fully consumed on the host chain
+            deps.querier.add_query_result(1, result_with_grant(stake_authorization_grant(0)));
+            sudo(deps.as_mut(), mock_env(), SudoMsg::KVQueryResult { query_id: 1 }).unwrap();
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, reg_key)
+                .unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::GrantExhausted });
+        }
+    }
+
+    mod test_sudo_ica_tx {
+        use cosmwasm_std::testing::{mock_env, mock_info, MockApi};
+        use cosmwasm_std::{coins, Addr, BankMsg, CosmosMsg, Decimal, Env, Response, StdResult, Uint128};
+        use neutron_sdk::bindings::msg::NeutronMsg;
+        use neutron_sdk::sudo::msg::{RequestPacket, SudoMsg};
+
+        use crate::helpers::{get_due_user_chain_registrations, host_fee_amount};
+        use crate::msg::ExecuteMsg;
+        use crate::reply::reply;
+        use crate::state::{
+            user_chain_registrations, BillingMode, HostFeeConfig, PauseReason, RegistrationStatus, COMPOUND_RETRY_DELAY,
+            DEFAULT_ICA_TIMEOUT_SECONDS, HOST_FEES, LEDGER, MAX_COMPOUND_FAILURES, TREASURY, USER_BALANCES,
+        };
+        use crate::sudo::sudo;
+        use crate::testing::helpers::{
+            assert_invariants, execute_checked, mock_compound_ready, mock_delegation_results, mock_request_packet,
+            mock_submit_tx_reply, MockDeps, MockValidator, MOCK_COMPOUND_COST, MOCK_DELEGATOR, MOCK_REWARD,
+        };
+
+        const VALIDATOR: &str = "cosmosvaloper18hl5c9xn5dze2g50uaw0l2mr02ew57zk0auktn";
+
+        fn mock_validators(count: usize) -> Vec<MockValidator> {
+            (0..count)
+                .map(|i| MockValidator {
+                    address: match i {
+                        0 => VALIDATOR.to_string(),
+                        i => MockApi::default().with_prefix("cosmosvaloper").addr_make(&format!("validator{}", i)).to_string(),
+                    },
+                    icq_id: 2 + i as u64,
+                    jailed: false,
+                })
+                .collect()
+        }
+
+        // local_user registered MOCK_DELEGATOR delegating to `validator_count` validators, due at the mock_env height
+        fn setup(validator_count: usize) -> (MockDeps, (Addr, String, String)) {
+            mock_compound_ready(&mock_validators(validator_count), None, BillingMode::Prepaid, None)
+        }
+
+        // `env` moved `blocks` later, with the delegation results submitted again at it
+        fn later(deps: &mut MockDeps, env: &Env, blocks: u64, validator_count: usize) -> Env {
+            let mut env = env.clone();
+            env.block.height += blocks;
+            env.block.time = env.block.time.plus_seconds(blocks * 6);
+            mock_delegation_results(deps, &env, 1, &mock_validators(validator_count));
+            env
+        }
+
+        fn add_reserve(deps: &mut MockDeps, reserve: u128) {
+            let contract = mock_env().contract.address;
+            let balance = deps.as_ref().querier.query_balance(&contract, "untrn").unwrap().amount;
+            deps.querier.set_balance(&contract, coins(balance.u128() + reserve, "untrn"));
+        }
+
+        // Autocompounds at `env`, the interchaintxs module gives the txs the sequences from `first_sequence` on.
+        // Returns their packets, for the sudo that settles each of them
+        fn compound(deps: &mut MockDeps, env: &Env, first_sequence: u64) -> Vec<RequestPacket> {
+            add_reserve(deps, 10000);
+            let res = execute_checked(
+                deps,
+                env.clone(),
+                mock_info("keeper", &[]),
+                ExecuteMsg::Autocompound { delegators_amount: 10 },
+            )
+            .unwrap();
+            let reply_ids: Vec<u64> = res
+                .messages
+                .iter()
+                .filter(|m| matches!(m.msg, CosmosMsg::Custom(NeutronMsg::SubmitTx { .. })))
+                .map(|m| m.id)
+                .collect();
+            assert!(!reply_ids.is_empty());
+            reply_ids
+                .into_iter()
+                .zip(first_sequence..)
+                .map(|(reply_id, sequence)| {
+                    reply(deps.as_mut(), env.clone(), mock_submit_tx_reply(reply_id, sequence)).unwrap();
+                    mock_request_packet(sequence)
+                })
+                .collect()
+        }
+
+        // Runs the sudo, pays out its untrn and checks the ledger still adds up
+        fn settle(deps: &mut MockDeps, env: &Env, msg: SudoMsg) -> Response<NeutronMsg> {
+            let res = sudo(deps.as_mut(), env.clone(), msg).unwrap();
+            let contract = env.contract.address.clone();
+            let mut balance = deps.as_ref().querier.query_balance(&contract, "untrn").unwrap().amount;
+            for msg in res.messages.iter() {
+                if let CosmosMsg::Bank(BankMsg::Send { amount, .. }) = &msg.msg {
+                    balance -= amount.iter().filter(|c| c.denom == "untrn").map(|c| c.amount).sum::<Uint128>();
+                }
+            }
+            deps.querier.set_balance(&contract, coins(balance.u128(), "untrn"));
+            assert_invariants(deps, env.clone());
+            res
+        }
+
+        fn ack(request: RequestPacket) -> SudoMsg {
+            SudoMsg::Response {
+                request,
+                data: Default::default(),
+            }
+        }
+
+        fn error(request: RequestPacket) -> SudoMsg {
+            SudoMsg::Error {
+                request,
+                details: "out of gas".to_string(),
+            }
+        }
+
+        fn set_compound_failures(deps: &mut MockDeps, reg_key: &(Addr, String, String), compound_failures: u32) {
+            user_chain_registrations()
+                .update(deps.as_mut().storage, reg_key.clone(), |registration| -> StdResult<_> {
+                    let mut registration = registration.unwrap();
+                    registration.compound_failures = compound_failures;
+                    Ok(registration)
+                })
+                .unwrap();
+        }
+
+        #[test]
+        fn test_ack_pays_escrowed_fee() {
+            let (mut deps, reg_key) = setup(1);
+            set_compound_failures(&mut deps, &reg_key, 2);
+            let env = mock_env();
+            let request = compound(&mut deps, &env, 1).remove(0);
+            let ledger = LEDGER.load(deps.as_ref().storage).unwrap();
+            assert_eq!(ledger.escrowed_fees, Uint128::new(MOCK_COMPOUND_COST));
+            assert_eq!(ledger.relayer_escrow, Uint128::new(2000));
+
+            let res = settle(&mut deps, &env, ack(request));
+            assert_eq!(
+                res.messages[0].msg,
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "keeper".to_string(),
+                    amount: coins(90000, "untrn"),
+                })
+            );
+            assert_eq!(TREASURY.load(deps.as_ref().storage, "untrn".to_string()).unwrap(), Uint128::new(10000));
+            let ledger = LEDGER.load(deps.as_ref().storage).unwrap();
+            assert_eq!(ledger.escrowed_fees, Uint128::zero());
+            assert_eq!(ledger.relayer_escrow, Uint128::zero());
+
+            // A delivered compound ends the streak of failures and releases the lock
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key).unwrap();
+            assert_eq!(registration.compound_failures, 0);
+            assert_eq!(registration.in_flight, None);
+        }
+
+        #[test]
+        fn test_in_flight_registration_is_not_due() {
+            let (mut deps, _) = setup(2);
+            let env = mock_env();
+            let due = |deps: &MockDeps, env: &Env| get_due_user_chain_registrations(&deps.as_ref(), env, 10).unwrap().len();
+            assert_eq!(due(&deps, &env), 1);
+
+            // Two validators, two txs, the lock holds until both are settled
+            let mut requests = compound(&mut deps, &env, 1);
+            let env = later(&mut deps, &env, 100, 2);
+            assert_eq!(due(&deps, &env), 0);
+            settle(&mut deps, &env, ack(requests.remove(0)));
+            assert_eq!(due(&deps, &env), 0);
+            settle(&mut deps, &env, ack(requests.remove(0)));
+            assert_eq!(due(&deps, &env), 1);
+
+            // Once its txs timed out on the host chain, a lock whose sudo never came stops holding the registration
+            let mut stuck = compound(&mut deps, &env, 3);
+            assert_eq!(due(&deps, &env), 0);
+            let expired_env = later(&mut deps, &env, DEFAULT_ICA_TIMEOUT_SECONDS / 6, 2);
+            assert_eq!(due(&deps, &expired_env), 1);
+
+            // The lock taken again by a new compound is not released by the late timeouts of the stuck txs
+            compound(&mut deps, &expired_env, 5);
+            settle(&mut deps, &expired_env, SudoMsg::Timeout { request: stuck.remove(0) });
+            settle(&mut deps, &expired_env, SudoMsg::Timeout { request: stuck.remove(0) });
+            let registration = user_chain_registrations()
+                .load(deps.as_ref().storage, (Addr::unchecked("local_user"), "chain_id".to_string(), MOCK_DELEGATOR.to_string()))
+                .unwrap();
+            assert_eq!(registration.in_flight.map(|in_flight| in_flight.txs), Some(2));
+            assert_eq!(due(&deps, &expired_env), 0);
+        }
+
+        #[test]
+        fn test_failed_compound_is_refunded() {
+            let (mut deps, reg_key) = setup(1);
+            let env = mock_env();
+
+            let request = compound(&mut deps, &env, 1).remove(0);
+            let res = settle(&mut deps, &env, SudoMsg::Timeout { request });
+            assert!(res.messages.is_empty());
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, reg_key.0.clone()).unwrap(),
+                Uint128::new(1000000)
+            );
+            assert!(TREASURY.may_load(deps.as_ref().storage, "untrn".to_string()).unwrap().is_none());
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(registration.compound_failures, 1);
+            assert_eq!(registration.next_compound_height, env.block.height + COMPOUND_RETRY_DELAY);
+            assert_eq!(registration.status, RegistrationStatus::Active);
+
+            // The retry waits twice as long after a second failure in a row
+            let env = later(&mut deps, &env, COMPOUND_RETRY_DELAY, 1);
+            let request = compound(&mut deps, &env, 2).remove(0);
+            settle(&mut deps, &env, error(request));
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(registration.compound_failures, 2);
+            assert_eq!(registration.next_compound_height, env.block.height + 2 * COMPOUND_RETRY_DELAY);
+
+            let mut env = env;
+            for sequence in 3..=MAX_COMPOUND_FAILURES as u64 {
+                env = later(&mut deps, &env, COMPOUND_RETRY_DELAY * 2u64.pow(sequence as u32 - 2), 1);
+                let request = compound(&mut deps, &env, sequence).remove(0);
+                settle(&mut deps, &env, SudoMsg::Timeout { request });
+            }
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(registration.compound_failures, MAX_COMPOUND_FAILURES);
+            assert_eq!(registration.status, RegistrationStatus::Paused { reason: PauseReason::CompoundFailures });
+            assert_eq!(
+                USER_BALANCES.load(deps.as_ref().storage, reg_key.0).unwrap(),
+                Uint128::new(1000000)
+            );
+        }
+
+        #[test]
+        fn test_compound_fails_once_per_lock() {
+            let (mut deps, reg_key) = setup(2);
+            set_compound_failures(&mut deps, &reg_key, 2);
+            let compound_failures = |deps: &MockDeps| {
+                user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap().compound_failures
+            };
+
+            // The ack of one tx doesn't end the streak while its sibling can still fail
+            let env = mock_env();
+            let mut requests = compound(&mut deps, &env, 1);
+            settle(&mut deps, &env, ack(requests.remove(0)));
+            assert_eq!(compound_failures(&deps), 2);
+            settle(&mut deps, &env, error(requests.remove(0)));
+            assert_eq!(compound_failures(&deps), 3);
+
+            // Both txs of a compound failing count once
+            let env = later(&mut deps, &env, 4 * COMPOUND_RETRY_DELAY, 2);
+            let mut requests = compound(&mut deps, &env, 3);
+            settle(&mut deps, &env, error(requests.remove(0)));
+            settle(&mut deps, &env, SudoMsg::Timeout { request: requests.remove(0) });
+            assert_eq!(compound_failures(&deps), 4);
+            let registration = user_chain_registrations().load(deps.as_ref().storage, reg_key.clone()).unwrap();
+            assert_eq!(registration.status, RegistrationStatus::Active);
+            assert_eq!(registration.next_compound_height, env.block.height + 8 * COMPOUND_RETRY_DELAY);
+
+            // Every tx acked ends it
+            let env = later(&mut deps, &env, 8 * COMPOUND_RETRY_DELAY, 2);
+            for request in compound(&mut deps, &env, 5) {
+                settle(&mut deps, &env, ack(request));
+            }
+            assert_eq!(compound_failures(&deps), 0);
+        }
+
+        // Host fees the chain ICA collects, swept to Neutron over channel-1
+        fn host_fee_config() -> HostFeeConfig {
+            HostFeeConfig {
+                percentage: Decimal::percent(5),
+                flat: Uint128::zero(),
+                collector: None,
+                sweep_channel: Some("channel-1".to_string()),
+                sweep_denom: Some("ibc/uatom".to_string()),
+                grant_spend_limit: None,
+            }
+        }
+
+        #[test]
+        fn test_host_fee_counted_on_ack() {
+            for acknowledged in [true, false] {
+                let (mut deps, _) =
+                    mock_compound_ready(&mock_validators(1), None, BillingMode::HostChain, Some(host_fee_config()));
+                let env = mock_env();
+                let request = compound(&mut deps, &env, 1).remove(0);
+                assert!(!HOST_FEES.has(deps.as_ref().storage, "chain_id".to_string()));
+
+                let msg = if acknowledged { ack(request) } else { SudoMsg::Timeout { request } };
+                settle(&mut deps, &env, msg);
+                let collected = HOST_FEES.may_load(deps.as_ref().storage, "chain_id".to_string()).unwrap();
+                if acknowledged {
+                    assert_eq!(collected, Some(host_fee_amount(&host_fee_config(), Uint128::new(MOCK_REWARD))));
+                } else {
+                    // The fee never left the delegator
+                    assert_eq!(collected, None);
+                }
+            }
+        }
+
+        #[test]
+        fn test_sweep_settlement() {
+            let (mut deps, _) = mock_compound_ready(&[], None, BillingMode::HostChain, Some(host_fee_config()));
+            add_reserve(&mut deps, 10000);
+            HOST_FEES.save(deps.as_mut().storage, "chain_id".to_string(), &Uint128::new(1500)).unwrap();
+            let sweep = |deps: &mut MockDeps, sequence: u64| {
+                let res = execute_checked(
+                    deps,
+                    mock_env(),
+                    mock_info("creator", &[]),
+                    ExecuteMsg::SweepHostFees {
+                        chain_id: "chain_id".to_string(),
+                        amount: None,
+                    },
+                )
+                .unwrap();
+                reply(deps.as_mut(), mock_env(), mock_submit_tx_reply(res.messages[0].id, sequence)).unwrap();
+                assert_eq!(HOST_FEES.load(deps.as_ref().storage, "chain_id".to_string()).unwrap(), Uint128::zero());
+                mock_request_packet(sequence)
+            };
+
+            // A sweep that timed out leaves the fees on the ICA, they can be swept again
+            let request = sweep(&mut deps, 1);
+            sudo(deps.as_mut(), mock_env(), SudoMsg::Timeout { request }).unwrap();
+            assert_eq!(HOST_FEES.load(deps.as_ref().storage, "chain_id".to_string()).unwrap(), Uint128::new(1500));
+            assert!(!TREASURY.has(deps.as_ref().storage, "ibc/uatom".to_string()));
+
+            let request = sweep(&mut deps, 2);
+            sudo(deps.as_mut(), mock_env(), SudoMsg::Response { request, data: Default::default() }).unwrap();
+            assert_eq!(HOST_FEES.load(deps.as_ref().storage, "chain_id".to_string()).unwrap(), Uint128::zero());
+            assert_eq!(TREASURY.load(deps.as_ref().storage, "ibc/uatom".to_string()).unwrap(), Uint128::new(1500));
+        }
+    }
 }
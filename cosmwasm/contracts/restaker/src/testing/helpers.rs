@@ -1,13 +1,501 @@
-use cosmwasm_std::testing::{MockApi, MockQuerier, MockStorage};
-use cosmwasm_std::OwnedDeps;
-use neutron_sdk::bindings::query::NeutronQuery;
+use std::collections::HashMap;
 use std::marker::PhantomData;
 
-pub fn mock_neutron_dependencies() -> OwnedDeps<MockStorage, MockApi, MockQuerier, NeutronQuery> {
+use cosmos_sdk_proto::cosmos::base::v1beta1::DecCoin;
+use cosmos_sdk_proto::cosmos::distribution::v1beta1::{
+    DelegatorStartingInfo, ValidatorCurrentRewards, ValidatorHistoricalRewards,
+};
+use cosmos_sdk_proto::cosmos::staking::v1beta1::{Commission, CommissionRates, Delegation, Validator};
+use cosmos_sdk_proto::traits::Message;
+use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    coins, from_json, to_json_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, Env, MessageInfo, Order, Response, Storage, Uint128, ContractResult, OwnedDeps, Querier, QuerierResult,
+    QueryRequest, Reply, SubMsgResponse, SubMsgResult, SystemError, SystemResult,
+};
+use neutron_sdk::bindings::msg::{IbcFee, MsgRegisterInterchainQueryResponse, MsgSubmitTxResponse, NeutronMsg};
+use neutron_sdk::bindings::query::{
+    NeutronQuery, QueryRegisteredQueryResponse, QueryRegisteredQueryResultResponse,
+};
+use neutron_sdk::bindings::types::{Height, InterchainQueryResult, KVKey, RegisteredQuery, StorageValue};
+use neutron_sdk::interchain_queries::types::QueryType;
+use neutron_sdk::query::min_ibc_fee::MinIbcFeeResponse;
+use neutron_sdk::sudo::msg::RequestPacket;
+use serde::Serialize;
+
+use crate::error::ContractError;
+use crate::execute::execute;
+use crate::icq::keys::{
+    create_delegator_delegations_query_keys, create_delegator_starting_info_query_keys,
+    create_validator_current_rewards_query_keys, create_validator_historical_rewards_query_keys,
+    create_validator_query_keys, StoreLayout, ValidatorHistoricalRange,
+};
+use crate::instantiate::instantiate;
+use crate::msg::{ExecuteMsg, InstantiateMsg, InvariantsResponse, QueryMsg};
+use crate::query::query;
+use crate::state::{
+    user_chain_registrations, BillingMode, ChainProfile, HostFeeConfig, RegistrationStatus, UserChainRegistration,
+    ValidatorQuery, SUPPORTED_CHAINS, VALIDATOR_QUERIES,
+};
+
+pub type MockDeps = OwnedDeps<MockStorage, MockApi, NeutronMockQuerier, NeutronQuery>;
+
+pub fn mock_neutron_dependencies() -> MockDeps {
     OwnedDeps {
         storage: MockStorage::default(),
         api: MockApi::default(),
-        querier: MockQuerier::default(),
+        querier: NeutronMockQuerier::default(),
         custom_query_type: PhantomData,
     }
 }
+
+/// The reply the interchainqueries module sends for a register query submessage
+pub fn mock_register_query_reply(reply_id: u64, result: Result<u64, String>) -> Reply {
+    Reply {
+        id: reply_id,
+        result: match result {
+            Ok(query_id) => SubMsgResult::Ok(SubMsgResponse {
+                events: vec![],
+                data: Some(
+                    to_json_binary(&MsgRegisterInterchainQueryResponse { id: query_id }).unwrap(),
+                ),
+            }),
+            Err(err) => SubMsgResult::Err(err),
+        },
+    }
+}
+
+/// The reply the interchaintxs module sends for a SubmitTx submessage
+pub fn mock_submit_tx_reply(reply_id: u64, sequence: u64) -> Reply {
+    Reply {
+        id: reply_id,
+        result: SubMsgResult::Ok(SubMsgResponse {
+            events: vec![],
+            data: Some(
+                to_json_binary(&MsgSubmitTxResponse {
+                    sequence_id: sequence,
+                    channel: "channel-0".to_string(),
+                })
+                .unwrap(),
+            ),
+        }),
+    }
+}
+
+/// The packet of the ICA tx acknowledged by mock_submit_tx_reply, for its sudo response, error or timeout
+pub fn mock_request_packet(sequence: u64) -> RequestPacket {
+    RequestPacket {
+        sequence: Some(sequence),
+        source_port: Some("icacontroller-ica".to_string()),
+        source_channel: Some("channel-0".to_string()),
+        destination_port: None,
+        destination_channel: None,
+        data: None,
+        timeout_height: None,
+        timeout_timestamp: None,
+    }
+}
+
+pub const MOCK_DELEGATOR: &str = "cosmos10h9stc5v6ntgeygf5xf945njqq5h32r53uquvw";
+pub const MOCK_ICA_ADDRESS: &str = "cosmos1fx6893deednhgupzp5p5j7awexcync08qkn5j6ndxq66sdltfzlqlutgjs";
+pub const MOCK_COMPOUND_COST: u128 = 100000;
+/// Reward of each delegation of mock_delegation_results, in uatom
+pub const MOCK_REWARD: u128 = 2444866473;
+
+/// A validator MOCK_DELEGATOR delegates to, with the id of its shared query
+pub struct MockValidator {
+    pub address: String,
+    pub icq_id: u64,
+    pub jailed: bool,
+}
+
+/// An instantiated contract keeping 10% of each autocompound cost, with an open ICA on chain_id. local_user registered
+/// MOCK_DELEGATOR with 1000000 untrn topped up, its primary query is 1 and holds a delegation to each of `validators`
+pub fn mock_compound_ready(
+    validators: &[MockValidator],
+    fallback_validator: Option<String>,
+    billing: BillingMode,
+    host_fee: Option<HostFeeConfig>,
+) -> (MockDeps, (Addr, String, String)) {
+    let mut deps = mock_neutron_dependencies();
+    let env = mock_env();
+    let creator_info = mock_info("creator", &coins(1000000, "untrn"));
+    instantiate(
+        deps.as_mut(),
+        env.clone(),
+        creator_info.clone(),
+        InstantiateMsg {
+            admin: creator_info.sender.to_string(),
+            neutron_register_ica_fee: 1000000,
+            autocompound_threshold: 100,
+            max_validators_per_registration: None,
+            max_kv_query_keys: None,
+            treasury_fee: Some(Decimal::percent(10)),
+        },
+    )
+    .unwrap();
+    execute_checked(
+        &mut deps,
+        env.clone(),
+        creator_info,
+        ExecuteMsg::AddSupportedChain {
+            chain_id: "chain_id".to_string(),
+            connection_id: "connection_id".to_string(),
+            denom: "uatom".to_string(),
+            autocompound_cost: MOCK_COMPOUND_COST,
+            profile: None,
+            max_icq_staleness: None,
+            icq_update_period: None,
+            ica_tx: None,
+            host_fee,
+        },
+    )
+    .unwrap();
+    SUPPORTED_CHAINS
+        .update(deps.as_mut().storage, "chain_id".to_string(), |chain| -> cosmwasm_std::StdResult<_> {
+            let mut chain = chain.unwrap();
+            chain.ica_address = Some(Addr::unchecked(MOCK_ICA_ADDRESS));
+            Ok(chain)
+        })
+        .unwrap();
+
+    let user_info = mock_info("local_user", &coins(1000000, "untrn"));
+    execute_checked(&mut deps, env.clone(), user_info.clone(), ExecuteMsg::TopupUserBalance {}).unwrap();
+
+    let reg_key = (user_info.sender.clone(), "chain_id".to_string(), MOCK_DELEGATOR.to_string());
+    user_chain_registrations()
+        .save(
+            deps.as_mut().storage,
+            reg_key.clone(),
+            &UserChainRegistration {
+                local_address: user_info.sender,
+                chain_id: "chain_id".to_string(),
+                remote_address: MOCK_DELEGATOR.to_string(),
+                validators: validators.iter().map(|v| v.address.clone()).collect(),
+                delegator_delegations_reply_id: 1,
+                delegator_delegations_icq_id: Some(1),
+                next_compound_height: env.block.height,
+                status: RegistrationStatus::Active,
+                grant_expiration: None,
+                grant_allowance: None,
+                icq_shards: vec![],
+                fallback_validator,
+                max_commission: None,
+                compounded_commissions: vec![],
+                withdraw_address: None,
+                billing,
+                compound_failures: 0,
+                in_flight: None,
+                failed_compound: None,
+            },
+        )
+        .unwrap();
+    for validator in validators {
+        VALIDATOR_QUERIES
+            .save(
+                deps.as_mut().storage,
+                ("chain_id".to_string(), validator.address.clone()),
+                &ValidatorQuery {
+                    reply_id: validator.icq_id,
+                    icq_id: Some(validator.icq_id),
+                    ref_count: 1,
+                    icq_error: None,
+                },
+            )
+            .unwrap();
+    }
+    mock_delegation_results(&mut deps, &env, 1, validators);
+
+    (deps, reg_key)
+}
+
+/// Submits the results of the primary query `icq_id` of MOCK_DELEGATOR and of the shared query of each validator,
+/// all read at remote height 100 and submitted at the height of `env`. Each delegation earned MOCK_REWARD
+pub fn mock_delegation_results(deps: &mut MockDeps, env: &Env, icq_id: u64, validators: &[MockValidator]) {
+    let layout = StoreLayout::for_profile(&ChainProfile::default());
+    let value = |key: KVKey, value: Vec<u8>| StorageValue {
+        storage_prefix: key.path,
+        key: key.key,
+        value: Binary::from(value),
+    };
+    let dec_coin = |amount: &str| DecCoin {
+        denom: "uatom".to_string(),
+        amount: amount.to_string(),
+    };
+    let stake = "1000000000000000000000000000000".to_string();
+
+    let mut user_values = vec![];
+    for validator in validators {
+        let address = validator.address.clone();
+        let single = |keys: Vec<KVKey>| keys.into_iter().next().unwrap();
+        let delegation_key =
+            create_delegator_delegations_query_keys(&layout, MOCK_DELEGATOR.to_string(), vec![address.clone()]).unwrap();
+        user_values.push(value(
+            single(delegation_key),
+            Delegation {
+                delegator_address: MOCK_DELEGATOR.to_string(),
+                validator_address: address.clone(),
+                shares: stake.clone(),
+            }
+            .encode_to_vec(),
+        ));
+        let starting_info_key =
+            create_delegator_starting_info_query_keys(&layout, MOCK_DELEGATOR.to_string(), vec![address.clone()])
+                .unwrap();
+        user_values.push(value(
+            single(starting_info_key),
+            DelegatorStartingInfo {
+                previous_period: 11,
+                stake: stake.clone(),
+                height: 7333,
+            }
+            .encode_to_vec(),
+        ));
+        let historical_rewards_key = create_validator_historical_rewards_query_keys(
+            &layout,
+            vec![ValidatorHistoricalRange {
+                validator: address.clone(),
+                period: 11,
+            }],
+        )
+        .unwrap();
+        user_values.push(value(
+            single(historical_rewards_key),
+            ValidatorHistoricalRewards {
+                cumulative_reward_ratio: vec![dec_coin("480297754365783730")],
+                reference_count: 2,
+            }
+            .encode_to_vec(),
+        ));
+
+        let validator_values = vec![
+            value(
+                single(create_validator_query_keys(&layout, vec![address.clone()]).unwrap()),
+                Validator {
+                    operator_address: address.clone(),
+                    jailed: validator.jailed,
+                    status: 3, // Bonded
+                    tokens: "1007000000000".to_string(),
+                    delegator_shares: "1007000000000000000000000000000".to_string(),
+                    commission: Some(Commission {
+                        commission_rates: Some(CommissionRates {
+                            rate: "100000000000000000".to_string(),
+                            max_rate: "200000000000000000".to_string(),
+                            max_change_rate: "10000000000000000".to_string(),
+                        }),
+                        update_time: None,
+                    }),
+                    ..Validator::default()
+                }
+                .encode_to_vec(),
+            ),
+            value(
+                single(create_validator_current_rewards_query_keys(&layout, vec![address]).unwrap()),
+                ValidatorCurrentRewards {
+                    rewards: vec![dec_coin("2444866473546000000000000000")],
+                    period: 12,
+                }
+                .encode_to_vec(),
+            ),
+        ];
+        mock_query_result(deps, env, validator.icq_id, validator_values);
+    }
+    mock_query_result(deps, env, icq_id, user_values);
+}
+
+fn mock_query_result(deps: &mut MockDeps, env: &Env, icq_id: u64, kv_results: Vec<StorageValue>) {
+    deps.querier.add_registered_query(RegisteredQuery {
+        id: icq_id,
+        owner: env.contract.address.to_string(),
+        keys: vec![],
+        query_type: QueryType::KV,
+        transactions_filter: "".to_string(),
+        connection_id: "connection_id".to_string(),
+        update_period: 5,
+        last_submitted_result_local_height: env.block.height,
+        last_submitted_result_remote_height: Height {
+            revision_number: 0,
+            revision_height: 100,
+        },
+        deposit: vec![],
+        submit_timeout: 0,
+        registered_at_height: 0,
+    });
+    deps.querier.add_query_result(icq_id, InterchainQueryResult {
+        kv_results,
+        height: 100,
+        revision: 0,
+    });
+}
+
+const INTERCHAIN_QUERIES_PARAMS_PATH: &str = "/neutron.interchainqueries.Query/Params";
+
+#[derive(Serialize)]
+struct InterchainQueriesParamsResponse {
+    params: InterchainQueriesParams,
+}
+
+#[derive(Serialize)]
+struct InterchainQueriesParams {
+    query_deposit: Vec<Coin>,
+}
+
+/// Answers the interchain queries module queries from whatever the test registered,
+/// everything else goes to the regular mock querier.
+pub struct NeutronMockQuerier {
+    base: MockQuerier<NeutronQuery>,
+    registered_queries: HashMap<u64, RegisteredQuery>,
+    query_results: HashMap<u64, InterchainQueryResult>,
+    query_deposit: Vec<Coin>, // No deposit unless a test sets one
+}
+
+impl Default for NeutronMockQuerier {
+    fn default() -> Self {
+        NeutronMockQuerier {
+            base: MockQuerier::new(&[]),
+            registered_queries: HashMap::new(),
+            query_results: HashMap::new(),
+            query_deposit: vec![],
+        }
+    }
+}
+
+impl NeutronMockQuerier {
+    pub fn add_registered_query(&mut self, registered_query: RegisteredQuery) {
+        self.registered_queries
+            .insert(registered_query.id, registered_query);
+    }
+
+    pub fn add_query_result(&mut self, query_id: u64, result: InterchainQueryResult) {
+        self.query_results.insert(query_id, result);
+    }
+
+    pub fn set_query_deposit(&mut self, query_deposit: Vec<Coin>) {
+        self.query_deposit = query_deposit;
+    }
+
+    pub fn set_balance(&mut self, addr: &Addr, balance: Vec<Coin>) {
+        self.base.update_balance(addr, balance);
+    }
+}
+
+fn untrn(coins: &[Coin]) -> Uint128 {
+    coins.iter().filter(|c| c.denom == "untrn").map(|c| c.amount).sum()
+}
+
+/// Runs `execute` and moves the untrn of the contract like the chain would: the funds come in, then the bank
+/// sends, ICA registration fees, query deposits and relayer fees go out. The ledger invariants have to hold after.
+/// A failed execute leaves the storage as it was, like a reverted transaction.
+pub fn execute_checked(
+    deps: &mut MockDeps,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response<NeutronMsg>, ContractError> {
+    let snapshot: Vec<_> = deps.storage.range(None, None, Order::Ascending).collect();
+    let res = match execute(deps.as_mut(), env.clone(), info.clone(), msg) {
+        Ok(res) => res,
+        Err(err) => {
+            deps.storage = MockStorage::default();
+            for (key, value) in snapshot {
+                deps.storage.set(&key, &value);
+            }
+            return Err(err);
+        }
+    };
+
+    let contract = env.contract.address.clone();
+    let query_deposit = untrn(&deps.querier.query_deposit);
+    let mut balance = deps.as_ref().querier.query_balance(&contract, "untrn").unwrap().amount + untrn(&info.funds);
+    for sub_msg in res.messages.iter() {
+        let spent = match &sub_msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { amount, .. }) => untrn(amount),
+            CosmosMsg::Custom(NeutronMsg::RegisterInterchainAccount { register_fee, .. }) => {
+                untrn(register_fee.as_deref().unwrap_or_default())
+            }
+            CosmosMsg::Custom(NeutronMsg::RegisterInterchainQuery { .. }) => query_deposit,
+            CosmosMsg::Custom(NeutronMsg::SubmitTx { fee, .. }) => untrn(&fee.ack_fee) + untrn(&fee.timeout_fee),
+            CosmosMsg::Custom(NeutronMsg::RemoveInterchainQuery { .. }) => {
+                balance += query_deposit;
+                Uint128::zero()
+            }
+            _ => Uint128::zero(),
+        };
+        balance = balance.checked_sub(spent).expect("the contract spends more untrn than it holds");
+    }
+    deps.querier.set_balance(&contract, coins(balance.u128(), "untrn"));
+
+    assert_invariants(deps, env);
+    Ok(res)
+}
+
+pub fn assert_invariants(deps: &MockDeps, env: Env) {
+    let invariants: InvariantsResponse =
+        from_json(query(deps.as_ref(), env, QueryMsg::Invariants {}).unwrap()).unwrap();
+    assert!(invariants.violations.is_empty(), "{:?}", invariants.violations);
+}
+
+impl Querier for NeutronMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<NeutronQuery> = match from_json(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+
+        match request {
+            QueryRequest::Custom(NeutronQuery::RegisteredInterchainQuery { query_id }) => {
+                match self.registered_queries.get(&query_id) {
+                    Some(registered_query) => SystemResult::Ok(ContractResult::Ok(
+                        to_json_binary(&QueryRegisteredQueryResponse {
+                            registered_query: registered_query.clone(),
+                        })
+                        .unwrap(),
+                    )),
+                    None => SystemResult::Ok(ContractResult::Err(format!(
+                        "query {} not found",
+                        query_id
+                    ))),
+                }
+            }
+            QueryRequest::Custom(NeutronQuery::InterchainQueryResult { query_id }) => {
+                match self.query_results.get(&query_id) {
+                    Some(result) => SystemResult::Ok(ContractResult::Ok(
+                        to_json_binary(&QueryRegisteredQueryResultResponse {
+                            result: result.clone(),
+                        })
+                        .unwrap(),
+                    )),
+                    None => SystemResult::Ok(ContractResult::Err(format!(
+                        "no result for query {}",
+                        query_id
+                    ))),
+                }
+            }
+            QueryRequest::Stargate { path, .. } if path == INTERCHAIN_QUERIES_PARAMS_PATH => {
+                SystemResult::Ok(ContractResult::Ok(
+                    to_json_binary(&InterchainQueriesParamsResponse {
+                        params: InterchainQueriesParams {
+                            query_deposit: self.query_deposit.clone(),
+                        },
+                    })
+                    .unwrap(),
+                ))
+            }
+            QueryRequest::Custom(NeutronQuery::MinIbcFee {}) => SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&MinIbcFeeResponse {
+                    min_fee: IbcFee {
+                        recv_fee: vec![],
+                        ack_fee: coins(1000, "untrn"),
+                        timeout_fee: coins(1000, "untrn"),
+                    },
+                })
+                .unwrap(),
+            )),
+            _ => self.base.handle_query(&request),
+        }
+    }
+}
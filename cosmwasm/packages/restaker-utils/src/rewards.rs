@@ -2,6 +2,7 @@ use std::{collections::HashMap, str::FromStr};
 use cosmwasm_std::{Coin, Decimal256, Deps, Env, StdError, StdResult, Uint128, Uint256};
 use crate::types::{DelegatorStartingInfo, ValidatorHistoricalRewards};
 
+#[allow(clippy::too_many_arguments)]
 pub fn calculate_delegation_rewards(
     env: Env,
     deps: Deps,